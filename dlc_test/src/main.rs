@@ -1,19 +1,26 @@
 mod resolvr_oracle;
 
+use bitcoin::secp256k1::PublicKey;
 use bitcoin::XOnlyPublicKey;
-use bitcoin_rpc_provider::BitcoinCoreProvider;
 use dlc::EnumerationPayout;
 use dlc_manager::contract::contract_input::{ContractInput, ContractInputInfo, OracleInput};
 use dlc_manager::contract::enum_descriptor::EnumDescriptor;
 use dlc_manager::contract::{Contract, ContractDescriptor};
+use dlc_manager::Blockchain;
 use dlc_manager::Oracle;
 use dlc_manager::Storage;
 use dlc_manager::SystemTimeProvider;
 use dlc_manager::Wallet;
 use dlc_messages::contract_msgs::ContractInfo;
+use dlc_sled_storage_provider::SledStorageProvider;
+use electrs_blockchain_provider::ElectrsBlockchainProvider;
+use escrow_agent_messages::create_completion_percentage_bounty_contract;
+use escrow_agent_messages::dlc_msg_codec::{encode_dlc_msg, DlcMsg, DlcMsgReassembler};
+use lightning::chain::chaininterface::FeeEstimator;
 use resolvr_oracle::{
     ResolvrOracle, BOUNTY_COMPLETE_ORACLE_MESSAGE, BOUNTY_INSUFFICIENT_ORACLE_MESSAGE,
 };
+use simple_wallet::SimpleWallet;
 use std::collections::hash_map::HashMap;
 use std::fs;
 use std::sync::{Arc, Mutex};
@@ -26,14 +33,16 @@ struct BitcoinCoreConfig {
 }
 
 pub type ResolvrDlcManager<'a> = dlc_manager::manager::Manager<
-    Arc<BitcoinCoreProvider>,
-    Arc<BitcoinCoreProvider>,
+    Arc<dyn Wallet + Send + Sync>,
+    Arc<dyn Blockchain + Send + Sync>,
     Box<dlc_sled_storage_provider::SledStorageProvider>,
     Arc<ResolvrOracle>,
     Arc<SystemTimeProvider>,
-    Arc<BitcoinCoreProvider>,
+    Arc<dyn FeeEstimator + Send + Sync>,
 >;
 
+type LocalWallet = SimpleWallet<Arc<ElectrsBlockchainProvider>, Arc<SledStorageProvider>>;
+
 #[tokio::main]
 async fn main() {
     let alice_storage_path = format!(
@@ -83,16 +92,73 @@ async fn main() {
         .expect("Error creating BitcoinCoreProvider"),
     );
 
-    let bob_bitcoind_provider = Arc::new(
-        bitcoin_rpc_provider::BitcoinCoreProvider::new(
-            bob_config.host,
-            bob_config.port,
-            None,
-            bob_config.rpc_user,
-            bob_config.rpc_password,
-        )
-        .expect("Error creating BitcoinCoreProvider"),
-    );
+    // Bob uses an Esplora server with a local wallet instead of his own node
+    // if one is given, e.g. an electrs instance running against regtest.
+    let (bob_wallet, bob_blockchain, bob_fee_estimator, bob_local_wallet_or): (
+        Arc<dyn Wallet + Send + Sync>,
+        Arc<dyn Blockchain + Send + Sync>,
+        Arc<dyn FeeEstimator + Send + Sync>,
+        Option<Arc<LocalWallet>>,
+    ) = match std::env::var("RESOLVR_TEST_ESPLORA_URL") {
+        Ok(esplora_url) => {
+            let bob_wallet_storage_path = format!(
+                "{}/{}",
+                std::env::current_dir().unwrap().to_str().unwrap(),
+                "data/bob_wallet"
+            );
+            let esplora_provider = Arc::new(ElectrsBlockchainProvider::new(
+                esplora_url,
+                bitcoin::Network::Regtest,
+            ));
+            let bob_local_wallet = Arc::new(SimpleWallet::new(
+                esplora_provider.clone(),
+                Arc::new(
+                    SledStorageProvider::new(&bob_wallet_storage_path)
+                        .expect("Error creating wallet storage."),
+                ),
+                bitcoin::Network::Regtest,
+            ));
+            (
+                bob_local_wallet.clone(),
+                esplora_provider.clone(),
+                esplora_provider,
+                Some(bob_local_wallet),
+            )
+        }
+        Err(_) => {
+            let bob_bitcoind_provider = Arc::new(
+                bitcoin_rpc_provider::BitcoinCoreProvider::new(
+                    bob_config.host,
+                    bob_config.port,
+                    None,
+                    bob_config.rpc_user,
+                    bob_config.rpc_password,
+                )
+                .expect("Error creating BitcoinCoreProvider"),
+            );
+            (
+                bob_bitcoind_provider.clone(),
+                bob_bitcoind_provider.clone(),
+                bob_bitcoind_provider,
+                None,
+            )
+        }
+    };
+
+    if let Some(bob_local_wallet) = &bob_local_wallet_or {
+        println!(
+            "Waiting for Bob's local wallet to be funded at {}...",
+            bob_local_wallet.get_new_address().unwrap()
+        );
+        loop {
+            bob_local_wallet.refresh().unwrap();
+            if bob_local_wallet.get_balance() > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        println!("Bob's local wallet is funded!");
+    }
 
     let time_provider = Arc::new(dlc_manager::SystemTimeProvider {});
 
@@ -101,23 +167,24 @@ async fn main() {
         .get_new_secret_key()
         .unwrap()
         .public_key(&secp);
-    let bob_public_key = bob_bitcoind_provider
-        .get_new_secret_key()
-        .unwrap()
-        .public_key(&secp);
+    let bob_public_key = bob_wallet.get_new_secret_key().unwrap().public_key(&secp);
+
+    let alice_wallet: Arc<dyn Wallet + Send + Sync> = alice_bitcoind_provider.clone();
+    let alice_blockchain: Arc<dyn Blockchain + Send + Sync> = alice_bitcoind_provider.clone();
+    let alice_fee_estimator: Arc<dyn FeeEstimator + Send + Sync> = alice_bitcoind_provider;
 
     println!("Creating Alice's DLC manager...");
     let alice_dlc_manager: Arc<Mutex<ResolvrDlcManager>> = Arc::new(Mutex::new(
         dlc_manager::manager::Manager::new(
-            alice_bitcoind_provider.clone(),
-            alice_bitcoind_provider.clone(),
+            alice_wallet,
+            alice_blockchain,
             Box::new(
                 dlc_sled_storage_provider::SledStorageProvider::new(&alice_storage_path)
                     .expect("Error creating storage."),
             ),
             oracles.clone(),
             time_provider.clone(),
-            alice_bitcoind_provider,
+            alice_fee_estimator,
         )
         .expect("Could not create manager."),
     ));
@@ -125,19 +192,20 @@ async fn main() {
     println!("Creating Bob's DLC manager...");
     let bob_dlc_manager: Arc<Mutex<ResolvrDlcManager>> = Arc::new(Mutex::new(
         dlc_manager::manager::Manager::new(
-            bob_bitcoind_provider.clone(),
-            bob_bitcoind_provider.clone(),
+            bob_wallet,
+            bob_blockchain,
             Box::new(
                 dlc_sled_storage_provider::SledStorageProvider::new(&bob_storage_path)
                     .expect("Error creating storage."),
             ),
             oracles,
             time_provider,
-            bob_bitcoind_provider,
+            bob_fee_estimator,
         )
         .expect("Could not create manager."),
     ));
 
+    println!("Running bounty contract...");
     let oracle_event_id = oracle.create_announcement();
     let dlc_contract = create_bounty_contract(
         1_000,
@@ -146,12 +214,72 @@ async fn main() {
         oracle.get_public_key(),
         oracle_event_id.to_string(),
     );
+    run_contract(
+        &alice_dlc_manager,
+        &bob_dlc_manager,
+        bob_local_wallet_or.as_deref(),
+        alice_public_key,
+        bob_public_key,
+        &dlc_contract,
+        || {
+            oracle
+                .create_attestation(
+                    &oracle_event_id,
+                    resolvr_oracle::BountyOutcome::Insufficient,
+                )
+                .unwrap();
+        },
+    )
+    .await;
+
+    println!("Running completion percentage bounty contract...");
+    let oracle_event_id = oracle.create_completion_percentage_announcement();
+    let dlc_contract = create_completion_percentage_bounty_contract(
+        1_000,
+        0,
+        10,
+        OracleInput {
+            public_keys: vec![oracle.get_public_key()],
+            event_id: oracle_event_id.to_string(),
+            threshold: 1,
+        },
+    )
+    .unwrap();
+    run_contract(
+        &alice_dlc_manager,
+        &bob_dlc_manager,
+        bob_local_wallet_or.as_deref(),
+        alice_public_key,
+        bob_public_key,
+        &dlc_contract,
+        || {
+            oracle
+                .create_completion_percentage_attestation(&oracle_event_id, 60)
+                .unwrap();
+        },
+    )
+    .await;
+}
+
+/// Offers, accepts and funds a contract between Alice and Bob, then calls
+/// `attest` and waits for the contract to close. Bob's local wallet, if he
+/// has one, is refreshed before each step that spends from it.
+async fn run_contract(
+    alice_dlc_manager: &Mutex<ResolvrDlcManager<'_>>,
+    bob_dlc_manager: &Mutex<ResolvrDlcManager<'_>>,
+    bob_local_wallet_or: Option<&LocalWallet>,
+    alice_public_key: PublicKey,
+    bob_public_key: PublicKey,
+    dlc_contract: &ContractInput,
+    attest: impl FnOnce(),
+) {
     dlc_contract.validate().unwrap();
+    let secp = bitcoin::secp256k1::Secp256k1::new();
 
     let offer_dlc = alice_dlc_manager
         .lock()
         .unwrap()
-        .send_offer(&dlc_contract, bob_public_key)
+        .send_offer(dlc_contract, bob_public_key)
         .unwrap();
     match &offer_dlc.contract_info {
         ContractInfo::SingleContractInfo(s) => s.contract_info.oracle_info.validate(&secp).unwrap(),
@@ -169,7 +297,10 @@ async fn main() {
     bob_dlc_manager
         .lock()
         .unwrap()
-        .on_dlc_message(&dlc_messages::Message::Offer(offer_dlc), alice_public_key)
+        .on_dlc_message(
+            &relay_dlc_msg(dlc_messages::Message::Offer(offer_dlc)),
+            alice_public_key,
+        )
         .unwrap();
     let contract_offers = bob_dlc_manager
         .lock()
@@ -177,6 +308,9 @@ async fn main() {
         .get_store()
         .get_contract_offers()
         .unwrap();
+    if let Some(bob_local_wallet) = bob_local_wallet_or {
+        bob_local_wallet.refresh().unwrap();
+    }
     let (contract_id, _counterparty_pub_key, accept_dlc) = bob_dlc_manager
         .lock()
         .unwrap()
@@ -185,13 +319,16 @@ async fn main() {
     let sign_dlc_message = alice_dlc_manager
         .lock()
         .unwrap()
-        .on_dlc_message(&dlc_messages::Message::Accept(accept_dlc), bob_public_key)
+        .on_dlc_message(
+            &relay_dlc_msg(dlc_messages::Message::Accept(accept_dlc)),
+            bob_public_key,
+        )
         .unwrap()
         .unwrap();
     bob_dlc_manager
         .lock()
         .unwrap()
-        .on_dlc_message(&sign_dlc_message, alice_public_key)
+        .on_dlc_message(&relay_dlc_msg(sign_dlc_message), alice_public_key)
         .unwrap();
     alice_dlc_manager.lock().unwrap().periodic_check().unwrap();
     bob_dlc_manager.lock().unwrap().periodic_check().unwrap();
@@ -237,12 +374,7 @@ async fn main() {
     println!("Funding confirmed!");
     alice_dlc_manager.lock().unwrap().periodic_check().unwrap();
     bob_dlc_manager.lock().unwrap().periodic_check().unwrap();
-    oracle
-        .create_attestation(
-            &oracle_event_id,
-            resolvr_oracle::BountyOutcome::Insufficient,
-        )
-        .unwrap();
+    attest();
     loop {
         alice_dlc_manager.lock().unwrap().periodic_check().unwrap();
         bob_dlc_manager.lock().unwrap().periodic_check().unwrap();
@@ -272,6 +404,38 @@ async fn main() {
     }
 }
 
+/// Passes a DLC message through the Nostr wire codec, as happens when it is
+/// sent between apps, and checks that it comes out unchanged.
+fn relay_dlc_msg(msg: dlc_messages::Message) -> dlc_messages::Message {
+    let chunks = encode_dlc_msg(&DlcMsg::Protocol(msg)).expect("Error encoding DLC message.");
+
+    // Deliver the chunks in reverse to make sure reassembly doesn't depend on
+    // the order that relays return them in.
+    let mut reassembler = DlcMsgReassembler::new();
+    let mut decoded_msg = None;
+    for chunk in chunks.iter().rev() {
+        if let Some(msg) = reassembler
+            .add_chunk((), chunk)
+            .expect("Error decoding DLC message.")
+        {
+            decoded_msg = Some(msg);
+        }
+    }
+    let decoded_msg = decoded_msg.expect("DLC message was not reassembled.");
+
+    assert_eq!(
+        encode_dlc_msg(&decoded_msg).expect("Error re-encoding DLC message."),
+        chunks,
+        "DLC message changed in transit."
+    );
+    println!("Relayed DLC message in {} chunk(s).", chunks.len());
+
+    match decoded_msg {
+        DlcMsg::Protocol(msg) => msg,
+        DlcMsg::Reject(_) => panic!("DLC message was decoded as a reject."),
+    }
+}
+
 /// Create a DLC contract template for a bounty.
 fn create_bounty_contract(
     bounty_amount_sats: u64,
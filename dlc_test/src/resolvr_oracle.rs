@@ -3,6 +3,9 @@ use dlc_manager::Oracle;
 use dlc_messages::oracle_msgs::{
     EnumEventDescriptor, EventDescriptor, OracleAnnouncement, OracleAttestation,
 };
+use escrow_agent_messages::{
+    get_completion_percentage_event_descriptor, get_completion_percentage_outcomes,
+};
 use mocks::mock_oracle_provider::MockOracle;
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
@@ -37,14 +40,22 @@ impl ResolvrOracle {
 
     /// Creates a new announcement and returns the event ID.
     pub fn create_announcement(&self) -> String {
-        let event_id = Self::generate_new_event_id();
-
-        let event_descriptor = EventDescriptor::EnumEvent(EnumEventDescriptor {
+        self.add_event(&EventDescriptor::EnumEvent(EnumEventDescriptor {
             outcomes: vec![
                 BOUNTY_COMPLETE_ORACLE_MESSAGE.to_string(),
                 BOUNTY_INSUFFICIENT_ORACLE_MESSAGE.to_string(),
             ],
-        });
+        }))
+    }
+
+    /// Creates a new announcement for a completion percentage, like escrow
+    /// agents do, and returns the event ID.
+    pub fn create_completion_percentage_announcement(&self) -> String {
+        self.add_event(&get_completion_percentage_event_descriptor())
+    }
+
+    fn add_event(&self, event_descriptor: &EventDescriptor) -> String {
+        let event_id = Self::generate_new_event_id();
 
         let maturity_epoch = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -55,7 +66,7 @@ impl ResolvrOracle {
         self.mock_oracle
             .lock()
             .unwrap()
-            .add_event(&event_id, &event_descriptor, maturity_epoch);
+            .add_event(&event_id, event_descriptor, maturity_epoch);
         event_id
     }
 
@@ -73,6 +84,20 @@ impl ResolvrOracle {
         Ok(())
     }
 
+    /// Creates an attestation to a completion percentage for the given event ID.
+    pub fn create_completion_percentage_attestation(
+        &self,
+        event_id: &str,
+        percent: u8,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let outcomes = get_completion_percentage_outcomes(percent)?;
+        self.mock_oracle
+            .lock()
+            .unwrap()
+            .add_attestation(event_id, &outcomes);
+        Ok(())
+    }
+
     /// Creates an new unique hex-encoded 256-bit event ID.
     fn generate_new_event_id() -> String {
         let mut rng = thread_rng();
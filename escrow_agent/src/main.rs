@@ -0,0 +1,605 @@
+mod oracle;
+mod storage;
+
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{KeyPair, Secp256k1};
+use dlc_messages::oracle_msgs::EventDescriptor;
+use escrow_agent_messages::error::ResolvrError;
+use escrow_agent_messages::{
+    decode_oracle_announcement, encode_oracle_announcement, encode_oracle_attestation,
+    get_completion_percentage_outcomes, AdjudicationRequest, AdjudicationRequestState,
+    AdjudicationRequestStatus, EscrowAgentRequest, EscrowAgentResponse,
+    BOUNTY_COMPLETE_ORACLE_MESSAGE, BOUNTY_INSUFFICIENT_ORACLE_MESSAGE, COMPLETION_PERCENTAGE_UNIT,
+    ORACLE_ANNOUNCEMENT_EVENT_KIND, ORACLE_ATTESTATION_EVENT_KIND,
+};
+use nostr_sdk::nips::nip04;
+use nostr_sdk::nips::nip19::ToBech32;
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{
+    Client, Event, EventBuilder, Filter, Keys, Kind, RelayPoolNotification, Tag, Timestamp,
+};
+use oracle::OracleSigner;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use storage::{AgentStorage, StoredAdjudicationRequest};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// Relays used when `ESCROW_AGENT_RELAYS` isn't set.
+static DEFAULT_NOSTR_RELAYS: [&str; 2] = ["wss://relay.damus.io", "wss://nos.lol"];
+
+/// How far before the newest handled request to re-read from relays on
+/// startup. Covers events that relays received late.
+const RECEIVE_CURSOR_SLACK_SECS: u64 = 60 * 60;
+
+/// How long after approval a bounty's oracle event matures, for requests that
+/// don't set a deadline and when `ESCROW_AGENT_BOUNTY_DURATION_SECS` isn't set.
+const DEFAULT_BOUNTY_DURATION_SECS: u32 = 60 * 60 * 24 * 30;
+
+struct EscrowAgentConfig {
+    data_dir: String,
+    relays: Vec<String>,
+
+    /// Whether to approve adjudication requests as soon as they arrive,
+    /// rather than waiting for the operator.
+    auto_approve: bool,
+
+    bounty_duration_secs: u32,
+}
+
+impl EscrowAgentConfig {
+    fn from_env() -> Self {
+        Self {
+            data_dir: std::env::var("ESCROW_AGENT_DATA_DIR")
+                .unwrap_or_else(|_| String::from("data")),
+            relays: match std::env::var("ESCROW_AGENT_RELAYS") {
+                Ok(v) => v.split(',').map(|relay| relay.trim().to_string()).collect(),
+                Err(_) => DEFAULT_NOSTR_RELAYS
+                    .iter()
+                    .map(|relay| relay.to_string())
+                    .collect(),
+            },
+            auto_approve: std::env::var("ESCROW_AGENT_AUTO_APPROVE")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            bounty_duration_secs: std::env::var("ESCROW_AGENT_BOUNTY_DURATION_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_BOUNTY_DURATION_SECS),
+        }
+    }
+}
+
+/// A headless escrow agent. Answers adjudication requests sent as Nostr DMs,
+/// and publishes oracle announcements and attestations for approved bounties.
+struct EscrowAgentDaemon {
+    config: EscrowAgentConfig,
+    keys: Keys,
+    client: Client,
+    signer: OracleSigner,
+    storage: AgentStorage,
+
+    /// Serializes changes to adjudication requests between incoming requests
+    /// and operator commands.
+    state_lock: tokio::sync::Mutex<()>,
+}
+
+#[tokio::main]
+async fn main() {
+    let config = EscrowAgentConfig::from_env();
+    let storage = AgentStorage::new(&config.data_dir).expect("Error opening agent storage.");
+
+    // The agent uses the same key for Nostr and for signing oracle events, so
+    // its npub doubles as its oracle public key.
+    let secret_key = match storage.get_secret_key().expect("Error reading agent key.") {
+        Some(v) => v,
+        None => {
+            let mut secret_key = [0u8; 32];
+            thread_rng().fill_bytes(&mut secret_key);
+            storage
+                .save_secret_key(&secret_key)
+                .expect("Error saving agent key.");
+            secret_key
+        }
+    };
+    let key_pair = KeyPair::from_seckey_slice(&Secp256k1::new(), &secret_key)
+        .expect("Error loading agent key.");
+    let keys = Keys::new(
+        nostr_sdk::secp256k1::SecretKey::from_slice(&secret_key).expect("Error loading agent key."),
+    );
+
+    println!(
+        "Escrow agent npub: {}",
+        keys.public_key()
+            .to_bech32()
+            .expect("Error encoding agent npub.")
+    );
+
+    let client = Client::new(&keys);
+    for relay in &config.relays {
+        client
+            .add_relay(relay.as_str(), None)
+            .await
+            .expect("Error adding relay.");
+    }
+    client.connect().await;
+
+    // Pick up where we left off, so that requests sent while the agent was
+    // down are answered. Requests that were already handled are skipped.
+    let now = Timestamp::now().as_u64();
+    let since = match storage
+        .get_receive_cursor()
+        .expect("Error reading receive cursor.")
+    {
+        Some(cursor) => cursor.saturating_sub(RECEIVE_CURSOR_SLACK_SECS),
+        None => now,
+    };
+    storage
+        .prune_handled_event_ids(now)
+        .expect("Error pruning handled events.");
+    client
+        .subscribe(vec![Filter::new()
+            .kind(Kind::EncryptedDirectMessage)
+            .pubkey(keys.public_key())
+            .since(Timestamp::from(since))])
+        .await;
+
+    let agent = Arc::new(EscrowAgentDaemon {
+        config,
+        keys,
+        client,
+        signer: OracleSigner::new(key_pair),
+        storage,
+        state_lock: tokio::sync::Mutex::new(()),
+    });
+
+    // Relays aren't guaranteed to keep events around, so make sure everything
+    // we've published is still available. This also moves events published
+    // under the old, non-replaceable kinds over to the current ones.
+    if let Err(e) = agent.republish_events().await {
+        println!("Error republishing events: {}", e);
+    }
+
+    tokio::task::spawn(agent.clone().run_request_loop());
+    agent.run_console().await;
+}
+
+impl EscrowAgentDaemon {
+    async fn run_request_loop(self: Arc<Self>) {
+        let secret_key = self
+            .keys
+            .secret_key()
+            .expect("Agent keys should have a secret key.");
+
+        let mut notifications = self.client.notifications();
+        while let Ok(notification) = notifications.recv().await {
+            let event = match notification {
+                RelayPoolNotification::Event(_, event) => event,
+                RelayPoolNotification::Shutdown => break,
+                _ => continue,
+            };
+
+            if event.kind != Kind::EncryptedDirectMessage {
+                continue;
+            }
+
+            let event_id = event.id.to_hex();
+            match self.storage.is_event_handled(&event_id) {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(e) => {
+                    println!("Error checking DM {}: {}", event.id, e);
+                    continue;
+                }
+            }
+
+            let content = match nip04::decrypt(&secret_key, &event.pubkey, &event.content) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Error decrypting DM {}: {}", event.id, e);
+                    continue;
+                }
+            };
+
+            // Ignore any DMs that aren't requests.
+            let request: EscrowAgentRequest = match serde_json::from_str(&content) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            let _state_guard = self.state_lock.lock().await;
+            let response = match self.handle_request(&event, request).await {
+                Ok(status) => EscrowAgentResponse::AdjudicationRequestStatus(status),
+                Err(e) => EscrowAgentResponse::Error(e),
+            };
+
+            // A request whose response couldn't be sent is handled again on
+            // the next start, which is safe since handling a request twice
+            // returns the same status.
+            if let Err(e) = self.send_response(event.pubkey, &response, &event).await {
+                println!("Error responding to request {}: {}", event.id, e);
+                continue;
+            }
+            if let Err(e) = self
+                .storage
+                .mark_event_handled(&event_id, event.created_at.as_u64())
+            {
+                println!("Error saving handled request {}: {}", event.id, e);
+            }
+        }
+    }
+
+    async fn handle_request(
+        &self,
+        event: &Event,
+        request: EscrowAgentRequest,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        match request {
+            EscrowAgentRequest::RequestAdjudication(adjudication_request) => {
+                self.receive_adjudication_request(event.pubkey, adjudication_request)
+                    .await
+            }
+            EscrowAgentRequest::GetAdjudicationRequestStatus { oracle_event_id } => {
+                match self.storage.get_request(&oracle_event_id)? {
+                    Some(request) => Ok(request_status(&request)),
+                    None => Err(ResolvrError::NotFound(format!(
+                        "No request found for event ID {}.",
+                        oracle_event_id
+                    ))),
+                }
+            }
+        }
+    }
+
+    async fn receive_adjudication_request(
+        &self,
+        requester: XOnlyPublicKey,
+        adjudication_request: AdjudicationRequest,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        let oracle_event_id = adjudication_request.bounty_template.oracle_event_id.clone();
+        if oracle_event_id.is_empty() {
+            return Err(ResolvrError::InvalidInput(String::from(
+                "Oracle event ID must not be empty.",
+            )));
+        }
+
+        if let Some(existing_request) = self.storage.get_request(&oracle_event_id)? {
+            if existing_request.requester != requester.to_string() {
+                return Err(ResolvrError::InvalidInput(format!(
+                    "Event ID {} is already in use.",
+                    oracle_event_id
+                )));
+            }
+            return Ok(request_status(&existing_request));
+        }
+
+        let request = StoredAdjudicationRequest {
+            requester: requester.to_string(),
+            bounty_template: adjudication_request.bounty_template,
+            state: AdjudicationRequestState::InReview,
+            announcement: None,
+            nonce_secrets: Vec::new(),
+            attestation: None,
+        };
+        self.storage.save_request(&request)?;
+        println!(
+            "Received adjudication request for event {}: {}",
+            oracle_event_id, request.bounty_template.title
+        );
+
+        if self.config.auto_approve {
+            return self.approve_request(&oracle_event_id).await;
+        }
+
+        Ok(request_status(&request))
+    }
+
+    /// Approves a request, announcing an oracle event for it.
+    async fn approve_request(
+        &self,
+        oracle_event_id: &str,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        let mut request =
+            self.get_request_in_state(oracle_event_id, AdjudicationRequestState::InReview)?;
+
+        let event_descriptor = request
+            .bounty_template
+            .get_event_descriptor()
+            .map_err(ResolvrError::InvalidInput)?;
+        let now = now_epoch_secs();
+        let maturity_epoch = match request.bounty_template.deadline {
+            Some(deadline) if deadline > now => deadline,
+            Some(_) => {
+                return Err(ResolvrError::InvalidInput(String::from(
+                    "Bounty deadline has already passed.",
+                )))
+            }
+            None => now + self.config.bounty_duration_secs,
+        };
+        let (announcement, nonce_secrets) =
+            self.signer
+                .create_announcement(oracle_event_id, event_descriptor, maturity_epoch);
+
+        // Persist the nonces before publishing anything, so that an approved
+        // event can always be attested to.
+        request.state = AdjudicationRequestState::Approved;
+        request.announcement = Some(encode_oracle_announcement(&announcement));
+        request.nonce_secrets = nonce_secrets.iter().map(hex::encode).collect();
+        self.storage.save_request(&request)?;
+
+        self.publish_oracle_event(
+            ORACLE_ANNOUNCEMENT_EVENT_KIND,
+            oracle_event_id,
+            request.announcement.clone().unwrap_or_default(),
+        )
+        .await?;
+        self.notify_requester(&request).await;
+
+        Ok(request_status(&request))
+    }
+
+    async fn deny_request(
+        &self,
+        oracle_event_id: &str,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        let mut request =
+            self.get_request_in_state(oracle_event_id, AdjudicationRequestState::InReview)?;
+        request.state = AdjudicationRequestState::Denied;
+        self.storage.save_request(&request)?;
+        self.notify_requester(&request).await;
+        Ok(request_status(&request))
+    }
+
+    /// Signs and publishes the outcome of an approved bounty. Each event can
+    /// only be attested to once.
+    async fn attest(&self, oracle_event_id: &str, outcome: &str) -> Result<(), ResolvrError> {
+        let mut request =
+            self.get_request_in_state(oracle_event_id, AdjudicationRequestState::Approved)?;
+        if request.attestation.is_some() {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Event {} was already attested to.",
+                oracle_event_id
+            )));
+        }
+
+        let announcement = match &request.announcement {
+            Some(v) => decode_oracle_announcement(v).map_err(ResolvrError::Storage)?,
+            None => {
+                return Err(ResolvrError::Storage(format!(
+                    "Event {} has no announcement.",
+                    oracle_event_id
+                )))
+            }
+        };
+        let outcomes = match &announcement.oracle_event.event_descriptor {
+            EventDescriptor::EnumEvent(descriptor) => {
+                if !descriptor.outcomes.iter().any(|o| o == outcome) {
+                    return Err(ResolvrError::InvalidInput(format!(
+                        "Outcome {} was not announced.",
+                        outcome
+                    )));
+                }
+                vec![outcome.to_string()]
+            }
+            EventDescriptor::DigitDecompositionEvent(descriptor) => {
+                if descriptor.unit != COMPLETION_PERCENTAGE_UNIT {
+                    return Err(ResolvrError::InvalidInput(format!(
+                        "Unsupported numeric event unit: {}",
+                        descriptor.unit
+                    )));
+                }
+                let percent = outcome.trim_end_matches('%').parse::<u8>().map_err(|_| {
+                    ResolvrError::InvalidInput(format!(
+                        "Invalid completion percentage: {}",
+                        outcome
+                    ))
+                })?;
+                get_completion_percentage_outcomes(percent).map_err(ResolvrError::InvalidInput)?
+            }
+        };
+
+        let nonce_secrets = request
+            .nonce_secrets
+            .iter()
+            .map(|nonce_secret| {
+                hex::decode(nonce_secret)
+                    .ok()
+                    .and_then(|v| v.try_into().ok())
+                    .ok_or_else(|| ResolvrError::Storage(String::from("Stored nonce is invalid.")))
+            })
+            .collect::<Result<Vec<[u8; 32]>, ResolvrError>>()?;
+
+        let attestation = self
+            .signer
+            .create_attestation(outcomes, &nonce_secrets)
+            .map_err(ResolvrError::Internal)?;
+        request.attestation = Some(encode_oracle_attestation(&attestation));
+        self.storage.save_request(&request)?;
+
+        self.publish_oracle_event(
+            ORACLE_ATTESTATION_EVENT_KIND,
+            oracle_event_id,
+            request.attestation.clone().unwrap_or_default(),
+        )
+        .await
+    }
+
+    async fn republish_events(&self) -> Result<(), ResolvrError> {
+        for request in self.storage.get_requests()? {
+            let oracle_event_id = &request.bounty_template.oracle_event_id;
+            if let Some(announcement) = request.announcement {
+                self.publish_oracle_event(
+                    ORACLE_ANNOUNCEMENT_EVENT_KIND,
+                    oracle_event_id,
+                    announcement,
+                )
+                .await?;
+            }
+            if let Some(attestation) = request.attestation {
+                self.publish_oracle_event(
+                    ORACLE_ATTESTATION_EVENT_KIND,
+                    oracle_event_id,
+                    attestation,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_request_in_state(
+        &self,
+        oracle_event_id: &str,
+        state: AdjudicationRequestState,
+    ) -> Result<StoredAdjudicationRequest, ResolvrError> {
+        let request = match self.storage.get_request(oracle_event_id)? {
+            Some(v) => v,
+            None => {
+                return Err(ResolvrError::NotFound(format!(
+                    "No request found for event ID {}.",
+                    oracle_event_id
+                )))
+            }
+        };
+        if request.state != state {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Request for event {} is {:?}, expected {:?}.",
+                oracle_event_id, request.state, state
+            )));
+        }
+        Ok(request)
+    }
+
+    async fn publish_oracle_event(
+        &self,
+        kind: u64,
+        oracle_event_id: &str,
+        content: String,
+    ) -> Result<(), ResolvrError> {
+        let event = EventBuilder::new(
+            Kind::Custom(kind),
+            content,
+            &[Tag::Identifier(oracle_event_id.to_string())],
+        )
+        .to_event(&self.keys)
+        .map_err(|e| ResolvrError::Internal(format!("Error building event: {}", e)))?;
+
+        self.client
+            .send_event(event)
+            .await
+            .map(|_| ())
+            .map_err(|e| ResolvrError::Transport(format!("Error publishing event: {}", e)))
+    }
+
+    async fn send_response(
+        &self,
+        receiver: XOnlyPublicKey,
+        response: &EscrowAgentResponse,
+        request_event: &Event,
+    ) -> Result<(), ResolvrError> {
+        let content = serde_json::to_string(response)
+            .map_err(|e| ResolvrError::Internal(format!("Error encoding response: {}", e)))?;
+        self.client
+            .send_direct_msg(receiver, content, Some(request_event.id))
+            .await
+            .map(|_| ())
+            .map_err(|e| ResolvrError::Transport(format!("Error sending DM: {}", e)))
+    }
+
+    /// Lets the requester know that their request's state changed, without
+    /// waiting for them to ask.
+    async fn notify_requester(&self, request: &StoredAdjudicationRequest) {
+        let requester = match XOnlyPublicKey::from_str(&request.requester) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error parsing requester public key: {}", e);
+                return;
+            }
+        };
+
+        let response = EscrowAgentResponse::AdjudicationRequestStatus(request_status(request));
+        let content = match serde_json::to_string(&response) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error encoding response: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.client.send_direct_msg(requester, content, None).await {
+            println!("Error notifying requester: {}", e);
+        }
+    }
+
+    /// Reads operator commands from stdin until it is closed.
+    async fn run_console(&self) {
+        println!("Commands:");
+        println!("  list");
+        println!("  approve <event_id>");
+        println!("  deny <event_id>");
+        println!("  attest <event_id> <complete|insufficient|partial outcome label|percentage>");
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let _state_guard = self.state_lock.lock().await;
+            let result = match args.as_slice() {
+                ["list"] => self.print_requests(),
+                ["approve", oracle_event_id] => {
+                    self.approve_request(oracle_event_id).await.map(|_| ())
+                }
+                ["deny", oracle_event_id] => self.deny_request(oracle_event_id).await.map(|_| ()),
+                ["attest", oracle_event_id, "complete"] => {
+                    self.attest(oracle_event_id, BOUNTY_COMPLETE_ORACLE_MESSAGE)
+                        .await
+                }
+                ["attest", oracle_event_id, "insufficient"] => {
+                    self.attest(oracle_event_id, BOUNTY_INSUFFICIENT_ORACLE_MESSAGE)
+                        .await
+                }
+                ["attest", oracle_event_id, outcome] => self.attest(oracle_event_id, outcome).await,
+                [] => Ok(()),
+                _ => Err(ResolvrError::InvalidInput(format!(
+                    "Unknown command: {}",
+                    line
+                ))),
+            };
+
+            match result {
+                Ok(()) => println!("Ok."),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+    }
+
+    fn print_requests(&self) -> Result<(), ResolvrError> {
+        for request in self.storage.get_requests()? {
+            println!(
+                "{} {:?}{} - {}",
+                request.bounty_template.oracle_event_id,
+                request.state,
+                if request.attestation.is_some() {
+                    " (attested)"
+                } else {
+                    ""
+                },
+                request.bounty_template.title
+            );
+        }
+        Ok(())
+    }
+}
+
+fn request_status(request: &StoredAdjudicationRequest) -> AdjudicationRequestStatus {
+    AdjudicationRequestStatus {
+        oracle_event_id: request.bounty_template.oracle_event_id.clone(),
+        adjudication_state: request.state,
+    }
+}
+
+fn now_epoch_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
@@ -0,0 +1,108 @@
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{All, KeyPair, Message, Secp256k1, XOnlyPublicKey};
+use dlc_messages::oracle_msgs::{
+    EventDescriptor, OracleAnnouncement, OracleAttestation, OracleEvent,
+};
+use lightning::util::ser::Writeable;
+
+/// Signs oracle announcements and attestations with the agent's key.
+pub struct OracleSigner {
+    secp: Secp256k1<All>,
+    key_pair: KeyPair,
+}
+
+impl OracleSigner {
+    pub fn new(key_pair: KeyPair) -> Self {
+        Self {
+            secp: Secp256k1::new(),
+            key_pair,
+        }
+    }
+
+    pub fn get_public_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_keypair(&self.key_pair).0
+    }
+
+    /// Creates a signed announcement for a new event, returning it along with
+    /// the secret nonces needed to later attest to it. The nonces must be kept
+    /// secret and never be used to sign more than one outcome, otherwise the
+    /// agent's key is leaked.
+    pub fn create_announcement(
+        &self,
+        event_id: &str,
+        event_descriptor: EventDescriptor,
+        maturity_epoch: u32,
+    ) -> (OracleAnnouncement, Vec<[u8; 32]>) {
+        let nb_nonces = match &event_descriptor {
+            EventDescriptor::EnumEvent(_) => 1,
+            EventDescriptor::DigitDecompositionEvent(d) => {
+                d.nb_digits as usize + usize::from(d.is_signed)
+            }
+        };
+
+        let mut rng = thread_rng();
+        let mut nonce_secrets = Vec::with_capacity(nb_nonces);
+        let mut oracle_nonces = Vec::with_capacity(nb_nonces);
+        for _ in 0..nb_nonces {
+            let mut nonce_secret = [0u8; 32];
+            rng.fill_bytes(&mut nonce_secret);
+            let nonce_key_pair = KeyPair::from_seckey_slice(&self.secp, &nonce_secret)
+                .expect("Random nonce should be a valid secret key.");
+            oracle_nonces.push(XOnlyPublicKey::from_keypair(&nonce_key_pair).0);
+            nonce_secrets.push(nonce_secret);
+        }
+
+        let oracle_event = OracleEvent {
+            oracle_nonces,
+            event_maturity_epoch: maturity_epoch,
+            event_descriptor,
+            event_id: event_id.to_string(),
+        };
+
+        let msg = Message::from_hashed_data::<sha256::Hash>(&oracle_event.encode());
+        let announcement = OracleAnnouncement {
+            announcement_signature: self.secp.sign_schnorr(&msg, &self.key_pair),
+            oracle_public_key: self.get_public_key(),
+            oracle_event,
+        };
+
+        (announcement, nonce_secrets)
+    }
+
+    /// Signs the given outcomes (one per nonce) using the secret nonces
+    /// returned when the event was announced.
+    pub fn create_attestation(
+        &self,
+        outcomes: Vec<String>,
+        nonce_secrets: &[[u8; 32]],
+    ) -> Result<OracleAttestation, String> {
+        if outcomes.len() != nonce_secrets.len() {
+            return Err(format!(
+                "Expected {} outcomes but got {}.",
+                nonce_secrets.len(),
+                outcomes.len()
+            ));
+        }
+
+        let signatures = outcomes
+            .iter()
+            .zip(nonce_secrets.iter())
+            .map(|(outcome, nonce_secret)| {
+                let msg = Message::from_hashed_data::<sha256::Hash>(outcome.as_bytes());
+                dlc::secp_utils::schnorrsig_sign_with_nonce(
+                    &self.secp,
+                    &msg,
+                    &self.key_pair,
+                    nonce_secret,
+                )
+            })
+            .collect();
+
+        Ok(OracleAttestation {
+            oracle_public_key: self.get_public_key(),
+            signatures,
+            outcomes,
+        })
+    }
+}
@@ -0,0 +1,197 @@
+use escrow_agent_messages::error::ResolvrError;
+use escrow_agent_messages::{AdjudicationRequestState, BountyTemplate};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+const SECRET_KEY_KEY: &[u8] = b"secret_key";
+const RECEIVE_CURSOR_KEY: &[u8] = b"receive_cursor";
+const ADJUDICATION_REQUESTS_TREE: &[u8] = b"adjudication_requests";
+const HANDLED_EVENT_IDS_TREE: &[u8] = b"handled_event_ids";
+
+/// How long to remember which events were already handled. Must be longer
+/// than the window that the agent re-reads from relays on startup.
+const HANDLED_EVENT_ID_RETENTION_SECS: u64 = 60 * 60 * 24 * 7;
+
+/// An adjudication request along with everything the agent has produced for
+/// it so far.
+#[derive(Serialize, Deserialize)]
+pub struct StoredAdjudicationRequest {
+    /// Hex-encoded Nostr public key of the client that sent the request.
+    pub requester: String,
+
+    pub bounty_template: BountyTemplate,
+
+    pub state: AdjudicationRequestState,
+
+    /// Hex-encoded announcement, set once the request is approved.
+    pub announcement: Option<String>,
+
+    /// Hex-encoded secret nonces for the announcement. These must never be
+    /// used to sign more than one set of outcomes.
+    pub nonce_secrets: Vec<String>,
+
+    /// Hex-encoded attestation, set once the agent has adjudicated the bounty.
+    pub attestation: Option<String>,
+}
+
+/// Persists the agent's key and adjudication requests in a sled database,
+/// along with which request events it has already handled.
+pub struct AgentStorage {
+    db: sled::Db,
+    requests: sled::Tree,
+    handled_event_ids: sled::Tree,
+}
+
+impl AgentStorage {
+    pub fn new(path: &str) -> Result<Self, ResolvrError> {
+        let db = sled::open(path)
+            .map_err(|e| ResolvrError::Storage(format!("Error opening database: {}", e)))?;
+        let open_tree = |name: &[u8]| {
+            db.open_tree(name)
+                .map_err(|e| ResolvrError::Storage(format!("Error opening database tree: {}", e)))
+        };
+        Ok(Self {
+            requests: open_tree(ADJUDICATION_REQUESTS_TREE)?,
+            handled_event_ids: open_tree(HANDLED_EVENT_IDS_TREE)?,
+            db,
+        })
+    }
+
+    pub fn get_secret_key(&self) -> Result<Option<[u8; 32]>, ResolvrError> {
+        let bytes = match self.db.get(SECRET_KEY_KEY) {
+            Ok(Some(v)) => v,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error reading secret key: {}",
+                    e
+                )))
+            }
+        };
+
+        match bytes.as_ref().try_into() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(ResolvrError::Storage(String::from(
+                "Stored secret key is not 32 bytes.",
+            ))),
+        }
+    }
+
+    pub fn save_secret_key(&self, secret_key: &[u8; 32]) -> Result<(), ResolvrError> {
+        self.db
+            .insert(SECRET_KEY_KEY, secret_key.as_slice())
+            .map_err(|e| ResolvrError::Storage(format!("Error saving secret key: {}", e)))?;
+        self.flush()
+    }
+
+    pub fn get_request(
+        &self,
+        oracle_event_id: &str,
+    ) -> Result<Option<StoredAdjudicationRequest>, ResolvrError> {
+        match self.requests.get(oracle_event_id) {
+            Ok(Some(v)) => serde_json::from_slice(&v).map(Some).map_err(|e| {
+                ResolvrError::Storage(format!("Error decoding adjudication request: {}", e))
+            }),
+            Ok(None) => Ok(None),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error reading adjudication request: {}",
+                e
+            ))),
+        }
+    }
+
+    pub fn get_requests(&self) -> Result<Vec<StoredAdjudicationRequest>, ResolvrError> {
+        self.requests
+            .iter()
+            .values()
+            .map(|v| match v {
+                Ok(v) => serde_json::from_slice(&v).map_err(|e| {
+                    ResolvrError::Storage(format!("Error decoding adjudication request: {}", e))
+                }),
+                Err(e) => Err(ResolvrError::Storage(format!(
+                    "Error reading adjudication request: {}",
+                    e
+                ))),
+            })
+            .collect()
+    }
+
+    pub fn save_request(&self, request: &StoredAdjudicationRequest) -> Result<(), ResolvrError> {
+        let bytes = serde_json::to_vec(request).map_err(|e| {
+            ResolvrError::Storage(format!("Error encoding adjudication request: {}", e))
+        })?;
+        self.requests
+            .insert(request.bounty_template.oracle_event_id.as_str(), bytes)
+            .map_err(|e| {
+                ResolvrError::Storage(format!("Error saving adjudication request: {}", e))
+            })?;
+        self.flush()
+    }
+
+    /// Returns the creation time of the newest request event that was
+    /// handled, if any.
+    pub fn get_receive_cursor(&self) -> Result<Option<u64>, ResolvrError> {
+        match self.db.get(RECEIVE_CURSOR_KEY) {
+            Ok(v) => Ok(v.map(|v| decode_u64(&v))),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error reading receive cursor: {}",
+                e
+            ))),
+        }
+    }
+
+    pub fn is_event_handled(&self, event_id: &str) -> Result<bool, ResolvrError> {
+        self.handled_event_ids
+            .contains_key(event_id)
+            .map_err(|e| ResolvrError::Storage(format!("Error reading handled events: {}", e)))
+    }
+
+    /// Records that a request event was handled, so that it's ignored if
+    /// relays deliver it again, and moves the receive cursor up to it.
+    pub fn mark_event_handled(&self, event_id: &str, created_at: u64) -> Result<(), ResolvrError> {
+        let result: Result<(), TransactionError<()>> = (&*self.db, &self.handled_event_ids)
+            .transaction(|(db, handled_event_ids)| {
+                handled_event_ids.insert(event_id, &created_at.to_be_bytes()[..])?;
+                let cursor = db.get(RECEIVE_CURSOR_KEY)?.map(|v| decode_u64(&v));
+                if cursor.map_or(true, |cursor| cursor < created_at) {
+                    db.insert(RECEIVE_CURSOR_KEY, &created_at.to_be_bytes()[..])?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            });
+        result
+            .map_err(|e| ResolvrError::Storage(format!("Error saving handled event: {:?}", e)))?;
+        self.flush()
+    }
+
+    /// Forgets about handled events that are too old to be re-delivered.
+    pub fn prune_handled_event_ids(&self, now: u64) -> Result<(), ResolvrError> {
+        let cutoff = now.saturating_sub(HANDLED_EVENT_ID_RETENTION_SECS);
+        for entry in self.handled_event_ids.iter() {
+            let (key, value) = entry.map_err(|e| {
+                ResolvrError::Storage(format!("Error reading handled events: {}", e))
+            })?;
+            if decode_u64(&value) < cutoff {
+                self.handled_event_ids.remove(key).map_err(|e| {
+                    ResolvrError::Storage(format!("Error pruning handled events: {}", e))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes to disk, since losing a nonce after publishing an announcement
+    /// would make the event impossible to attest to.
+    fn flush(&self) -> Result<(), ResolvrError> {
+        self.db
+            .flush()
+            .map(|_| ())
+            .map_err(|e| ResolvrError::Storage(format!("Error flushing database: {}", e)))
+    }
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
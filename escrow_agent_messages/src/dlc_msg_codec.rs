@@ -0,0 +1,523 @@
+//! Encoding of DLC messages into Nostr event content.
+//!
+//! Besides the messages of the DLC spec, counterparties exchange a `RejectDlc`
+//! to call off a contract before it's signed, which the spec has no message
+//! for (see `DlcMsg`).
+//!
+//! A message is serialized with its `lightning` wire encoding, prefixed with a
+//! type tag (see `serialize_dlc_msg`), deflate-compressed and then split into
+//! one or more chunks, each of which fits in a single event. Every chunk is a
+//! string of the form
+//! `resolvr-dlc/<version>:<message id>:<index>:<total>:<base64 data>`, where
+//! the message ID is derived from the compressed payload so that chunks can be
+//! reassembled in any order.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bitcoin::hashes::{sha256, Hash};
+use dlc_messages::{AcceptDlc, Message, OfferDlc, SignDlc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use lightning::util::ser::{Readable, Writeable};
+use std::collections::HashMap;
+use std::hash::Hash as StdHash;
+use std::io::{Cursor, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Version of the encoding described in the module docs. Chunks with any other
+/// version are rejected rather than misinterpreted.
+pub const DLC_MSG_CODEC_VERSION: u8 = 1;
+
+const CHUNK_PREFIX: &str = "resolvr-dlc";
+
+/// Maximum number of payload bytes per chunk. Chosen so that a chunk stays well
+/// below common relay event size limits after base64 and NIP-04 encryption.
+const MAX_CHUNK_DATA_BYTES: usize = 16 * 1024;
+
+/// Upper bound on the number of chunks per message, which bounds how much
+/// memory a single sender can make us hold onto.
+const MAX_CHUNKS_PER_MSG: usize = 64;
+
+/// Upper bound on the size of a message once decompressed. A few times more
+/// than the most that fits in `MAX_CHUNKS_PER_MSG` chunks, since signatures
+/// barely compress, while keeping a small payload of zeros from inflating
+/// into gigabytes.
+const MAX_DECOMPRESSED_BYTES: u64 = 4 * (MAX_CHUNKS_PER_MSG * MAX_CHUNK_DATA_BYTES) as u64;
+
+/// How long to hold onto a partially received message before dropping it.
+const PARTIAL_MSG_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+
+const OFFER_MSG_TYPE: u8 = 0;
+const ACCEPT_MSG_TYPE: u8 = 1;
+const SIGN_MSG_TYPE: u8 = 2;
+const REJECT_MSG_TYPE: u8 = 3;
+
+/// A message sent between counterparties to progress the state of a contract.
+pub enum DlcMsg {
+    /// A message from the DLC spec.
+    Protocol(Message),
+
+    Reject(RejectDlc),
+}
+
+/// Calls off a contract before it's signed, either because the party that was
+/// offered it declined it or because it expired on either side.
+pub struct RejectDlc {
+    /// ID of the offered contract, which is still its temporary ID.
+    pub temporary_contract_id: [u8; 32],
+
+    pub reason: Option<String>,
+}
+
+/// Encodes a DLC message into one or more chunks, each to be sent as the
+/// content of a separate event.
+pub fn encode_dlc_msg(msg: &DlcMsg) -> Result<Vec<String>, String> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder
+        .write_all(&serialize_dlc_msg(msg)?)
+        .map_err(|e| format!("Error compressing DLC message: {}", e))?;
+    let payload = encoder
+        .finish()
+        .map_err(|e| format!("Error compressing DLC message: {}", e))?;
+
+    let chunks: Vec<&[u8]> = payload.chunks(MAX_CHUNK_DATA_BYTES).collect();
+    if chunks.len() > MAX_CHUNKS_PER_MSG {
+        return Err(format!(
+            "DLC message is too large ({} bytes compressed).",
+            payload.len()
+        ));
+    }
+
+    let msg_id = get_msg_id(&payload);
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, data)| {
+            format!(
+                "{}/{}:{}:{}:{}:{}",
+                CHUNK_PREFIX,
+                DLC_MSG_CODEC_VERSION,
+                msg_id,
+                index,
+                chunks.len(),
+                BASE64.encode(data)
+            )
+        })
+        .collect())
+}
+
+/// Serializes a DLC message into its type tag followed by its uncompressed
+/// wire encoding. A `RejectDlc` is encoded as its contract ID followed by the
+/// UTF-8 bytes of its reason, if any.
+pub fn serialize_dlc_msg(msg: &DlcMsg) -> Result<Vec<u8>, String> {
+    let (msg_type, msg_bytes) = match msg {
+        DlcMsg::Protocol(Message::Offer(offer)) => (OFFER_MSG_TYPE, offer.encode()),
+        DlcMsg::Protocol(Message::Accept(accept)) => (ACCEPT_MSG_TYPE, accept.encode()),
+        DlcMsg::Protocol(Message::Sign(sign)) => (SIGN_MSG_TYPE, sign.encode()),
+        DlcMsg::Protocol(_) => return Err(String::from("Unsupported DLC message type.")),
+        DlcMsg::Reject(reject) => {
+            let mut reject_bytes = reject.temporary_contract_id.to_vec();
+            if let Some(reason) = &reject.reason {
+                reject_bytes.extend(reason.as_bytes());
+            }
+            (REJECT_MSG_TYPE, reject_bytes)
+        }
+    };
+
+    let mut bytes = vec![msg_type];
+    bytes.extend(msg_bytes);
+    Ok(bytes)
+}
+
+/// Deserializes a DLC message serialized with `serialize_dlc_msg`.
+pub fn deserialize_dlc_msg(bytes: &[u8]) -> Result<DlcMsg, String> {
+    let (msg_type, msg_bytes) = match bytes.split_first() {
+        Some(v) => v,
+        None => return Err(String::from("Empty DLC message.")),
+    };
+
+    if *msg_type == REJECT_MSG_TYPE {
+        return deserialize_reject_msg(msg_bytes).map(DlcMsg::Reject);
+    }
+
+    let mut reader = Cursor::new(msg_bytes);
+    let msg = match *msg_type {
+        OFFER_MSG_TYPE => OfferDlc::read(&mut reader).map(Message::Offer),
+        ACCEPT_MSG_TYPE => AcceptDlc::read(&mut reader).map(Message::Accept),
+        SIGN_MSG_TYPE => SignDlc::read(&mut reader).map(Message::Sign),
+        _ => return Err(format!("Unknown DLC message type: {}", msg_type)),
+    };
+
+    msg.map(DlcMsg::Protocol)
+        .map_err(|e| format!("Error decoding DLC message: {:?}", e))
+}
+
+fn deserialize_reject_msg(bytes: &[u8]) -> Result<RejectDlc, String> {
+    if bytes.len() < 32 {
+        return Err(String::from("Reject message is truncated."));
+    }
+    let (temporary_contract_id, reason) = bytes.split_at(32);
+    let reason = String::from_utf8(reason.to_vec())
+        .map_err(|e| format!("Error decoding reject reason: {}", e))?;
+
+    let mut id = [0u8; 32];
+    id.copy_from_slice(temporary_contract_id);
+    Ok(RejectDlc {
+        temporary_contract_id: id,
+        reason: if reason.is_empty() {
+            None
+        } else {
+            Some(reason)
+        },
+    })
+}
+
+/// Whether the given event content looks like a DLC message chunk. Content
+/// for which this is false can be safely ignored.
+pub fn is_dlc_msg_chunk(content: &str) -> bool {
+    content.starts_with(CHUNK_PREFIX)
+}
+
+/// Collects chunks of DLC messages from any number of senders, and returns
+/// each message once all of its chunks have arrived.
+pub struct DlcMsgReassembler<K> {
+    partial_msgs: HashMap<(K, String), PartialMsg>,
+}
+
+struct PartialMsg {
+    chunks: Vec<Option<Vec<u8>>>,
+    first_received_at: Instant,
+}
+
+struct Chunk {
+    msg_id: String,
+    index: usize,
+    total: usize,
+    data: Vec<u8>,
+}
+
+impl<K: StdHash + Eq + Clone> DlcMsgReassembler<K> {
+    pub fn new() -> Self {
+        Self {
+            partial_msgs: HashMap::new(),
+        }
+    }
+
+    /// Adds a chunk received from the given sender. Returns the decoded
+    /// message if this was its last missing chunk.
+    pub fn add_chunk(&mut self, sender: K, content: &str) -> Result<Option<DlcMsg>, String> {
+        self.partial_msgs
+            .retain(|_, partial_msg| partial_msg.first_received_at.elapsed() < PARTIAL_MSG_TIMEOUT);
+
+        let chunk = parse_chunk(content)?;
+
+        // Single-chunk messages (by far the most common) skip the buffer.
+        if chunk.total == 1 {
+            return decode_payload(&chunk.data, &chunk.msg_id).map(Some);
+        }
+
+        let key = (sender, chunk.msg_id);
+        let partial_msg = self
+            .partial_msgs
+            .entry(key.clone())
+            .or_insert_with(|| PartialMsg {
+                chunks: vec![None; chunk.total],
+                first_received_at: Instant::now(),
+            });
+        if partial_msg.chunks.len() != chunk.total {
+            return Err(String::from("Chunk count mismatch for DLC message."));
+        }
+        partial_msg.chunks[chunk.index] = Some(chunk.data);
+
+        if partial_msg.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let payload: Vec<u8> = match self.partial_msgs.remove(&key) {
+            Some(partial_msg) => partial_msg.chunks.into_iter().flatten().flatten().collect(),
+            None => return Ok(None),
+        };
+        decode_payload(&payload, &key.1).map(Some)
+    }
+}
+
+impl<K: StdHash + Eq + Clone> Default for DlcMsgReassembler<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a short ID for a message payload, used to group its chunks.
+fn get_msg_id(payload: &[u8]) -> String {
+    hex::encode(&sha256::Hash::hash(payload).into_inner()[..8])
+}
+
+fn parse_chunk(content: &str) -> Result<Chunk, String> {
+    let mut parts = content.splitn(5, ':');
+    let mut next_part = || {
+        parts
+            .next()
+            .ok_or_else(|| String::from("Malformed DLC message chunk."))
+    };
+
+    let version_prefix = next_part()?;
+    if version_prefix != format!("{}/{}", CHUNK_PREFIX, DLC_MSG_CODEC_VERSION) {
+        return Err(format!(
+            "Unsupported DLC message encoding: {}",
+            version_prefix
+        ));
+    }
+
+    let msg_id = next_part()?.to_string();
+    let index: usize = next_part()?
+        .parse()
+        .map_err(|_| String::from("Malformed DLC message chunk index."))?;
+    let total: usize = next_part()?
+        .parse()
+        .map_err(|_| String::from("Malformed DLC message chunk count."))?;
+    if total == 0 || total > MAX_CHUNKS_PER_MSG || index >= total {
+        return Err(String::from("Invalid DLC message chunk index."));
+    }
+
+    let data = BASE64
+        .decode(next_part()?)
+        .map_err(|e| format!("Error decoding DLC message chunk: {}", e))?;
+
+    Ok(Chunk {
+        msg_id,
+        index,
+        total,
+        data,
+    })
+}
+
+fn decode_payload(payload: &[u8], msg_id: &str) -> Result<DlcMsg, String> {
+    if get_msg_id(payload) != msg_id {
+        return Err(String::from("DLC message does not match its ID."));
+    }
+
+    // One byte more than the limit is read, to tell a message that's exactly
+    // at the limit from one that's over it.
+    let mut bytes = Vec::new();
+    DeflateDecoder::new(payload)
+        .take(MAX_DECOMPRESSED_BYTES + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Error decompressing DLC message: {}", e))?;
+    if bytes.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(String::from("DLC message is too large once decompressed."));
+    }
+
+    deserialize_dlc_msg(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a message that can stand in for any DLC message, since a reject
+    /// needs no contract. Its reason is made of hash output so that it doesn't
+    /// compress, which lets long reasons span several chunks.
+    fn get_test_msg(reason_len: usize) -> DlcMsg {
+        let mut reason = String::new();
+        let mut hash = sha256::Hash::hash(b"reason");
+        while reason.len() < reason_len {
+            reason.push_str(&BASE64.encode(hash.into_inner()));
+            hash = sha256::Hash::hash(&hash.into_inner());
+        }
+        reason.truncate(reason_len);
+
+        DlcMsg::Reject(RejectDlc {
+            temporary_contract_id: [7; 32],
+            reason: Some(reason),
+        })
+    }
+
+    fn assert_same_msg(msg: &DlcMsg, expected_msg: &DlcMsg) {
+        match (msg, expected_msg) {
+            (DlcMsg::Reject(reject), DlcMsg::Reject(expected_reject)) => {
+                assert_eq!(
+                    reject.temporary_contract_id,
+                    expected_reject.temporary_contract_id
+                );
+                assert_eq!(reject.reason, expected_reject.reason);
+            }
+            _ => panic!("Expected a reject message."),
+        }
+    }
+
+    fn reassemble(chunks: &[String]) -> Result<Option<DlcMsg>, String> {
+        let mut reassembler = DlcMsgReassembler::new();
+        let mut msg_or = None;
+        for chunk in chunks {
+            if let Some(msg) = reassembler.add_chunk((), chunk)? {
+                msg_or = Some(msg);
+            }
+        }
+        Ok(msg_or)
+    }
+
+    fn get_decode_error(chunks: &[String]) -> String {
+        match reassemble(chunks) {
+            Ok(_) => panic!("Expected decoding to fail."),
+            Err(e) => e,
+        }
+    }
+
+    /// Encodes already serialized bytes as a single chunk, bypassing the
+    /// checks in `serialize_dlc_msg`.
+    fn encode_single_chunk(bytes: &[u8]) -> String {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(bytes).unwrap();
+        let payload = encoder.finish().unwrap();
+        format!(
+            "{}/{}:{}:0:1:{}",
+            CHUNK_PREFIX,
+            DLC_MSG_CODEC_VERSION,
+            get_msg_id(&payload),
+            BASE64.encode(&payload)
+        )
+    }
+
+    #[test]
+    fn single_chunk_round_trip() {
+        let msg = get_test_msg(100);
+        let chunks = encode_dlc_msg(&msg).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(is_dlc_msg_chunk(&chunks[0]));
+
+        let decoded_msg = reassemble(&chunks).unwrap().unwrap();
+        assert_same_msg(&decoded_msg, &msg);
+        assert_eq!(encode_dlc_msg(&decoded_msg).unwrap(), chunks);
+    }
+
+    #[test]
+    fn multi_chunk_round_trip() {
+        let msg = get_test_msg(3 * MAX_CHUNK_DATA_BYTES);
+        let chunks = encode_dlc_msg(&msg).unwrap();
+        assert!(chunks.len() > 1);
+
+        let decoded_msg = reassemble(&chunks).unwrap().unwrap();
+        assert_same_msg(&decoded_msg, &msg);
+    }
+
+    #[test]
+    fn protocol_msgs_round_trip() {
+        let msgs = crate::test_utils::run_mock_dlc_flow();
+        let msg_types = [OFFER_MSG_TYPE, ACCEPT_MSG_TYPE, SIGN_MSG_TYPE];
+        assert_eq!(msgs.len(), msg_types.len());
+
+        for (msg, msg_type) in msgs.into_iter().zip(msg_types) {
+            let msg = DlcMsg::Protocol(msg);
+            let bytes = serialize_dlc_msg(&msg).unwrap();
+            assert_eq!(bytes[0], msg_type);
+
+            // Accepts and signs carry adaptor signatures for every outcome and
+            // combination of agents, so they have to be reassembled.
+            let mut chunks = encode_dlc_msg(&msg).unwrap();
+            if msg_type != OFFER_MSG_TYPE {
+                assert!(chunks.len() > 1);
+            }
+            chunks.reverse();
+
+            let decoded_msg = reassemble(&chunks).unwrap().unwrap();
+            assert_eq!(serialize_dlc_msg(&decoded_msg).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn out_of_order_chunks() {
+        let msg = get_test_msg(3 * MAX_CHUNK_DATA_BYTES);
+        let mut chunks = encode_dlc_msg(&msg).unwrap();
+        chunks.reverse();
+
+        let (last_chunk, other_chunks) = chunks.split_last().unwrap();
+        let mut reassembler = DlcMsgReassembler::new();
+        for chunk in other_chunks {
+            assert!(reassembler.add_chunk((), chunk).unwrap().is_none());
+        }
+        let decoded_msg = reassembler.add_chunk((), last_chunk).unwrap().unwrap();
+        assert_same_msg(&decoded_msg, &msg);
+    }
+
+    #[test]
+    fn bad_version() {
+        let chunks: Vec<String> = encode_dlc_msg(&get_test_msg(100))
+            .unwrap()
+            .iter()
+            .map(|chunk| {
+                chunk.replacen(
+                    &format!("{}/{}:", CHUNK_PREFIX, DLC_MSG_CODEC_VERSION),
+                    &format!("{}/{}:", CHUNK_PREFIX, DLC_MSG_CODEC_VERSION + 1),
+                    1,
+                )
+            })
+            .collect();
+
+        assert!(get_decode_error(&chunks).starts_with("Unsupported DLC message encoding"));
+    }
+
+    #[test]
+    fn msg_id_mismatch() {
+        let chunk = encode_dlc_msg(&get_test_msg(100)).unwrap().remove(0);
+        let msg_id = chunk.split(':').nth(1).unwrap();
+        let chunks = vec![chunk.replacen(msg_id, "0000000000000000", 1)];
+
+        assert_eq!(
+            get_decode_error(&chunks),
+            "DLC message does not match its ID."
+        );
+    }
+
+    #[test]
+    fn unknown_type() {
+        let chunks = vec![encode_single_chunk(&[200, 1, 2, 3])];
+
+        assert_eq!(get_decode_error(&chunks), "Unknown DLC message type: 200");
+    }
+
+    #[test]
+    fn decompression_bomb() {
+        let bytes = vec![0; MAX_DECOMPRESSED_BYTES as usize + 1];
+        let chunks = vec![encode_single_chunk(&bytes)];
+
+        assert_eq!(
+            get_decode_error(&chunks),
+            "DLC message is too large once decompressed."
+        );
+    }
+
+    #[test]
+    fn reject_round_trip() {
+        for reason in [None, Some(String::from("Collateral is too high. ✗"))] {
+            let msg = DlcMsg::Reject(RejectDlc {
+                temporary_contract_id: [9; 32],
+                reason,
+            });
+            let bytes = serialize_dlc_msg(&msg).unwrap();
+            assert_eq!(bytes[0], REJECT_MSG_TYPE);
+
+            assert_same_msg(&deserialize_dlc_msg(&bytes).unwrap(), &msg);
+        }
+    }
+
+    #[test]
+    fn truncated_reject() {
+        match deserialize_dlc_msg(&[REJECT_MSG_TYPE; 32]) {
+            Ok(_) => panic!("Expected decoding to fail."),
+            Err(e) => assert_eq!(e, "Reject message is truncated."),
+        }
+    }
+
+    #[test]
+    fn non_utf8_reject_reason() {
+        let mut bytes = vec![REJECT_MSG_TYPE];
+        bytes.extend([9; 32]);
+        bytes.extend([0xff, 0xfe]);
+
+        match deserialize_dlc_msg(&bytes) {
+            Ok(_) => panic!("Expected decoding to fail."),
+            Err(e) => assert!(e.starts_with("Error decoding reject reason")),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// An error along with a stable code saying what kind of error it is, so that
+/// callers (such as the app's frontend) can branch on the code rather than on
+/// the message. Serializes as `{ "code": "<variant>", "message": "<message>" }`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "code", content = "message")]
+pub enum ResolvrError {
+    /// Something that must be set up first, such as the DLC manager, hasn't
+    /// been.
+    NotInitialized(String),
+
+    /// Something that can only be set up once already has been.
+    AlreadyInitialized(String),
+
+    /// A contract ID isn't a 32-byte hex string.
+    InvalidContractId(String),
+
+    /// Some other input, such as a public key or contract parameter, is
+    /// invalid.
+    InvalidInput(String),
+
+    /// The requested item doesn't exist.
+    NotFound(String),
+
+    /// Reading from or writing to local storage failed.
+    Storage(String),
+
+    /// A call to the Bitcoin node failed.
+    Rpc(String),
+
+    /// The oracle (escrow agent) failed or returned something invalid.
+    Oracle(String),
+
+    /// Sending or receiving messages over Nostr failed.
+    Transport(String),
+
+    /// Anything else, such as a background task having stopped.
+    Internal(String),
+}
+
+impl ResolvrError {
+    /// Maps an error from the DLC manager to the closest code, prefixing its
+    /// message with `context`.
+    pub fn from_dlc_manager_error(context: &str, e: dlc_manager::error::Error) -> Self {
+        let message = format!("{}: {}", context, e);
+        match e {
+            dlc_manager::error::Error::InvalidParameters(_) => ResolvrError::InvalidInput(message),
+            dlc_manager::error::Error::StorageError(_) => ResolvrError::Storage(message),
+            dlc_manager::error::Error::WalletError(_)
+            | dlc_manager::error::Error::BlockchainError(_) => ResolvrError::Rpc(message),
+            dlc_manager::error::Error::OracleError(_) => ResolvrError::Oracle(message),
+            _ => ResolvrError::Internal(message),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ResolvrError::NotInitialized(message)
+            | ResolvrError::AlreadyInitialized(message)
+            | ResolvrError::InvalidContractId(message)
+            | ResolvrError::InvalidInput(message)
+            | ResolvrError::NotFound(message)
+            | ResolvrError::Storage(message)
+            | ResolvrError::Rpc(message)
+            | ResolvrError::Oracle(message)
+            | ResolvrError::Transport(message)
+            | ResolvrError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ResolvrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ResolvrError {}
@@ -1,24 +1,91 @@
+pub mod dlc_msg_codec;
+pub mod error;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+
+use dlc_manager::contract::contract_input::{ContractInput, ContractInputInfo, OracleInput};
+use dlc_manager::contract::numerical_descriptor::NumericalDescriptor;
+use dlc_manager::contract::ContractDescriptor;
+use dlc_manager::payout_curve::{
+    PayoutFunction, PayoutFunctionPiece, PayoutPoint, PolynomialPayoutCurvePiece, RoundingInterval,
+    RoundingIntervals,
+};
 use dlc_manager::Oracle;
+use dlc_messages::oracle_msgs::{
+    DigitDecompositionEventDescriptor, EnumEventDescriptor, EventDescriptor, OracleAnnouncement,
+    OracleAttestation,
+};
+use dlc_trie::OracleNumericInfo;
+use error::ResolvrError;
+use lightning::util::ser::{Readable, Writeable};
 use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+/// Outcome attested to by an escrow agent when a bounty was completed.
+pub const BOUNTY_COMPLETE_ORACLE_MESSAGE: &str = "BOUNTY_COMPLETE";
+
+/// Outcome attested to by an escrow agent when a bounty was not completed.
+pub const BOUNTY_INSUFFICIENT_ORACLE_MESSAGE: &str = "BOUNTY_INSUFFICIENT";
+
+/// Number of base 2 digits that an escrow agent attests a completion
+/// percentage in. Seven digits can represent 0 to 127, but agents only ever
+/// attest to 0 to 100.
+pub const COMPLETION_PERCENTAGE_NB_DIGITS: u16 = 7;
+
+/// Unit of the numeric event announced for bounties adjudicated by completion
+/// percentage.
+pub const COMPLETION_PERCENTAGE_UNIT: &str = "percent";
+
+/// Nostr event kind that escrow agents publish oracle announcements under.
+/// The event's `d` tag holds the oracle event ID and its content holds the
+/// hex-encoded announcement (see `encode_oracle_announcement`). The kind is
+/// parameterized replaceable (NIP-33), so relays only keep an agent's latest
+/// announcement for each oracle event ID.
+pub const ORACLE_ANNOUNCEMENT_EVENT_KIND: u64 = 30088;
+
+/// Nostr event kind that escrow agents publish oracle attestations under.
+/// The event's `d` tag holds the oracle event ID and its content holds the
+/// hex-encoded attestation (see `encode_oracle_attestation`). Like
+/// announcements, attestations are parameterized replaceable.
+pub const ORACLE_ATTESTATION_EVENT_KIND: u64 = 30089;
 
 pub trait EscrowAgent: Oracle {
     fn request_adjudication(
         &self,
         adjudication_request: AdjudicationRequest,
-    ) -> Result<AdjudicationRequestStatus, String>;
+    ) -> Result<AdjudicationRequestStatus, ResolvrError>;
 
     fn get_adjudication_request_status(
         &self,
         event_id: &str,
-    ) -> Result<AdjudicationRequestStatus, String>;
+    ) -> Result<AdjudicationRequestStatus, ResolvrError>;
+}
+
+/// Requests that clients send to an escrow agent as JSON-encoded NIP-04 DMs.
+#[derive(Serialize, Deserialize)]
+pub enum EscrowAgentRequest {
+    RequestAdjudication(AdjudicationRequest),
+    GetAdjudicationRequestStatus { oracle_event_id: String },
 }
 
+/// Responses that an escrow agent sends as JSON-encoded NIP-04 DMs. Each
+/// response is a reply to (i.e. `e`-tags) the DM containing the request.
 #[derive(Serialize, Deserialize)]
+pub enum EscrowAgentResponse {
+    AdjudicationRequestStatus(AdjudicationRequestStatus),
+
+    /// The request failed. Carries the agent's error code along with its
+    /// message, so that clients can tell, say, an unknown event from a relay
+    /// failure.
+    Error(ResolvrError),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AdjudicationRequest {
     pub bounty_template: BountyTemplate,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AdjudicationRequestStatus {
     /// The event ID of the bounty. ID is not usable until the bounty is
     /// approved (which can be checked with `adjudication_state`).
@@ -27,16 +94,193 @@ pub struct AdjudicationRequestStatus {
     pub adjudication_state: AdjudicationRequestState,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BountyTemplate {
     pub title: String,
     pub description: String,
     pub oracle_event_id: String,
+
+    /// Unix timestamp (in seconds) by which the bounty must be completed. The
+    /// agent's oracle event matures at this time, so a contract can't be
+    /// adjudicated (or refunded) before it. If unset, the agent picks a
+    /// deadline.
+    #[serde(default)]
+    pub deadline: Option<u32>,
+
+    /// Labels of outcomes the agent can attest to besides
+    /// `BOUNTY_COMPLETE_ORACLE_MESSAGE` and `BOUNTY_INSUFFICIENT_ORACLE_MESSAGE`,
+    /// for bounties that were only partially completed (e.g. `PARTIAL_50`).
+    /// How much each one pays out is up to the contract.
+    #[serde(default)]
+    pub partial_outcomes: Vec<String>,
+
+    /// If set, the agent attests to how much of the bounty was completed, as
+    /// a percentage from 0 to 100, rather than to one of the outcomes above.
+    /// Can't be combined with `partial_outcomes`.
+    #[serde(default)]
+    pub completion_percentage: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+impl BountyTemplate {
+    /// Returns every outcome the agent announces for the bounty. Contracts
+    /// for the bounty must have a payout for each of them.
+    pub fn get_outcomes(&self) -> Result<Vec<String>, String> {
+        let mut outcomes = vec![
+            BOUNTY_COMPLETE_ORACLE_MESSAGE.to_string(),
+            BOUNTY_INSUFFICIENT_ORACLE_MESSAGE.to_string(),
+        ];
+        for outcome in &self.partial_outcomes {
+            if outcome.is_empty() || outcomes.contains(outcome) {
+                return Err(format!("Invalid or duplicate outcome: {:?}", outcome));
+            }
+            outcomes.push(outcome.clone());
+        }
+        Ok(outcomes)
+    }
+
+    /// Returns the descriptor of the oracle event the agent announces for the
+    /// bounty.
+    pub fn get_event_descriptor(&self) -> Result<EventDescriptor, String> {
+        if !self.completion_percentage {
+            return Ok(EventDescriptor::EnumEvent(EnumEventDescriptor {
+                outcomes: self.get_outcomes()?,
+            }));
+        }
+
+        if !self.partial_outcomes.is_empty() {
+            return Err(String::from(
+                "Partial outcomes can't be used with a completion percentage.",
+            ));
+        }
+        Ok(get_completion_percentage_event_descriptor())
+    }
+}
+
+/// Returns the descriptor of the numeric event that an agent announces for a
+/// bounty adjudicated by completion percentage.
+pub fn get_completion_percentage_event_descriptor() -> EventDescriptor {
+    EventDescriptor::DigitDecompositionEvent(DigitDecompositionEventDescriptor {
+        base: 2,
+        is_signed: false,
+        unit: COMPLETION_PERCENTAGE_UNIT.to_string(),
+        precision: 0,
+        nb_digits: COMPLETION_PERCENTAGE_NB_DIGITS,
+    })
+}
+
+/// Returns the outcomes an agent attests to for a completion percentage: one
+/// base 2 digit per nonce, most significant first.
+pub fn get_completion_percentage_outcomes(percent: u8) -> Result<Vec<String>, String> {
+    if percent > 100 {
+        return Err(format!(
+            "Completion percentage must be between 0 and 100, got {}.",
+            percent
+        ));
+    }
+    Ok((0..COMPLETION_PERCENTAGE_NB_DIGITS)
+        .rev()
+        .map(|i| ((percent >> i) & 1).to_string())
+        .collect())
+}
+
+/// Creates a DLC contract template for a bounty that the escrow agent
+/// adjudicates by attesting to a completion percentage. The payout is
+/// interpolated between what each party gets for
+/// `BOUNTY_INSUFFICIENT_ORACLE_MESSAGE` at 0% and for
+/// `BOUNTY_COMPLETE_ORACLE_MESSAGE` at 100%.
+pub fn create_completion_percentage_bounty_contract(
+    bounty_amount_sats: u64,
+    taker_collateral_sats: u64,
+    fee_rate_sats_per_vbyte: u64,
+    oracle_input: OracleInput,
+) -> Result<ContractInput, ResolvrError> {
+    let total_collateral_sats = bounty_amount_sats + taker_collateral_sats;
+    // The announced event can represent values above 100, which the agent never
+    // attests to but which the payout function must still cover.
+    let max_outcome = (1 << COMPLETION_PERCENTAGE_NB_DIGITS) - 1;
+
+    // Payouts are what the maker gets, and the taker gets the rest.
+    let payout_function = PayoutFunction::new(vec![
+        PayoutFunctionPiece::PolynomialPayoutCurvePiece(
+            PolynomialPayoutCurvePiece::new(vec![
+                PayoutPoint {
+                    event_outcome: 0,
+                    outcome_payout: total_collateral_sats,
+                    extra_precision: 0,
+                },
+                PayoutPoint {
+                    event_outcome: 100,
+                    outcome_payout: 0,
+                    extra_precision: 0,
+                },
+            ])
+            .map_err(|e| ResolvrError::from_dlc_manager_error("Error creating payout curve", e))?,
+        ),
+        PayoutFunctionPiece::PolynomialPayoutCurvePiece(
+            PolynomialPayoutCurvePiece::new(vec![
+                PayoutPoint {
+                    event_outcome: 100,
+                    outcome_payout: 0,
+                    extra_precision: 0,
+                },
+                PayoutPoint {
+                    event_outcome: max_outcome,
+                    outcome_payout: 0,
+                    extra_precision: 0,
+                },
+            ])
+            .map_err(|e| ResolvrError::from_dlc_manager_error("Error creating payout curve", e))?,
+        ),
+    ])
+    .map_err(|e| ResolvrError::from_dlc_manager_error("Error creating payout function", e))?;
+
+    Ok(ContractInput {
+        offer_collateral: bounty_amount_sats,
+        accept_collateral: taker_collateral_sats,
+        fee_rate: fee_rate_sats_per_vbyte,
+        contract_infos: vec![ContractInputInfo {
+            contract_descriptor: ContractDescriptor::Numerical(NumericalDescriptor {
+                payout_function,
+                rounding_intervals: RoundingIntervals {
+                    intervals: vec![RoundingInterval {
+                        begin_interval: 0,
+                        rounding_mod: 1,
+                    }],
+                },
+                difference_params: None,
+                oracle_numeric_infos: OracleNumericInfo {
+                    base: 2,
+                    nb_digits: vec![COMPLETION_PERCENTAGE_NB_DIGITS as usize],
+                },
+            }),
+            oracles: oracle_input,
+        }],
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AdjudicationRequestState {
     Approved,
     Denied,
     InReview,
 }
+
+pub fn encode_oracle_announcement(announcement: &OracleAnnouncement) -> String {
+    hex::encode(announcement.encode())
+}
+
+pub fn decode_oracle_announcement(content: &str) -> Result<OracleAnnouncement, String> {
+    let bytes = hex::decode(content).map_err(|e| format!("Error decoding hex: {}", e))?;
+    OracleAnnouncement::read(&mut Cursor::new(bytes))
+        .map_err(|e| format!("Error decoding oracle announcement: {:?}", e))
+}
+
+pub fn encode_oracle_attestation(attestation: &OracleAttestation) -> String {
+    hex::encode(attestation.encode())
+}
+
+pub fn decode_oracle_attestation(content: &str) -> Result<OracleAttestation, String> {
+    let bytes = hex::decode(content).map_err(|e| format!("Error decoding hex: {}", e))?;
+    OracleAttestation::read(&mut Cursor::new(bytes))
+        .map_err(|e| format!("Error decoding oracle attestation: {:?}", e))
+}
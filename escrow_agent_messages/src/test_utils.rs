@@ -0,0 +1,137 @@
+//! Helpers for tests that need real DLC protocol messages. Two DLC managers
+//! backed by mock wallets and the same mock escrow agents negotiate a contract
+//! without a chain or a network, so the offer, accept and sign messages they
+//! exchange carry real adaptor signatures.
+
+use crate::{
+    create_completion_percentage_bounty_contract, get_completion_percentage_event_descriptor,
+};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::XOnlyPublicKey;
+use dlc_manager::contract::contract_input::{ContractInput, OracleInput};
+use dlc_manager::manager::Manager;
+use dlc_manager::{Oracle, SystemTimeProvider};
+use dlc_messages::Message;
+use mocks::memory_storage_provider::MemoryStorage;
+use mocks::mock_blockchain::MockBlockchain;
+use mocks::mock_oracle_provider::MockOracle;
+use mocks::mock_wallet::MockWallet;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+pub type MockDlcManager = Manager<
+    Rc<MockWallet>,
+    Rc<MockBlockchain>,
+    Rc<MemoryStorage>,
+    Rc<MockOracle>,
+    Rc<SystemTimeProvider>,
+    Rc<MockBlockchain>,
+>;
+
+/// How many escrow agents adjudicate the test contract, and how many of them
+/// must agree. Every combination of agents gets its own adaptor signatures, so
+/// accepts and signs are large enough to span several chunks.
+const NB_ESCROW_AGENTS: usize = 3;
+const ESCROW_AGENT_THRESHOLD: u16 = 2;
+
+/// Value of each UTXO in the mock wallets.
+const MOCK_UTXO_VALUE_SATS: u64 = 1_000_000;
+
+/// Creates the managers of two parties that trust the same escrow agents, and
+/// a completion percentage bounty for them to negotiate.
+pub fn create_mock_dlc_managers() -> (MockDlcManager, MockDlcManager, ContractInput) {
+    let event_id = String::from("mock-bounty");
+    let maturity_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+        + 60 * 60 * 24;
+
+    let mut oracles = HashMap::new();
+    let mut public_keys = Vec::new();
+    for _ in 0..NB_ESCROW_AGENTS {
+        let mut oracle = MockOracle::new();
+        oracle.add_event(
+            &event_id,
+            &get_completion_percentage_event_descriptor(),
+            maturity_epoch,
+        );
+        public_keys.push(oracle.get_public_key());
+        oracles.insert(oracle.get_public_key(), Rc::new(oracle));
+    }
+
+    let contract_input = create_completion_percentage_bounty_contract(
+        100_000,
+        10_000,
+        2,
+        OracleInput {
+            public_keys,
+            event_id,
+            threshold: ESCROW_AGENT_THRESHOLD,
+        },
+    )
+    .expect("Error creating test contract.");
+
+    (
+        create_mock_dlc_manager(oracles.clone()),
+        create_mock_dlc_manager(oracles),
+        contract_input,
+    )
+}
+
+/// Runs offer, accept and sign between two mock parties, and returns the
+/// messages in the order they're sent.
+pub fn run_mock_dlc_flow() -> Vec<Message> {
+    let (mut offer_manager, mut accept_manager, contract_input) = create_mock_dlc_managers();
+    let offer_party = get_mock_public_key(1);
+    let accept_party = get_mock_public_key(2);
+
+    let offer_dlc = offer_manager
+        .send_offer(&contract_input, accept_party)
+        .expect("Error sending offer.");
+    accept_manager
+        .on_dlc_message(&Message::Offer(offer_dlc.clone()), offer_party)
+        .expect("Error receiving offer.");
+
+    let (_, _, accept_dlc) = accept_manager
+        .accept_contract_offer(&offer_dlc.temporary_contract_id)
+        .expect("Error accepting offer.");
+    let sign_msg = offer_manager
+        .on_dlc_message(&Message::Accept(accept_dlc.clone()), accept_party)
+        .expect("Error receiving accept.")
+        .expect("Expected a sign message.");
+    let sign_dlc = match sign_msg {
+        Message::Sign(v) => v,
+        _ => panic!("Expected a sign message."),
+    };
+    accept_manager
+        .on_dlc_message(&Message::Sign(sign_dlc.clone()), offer_party)
+        .expect("Error receiving sign.");
+
+    vec![
+        Message::Offer(offer_dlc),
+        Message::Accept(accept_dlc),
+        Message::Sign(sign_dlc),
+    ]
+}
+
+/// Returns a fixed public key to identify a mock party by.
+fn get_mock_public_key(seed: u8) -> PublicKey {
+    let secret_key = SecretKey::from_slice(&[seed; 32]).expect("Error creating secret key.");
+    PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+}
+
+fn create_mock_dlc_manager(oracles: HashMap<XOnlyPublicKey, Rc<MockOracle>>) -> MockDlcManager {
+    let blockchain = Rc::new(MockBlockchain::new());
+    let wallet = Rc::new(MockWallet::new(&blockchain, &[MOCK_UTXO_VALUE_SATS; 4]));
+    Manager::new(
+        wallet,
+        blockchain.clone(),
+        Rc::new(MemoryStorage::new()),
+        oracles,
+        Rc::new(SystemTimeProvider {}),
+        blockchain,
+    )
+    .expect("Error creating mock DLC manager.")
+}
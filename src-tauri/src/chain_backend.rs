@@ -0,0 +1,512 @@
+use crate::fee_service::estimate_send_tx_vbytes;
+use crate::network::get_genesis_block_hash;
+use crate::BitcoinCoreConfig;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut,
+    Txid, Witness,
+};
+use bitcoin_rpc_provider::BitcoinCoreProvider;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use dlc_manager::{Blockchain, Signer, Wallet};
+use dlc_sled_storage_provider::SledStorageProvider;
+use electrs_blockchain_provider::ElectrsBlockchainProvider;
+use escrow_agent_messages::error::ResolvrError;
+use lightning::chain::chaininterface::FeeEstimator;
+use serde::{Deserialize, Serialize};
+use simple_wallet::{SimpleWallet, WalletStorage};
+use std::sync::Arc;
+
+/// An Esplora HTTP API, such as the one served by electrs.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EsploraConfig {
+    /// Base URL of the API, e.g. `http://localhost:3002/`.
+    pub url: String,
+}
+
+/// Where the app gets chain data, fee estimates and its wallet from.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum ChainBackendConfig {
+    /// A Bitcoin Core node, whose wallet is used.
+    BitcoinCore(BitcoinCoreConfig),
+
+    /// An Esplora server, paired with a wallet whose keys are kept in the
+    /// app's DLC storage.
+    Esplora(EsploraConfig),
+}
+
+type LocalWallet = SimpleWallet<Arc<ElectrsBlockchainProvider>, Arc<SledStorageProvider>>;
+
+/// Smallest change output the local wallet makes. Anything less goes to fees
+/// instead, since nodes won't relay outputs below the dust limit.
+const MIN_CHANGE_SATS: u64 = 546;
+
+/// An entry of Bitcoin Core's `listlockunspent`.
+#[derive(Deserialize)]
+struct LockedOutPoint {
+    txid: Txid,
+    vout: u32,
+}
+
+#[derive(Serialize)]
+pub struct WalletBalance {
+    /// Includes UTXOs reserved by contracts that haven't been funded yet.
+    pub confirmed_sats: u64,
+
+    pub unconfirmed_sats: u64,
+}
+
+#[derive(Serialize)]
+pub struct WalletUtxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value_sats: u64,
+    pub address: Option<String>,
+
+    /// None if the wallet doesn't track confirmations.
+    pub confirmations: Option<u32>,
+
+    /// Whether the UTXO is set aside to fund a contract, and so won't be spent
+    /// by `send_to_address` or other contracts.
+    pub reserved: bool,
+}
+
+/// What wallet operations that aren't part of the `Wallet` trait, such as
+/// listing UTXOs, are done through.
+enum WalletBackend {
+    /// The node's own wallet, through an RPC client of its own since
+    /// `BitcoinCoreProvider` doesn't expose one.
+    BitcoinCore(Client),
+
+    /// A wallet with no node of its own, whose UTXOs have to be looked up on
+    /// the chain.
+    Local {
+        wallet: Arc<LocalWallet>,
+        storage: Arc<SledStorageProvider>,
+    },
+}
+
+/// The wallet, blockchain and fee estimator that the DLC manager is created
+/// with, backed by whichever chain backend was connected to.
+pub struct ChainBackend {
+    pub wallet: Arc<dyn Wallet + Send + Sync>,
+    pub blockchain: Arc<dyn Blockchain + Send + Sync>,
+    pub fee_estimator: Arc<dyn FeeEstimator + Send + Sync>,
+    wallet_backend: WalletBackend,
+}
+
+impl ChainBackend {
+    /// Connects to the given backend and checks that it's reachable and on
+    /// `network`. Backends without a wallet of their own keep their wallet in
+    /// `wallet_storage`.
+    pub fn connect(
+        config: &ChainBackendConfig,
+        network: Network,
+        wallet_storage: Arc<SledStorageProvider>,
+    ) -> Result<Self, ResolvrError> {
+        let chain_backend = match config {
+            ChainBackendConfig::BitcoinCore(bitcoin_core_config) => {
+                let bitcoin_core_provider = match BitcoinCoreProvider::new(
+                    bitcoin_core_config.host.clone(),
+                    bitcoin_core_config.port,
+                    None,
+                    bitcoin_core_config.rpc_user.clone(),
+                    bitcoin_core_config.rpc_password.clone(),
+                ) {
+                    Ok(v) => Arc::new(v),
+                    Err(e) => {
+                        return Err(ResolvrError::Rpc(format!(
+                            "Error creating Bitcoin Core provider: {}",
+                            e
+                        )))
+                    }
+                };
+                let client = match Client::new(
+                    &format!(
+                        "http://{}:{}",
+                        bitcoin_core_config.host, bitcoin_core_config.port
+                    ),
+                    Auth::UserPass(
+                        bitcoin_core_config.rpc_user.clone(),
+                        bitcoin_core_config.rpc_password.clone(),
+                    ),
+                ) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return Err(ResolvrError::Rpc(format!(
+                            "Error creating Bitcoin Core client: {}",
+                            e
+                        )))
+                    }
+                };
+                Self {
+                    wallet: bitcoin_core_provider.clone(),
+                    blockchain: bitcoin_core_provider.clone(),
+                    fee_estimator: bitcoin_core_provider,
+                    wallet_backend: WalletBackend::BitcoinCore(client),
+                }
+            }
+            ChainBackendConfig::Esplora(esplora_config) => {
+                let esplora_provider = Arc::new(ElectrsBlockchainProvider::new(
+                    esplora_config.url.clone(),
+                    network,
+                ));
+                let local_wallet = Arc::new(SimpleWallet::new(
+                    esplora_provider.clone(),
+                    wallet_storage.clone(),
+                    network,
+                ));
+                Self {
+                    wallet: local_wallet.clone(),
+                    blockchain: esplora_provider.clone(),
+                    fee_estimator: esplora_provider,
+                    wallet_backend: WalletBackend::Local {
+                        wallet: local_wallet,
+                        storage: wallet_storage,
+                    },
+                }
+            }
+        };
+
+        // Creating a provider doesn't talk to the backend, so make sure it's
+        // reachable and on the expected chain before it's used. Bitcoin Core
+        // reports its network from `getblockchaininfo`, while Esplora only
+        // echoes back the network it was created with, so the genesis block
+        // is compared as well.
+        let backend_network = match chain_backend.blockchain.get_network() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Rpc(format!(
+                    "Error connecting to chain backend: {}",
+                    e
+                )))
+            }
+        };
+        if backend_network != network {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Chain backend is on {} but the app is set to {}.",
+                backend_network, network
+            )));
+        }
+        let genesis_block = match chain_backend.blockchain.get_block_at_height(0) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Rpc(format!(
+                    "Error connecting to chain backend: {}",
+                    e
+                )))
+            }
+        };
+        if genesis_block.block_hash() != get_genesis_block_hash(network) {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Chain backend isn't on {}.",
+                network
+            )));
+        }
+
+        Ok(chain_backend)
+    }
+
+    /// Looks up the UTXOs of a local wallet on the chain, so that they can be
+    /// used to fund contracts. Does nothing for backends with their own wallet.
+    pub fn refresh_wallet(&self) -> Result<(), ResolvrError> {
+        match &self.wallet_backend {
+            WalletBackend::BitcoinCore(_) => Ok(()),
+            WalletBackend::Local { wallet, .. } => wallet
+                .refresh()
+                .map_err(|e| ResolvrError::from_dlc_manager_error("Error refreshing wallet", e)),
+        }
+    }
+
+    /// The local wallet doesn't track confirmations, so everything it has
+    /// found on the chain is counted as confirmed.
+    pub fn get_balance(&self) -> Result<WalletBalance, ResolvrError> {
+        match &self.wallet_backend {
+            WalletBackend::BitcoinCore(client) => {
+                let balances = client.get_balances().map_err(|e| {
+                    ResolvrError::Rpc(format!("Error getting wallet balance: {}", e))
+                })?;
+                Ok(WalletBalance {
+                    confirmed_sats: balances.mine.trusted.to_sat(),
+                    unconfirmed_sats: balances.mine.untrusted_pending.to_sat(),
+                })
+            }
+            WalletBackend::Local { .. } => Ok(WalletBalance {
+                confirmed_sats: self.list_utxos()?.iter().map(|utxo| utxo.value_sats).sum(),
+                unconfirmed_sats: 0,
+            }),
+        }
+    }
+
+    pub fn get_new_address(&self) -> Result<Address, ResolvrError> {
+        self.wallet
+            .get_new_address()
+            .map_err(|e| ResolvrError::from_dlc_manager_error("Error getting new address", e))
+    }
+
+    /// Lists the wallet's UTXOs. Bitcoin Core leaves out the UTXOs that
+    /// contracts have locked, so those have to be added from the contracts.
+    pub fn list_utxos(&self) -> Result<Vec<WalletUtxo>, ResolvrError> {
+        match &self.wallet_backend {
+            WalletBackend::BitcoinCore(client) => {
+                let utxos = client
+                    .list_unspent(Some(0), None, None, Some(true), None)
+                    .map_err(|e| ResolvrError::Rpc(format!("Error listing UTXOs: {}", e)))?;
+                Ok(utxos
+                    .into_iter()
+                    .map(|utxo| WalletUtxo {
+                        txid: utxo.txid.to_string(),
+                        vout: utxo.vout,
+                        value_sats: utxo.amount.to_sat(),
+                        address: utxo.address.map(|address| address.to_string()),
+                        confirmations: Some(utxo.confirmations),
+                        reserved: false,
+                    })
+                    .collect())
+            }
+            WalletBackend::Local { storage, .. } => {
+                let utxos = storage
+                    .get_utxos()
+                    .map_err(|e| ResolvrError::from_dlc_manager_error("Error listing UTXOs", e))?;
+                Ok(utxos
+                    .into_iter()
+                    .map(|utxo| WalletUtxo {
+                        txid: utxo.outpoint.txid.to_string(),
+                        vout: utxo.outpoint.vout,
+                        value_sats: utxo.tx_out.value,
+                        address: Some(utxo.address.to_string()),
+                        confirmations: None,
+                        reserved: utxo.reserved,
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Reserves UTXOs that contracts are waiting to be funded with, so that
+    /// they aren't spent by anything else. Bitcoin Core only keeps its locks in
+    /// memory, so they're lost whenever the node restarts and have to be made
+    /// again. The local wallet keeps its reservations in storage, so this does
+    /// nothing for it. Returns the outpoints that couldn't be reserved, such as
+    /// ones that were already spent, along with why.
+    pub fn reserve_utxos(
+        &self,
+        outpoints: &[OutPoint],
+    ) -> Result<Vec<(OutPoint, ResolvrError)>, ResolvrError> {
+        let client = match &self.wallet_backend {
+            WalletBackend::BitcoinCore(client) => client,
+            WalletBackend::Local { .. } => return Ok(Vec::new()),
+        };
+
+        let locked_outpoints: Vec<LockedOutPoint> = client
+            .call("listlockunspent", &[])
+            .map_err(|e| ResolvrError::Rpc(format!("Error listing locked UTXOs: {}", e)))?;
+
+        // Each UTXO is locked on its own, since Core refuses the whole batch
+        // if any of them can't be locked.
+        let mut failures = Vec::new();
+        for outpoint in outpoints {
+            if locked_outpoints.iter().any(|locked_outpoint| {
+                locked_outpoint.txid == outpoint.txid && locked_outpoint.vout == outpoint.vout
+            }) {
+                continue;
+            }
+            if let Err(e) = client.lock_unspent(&[*outpoint]) {
+                failures.push((
+                    *outpoint,
+                    ResolvrError::Rpc(format!("Error reserving UTXO: {}", e)),
+                ));
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Releases UTXOs that were reserved to fund a contract that will never be
+    /// funded, so that they can be spent again.
+    pub fn release_utxos(&self, outpoints: &[OutPoint]) -> Result<(), ResolvrError> {
+        // Bitcoin Core unlocks every UTXO when given none.
+        if outpoints.is_empty() {
+            return Ok(());
+        }
+
+        match &self.wallet_backend {
+            WalletBackend::BitcoinCore(client) => client
+                .unlock_unspent(outpoints)
+                .map(|_| ())
+                .map_err(|e| ResolvrError::Rpc(format!("Error releasing UTXOs: {}", e))),
+            WalletBackend::Local { storage, .. } => {
+                let utxos = storage.get_utxos().map_err(|e| {
+                    ResolvrError::from_dlc_manager_error("Error releasing UTXOs", e)
+                })?;
+                for mut utxo in utxos {
+                    if utxo.reserved && outpoints.contains(&utxo.outpoint) {
+                        utxo.reserved = false;
+                        storage.upsert_utxo(&utxo).map_err(|e| {
+                            ResolvrError::from_dlc_manager_error("Error releasing UTXOs", e)
+                        })?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends from the wallet, never spending UTXOs reserved by contracts. With
+    /// Bitcoin Core, the reservations must have been made again with
+    /// `reserve_utxos` since the node last started. The fee rate is only used
+    /// by the local wallet, since Bitcoin Core estimates its own.
+    pub fn send_to_address(
+        &self,
+        address: &Address,
+        amount_sats: u64,
+        fee_rate_sats_per_vbyte: u64,
+    ) -> Result<Txid, ResolvrError> {
+        match &self.wallet_backend {
+            WalletBackend::BitcoinCore(client) => client
+                .send_to_address(
+                    address,
+                    Amount::from_sat(amount_sats),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+                .map_err(|e| ResolvrError::Rpc(format!("Error sending to address: {}", e))),
+            WalletBackend::Local { wallet, .. } => {
+                self.send_from_local_wallet(wallet, address, amount_sats, fee_rate_sats_per_vbyte)
+            }
+        }
+    }
+
+    /// Builds, signs and broadcasts a transaction from the local wallet, which
+    /// can't send on its own. Reserved UTXOs are never selected.
+    fn send_from_local_wallet(
+        &self,
+        wallet: &LocalWallet,
+        address: &Address,
+        amount_sats: u64,
+        fee_rate_sats_per_vbyte: u64,
+    ) -> Result<Txid, ResolvrError> {
+        let recipient_script = address.script_pubkey();
+        let recipient_script_len = recipient_script.len() as u64;
+
+        // UTXO selection only accounts for the fees of the inputs it picks, so
+        // the rest of the transaction's fee is added to the amount.
+        let fixed_fee_sats =
+            estimate_send_tx_vbytes(0, recipient_script_len, true) * fee_rate_sats_per_vbyte;
+        let utxos = wallet
+            .get_utxos_for_amount(
+                amount_sats + fixed_fee_sats,
+                Some(fee_rate_sats_per_vbyte),
+                false,
+            )
+            .map_err(|e| ResolvrError::from_dlc_manager_error("Error selecting UTXOs", e))?;
+        let input_sats: u64 = utxos.iter().map(|utxo| utxo.tx_out.value).sum();
+        let nb_inputs = utxos.len() as u64;
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: utxos
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: Script::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: amount_sats,
+                script_pubkey: recipient_script,
+            }],
+        };
+
+        let fee_with_change_sats = estimate_send_tx_vbytes(nb_inputs, recipient_script_len, true)
+            * fee_rate_sats_per_vbyte;
+        let fee_without_change_sats =
+            estimate_send_tx_vbytes(nb_inputs, recipient_script_len, false)
+                * fee_rate_sats_per_vbyte;
+        if input_sats >= amount_sats + fee_with_change_sats + MIN_CHANGE_SATS {
+            tx.output.push(TxOut {
+                value: input_sats - amount_sats - fee_with_change_sats,
+                script_pubkey: self.get_new_address()?.script_pubkey(),
+            });
+        } else if input_sats < amount_sats + fee_without_change_sats {
+            return Err(ResolvrError::InvalidInput(String::from(
+                "Not enough unreserved funds to cover the amount and fee.",
+            )));
+        }
+
+        for (input_index, utxo) in utxos.iter().enumerate() {
+            wallet
+                .sign_tx_input(&mut tx, input_index, &utxo.tx_out, None)
+                .map_err(|e| {
+                    ResolvrError::from_dlc_manager_error("Error signing transaction", e)
+                })?;
+        }
+
+        self.blockchain.send_transaction(&tx).map_err(|e| {
+            ResolvrError::from_dlc_manager_error("Error broadcasting transaction", e)
+        })?;
+        Ok(tx.txid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connects to the regtest Esplora API at `RESOLVR_TEST_ESPLORA_URL`, e.g.
+    /// one served by a local electrs, with a fresh wallet. Run with `cargo test
+    /// -- --ignored`.
+    fn connect_to_test_esplora(network: Network) -> Result<ChainBackend, ResolvrError> {
+        let url = std::env::var("RESOLVR_TEST_ESPLORA_URL")
+            .expect("RESOLVR_TEST_ESPLORA_URL must be set to a regtest Esplora API.");
+        let wallet_storage_path = std::env::temp_dir().join(format!(
+            "resolvr_esplora_test_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let wallet_storage = Arc::new(
+            SledStorageProvider::new(wallet_storage_path.to_str().unwrap())
+                .expect("Error opening wallet storage."),
+        );
+        ChainBackend::connect(
+            &ChainBackendConfig::Esplora(EsploraConfig { url }),
+            network,
+            wallet_storage,
+        )
+    }
+
+    #[test]
+    #[ignore = "needs a regtest Esplora API at RESOLVR_TEST_ESPLORA_URL"]
+    fn esplora_wallet() {
+        let chain_backend = connect_to_test_esplora(Network::Regtest).unwrap();
+
+        // A fresh wallet has nothing on the chain yet.
+        chain_backend.refresh_wallet().unwrap();
+        assert!(chain_backend.list_utxos().unwrap().is_empty());
+        let balance = chain_backend.get_balance().unwrap();
+        assert_eq!(balance.confirmed_sats, 0);
+        assert_eq!(balance.unconfirmed_sats, 0);
+
+        let address = chain_backend.get_new_address().unwrap();
+        assert!(address.is_valid_for_network(Network::Regtest));
+        assert_ne!(chain_backend.get_new_address().unwrap(), address);
+    }
+
+    #[test]
+    #[ignore = "needs a regtest Esplora API at RESOLVR_TEST_ESPLORA_URL"]
+    fn esplora_wrong_network() {
+        assert!(matches!(
+            connect_to_test_esplora(Network::Testnet),
+            Err(ResolvrError::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::chain_backend::{ChainBackendConfig, EsploraConfig};
+use crate::{keyring_error, BitcoinCoreConfig, RESOLVR_KEYRING_SERVICE};
+use bitcoin::Network;
+use escrow_agent_messages::error::ResolvrError;
+use keyring::Entry;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Prefix of the keychain user that the Bitcoin Core RPC password is saved
+/// under, followed by the network. Before networks were kept apart, the prefix
+/// alone was used.
+static RPC_PASSWORD_KEYRING_USER_PREFIX: &str = "bitcoin_core_rpc_password";
+
+/// The parts of a `BitcoinCoreConfig` that are saved in the app config. The
+/// RPC password is kept in the keychain instead.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedBitcoinCoreConfig {
+    pub host: String,
+    pub port: u16,
+    pub rpc_user: String,
+}
+
+/// A `ChainBackendConfig` as saved in the app config, without any secrets.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum SavedChainBackendConfig {
+    BitcoinCore(SavedBitcoinCoreConfig),
+    Esplora(EsploraConfig),
+}
+
+/// Remembers the last chain backend connected to on a network, so that the app
+/// can connect to it again on startup.
+pub struct ChainBackendConfigStore {
+    path: PathBuf,
+    network: Network,
+}
+
+impl ChainBackendConfigStore {
+    pub fn new(path: PathBuf, network: Network) -> Self {
+        Self { path, network }
+    }
+
+    /// Returns the saved config without the RPC password, if there is one.
+    pub fn get(&self) -> Result<Option<SavedChainBackendConfig>, ResolvrError> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error reading chain backend config: {}",
+                    e
+                )))
+            }
+        };
+        serde_json::from_slice(&bytes).map(Some).map_err(|e| {
+            ResolvrError::Storage(format!("Error decoding chain backend config: {}", e))
+        })
+    }
+
+    /// Returns the saved config along with the RPC password from the keychain.
+    pub fn load(&self) -> Result<Option<ChainBackendConfig>, ResolvrError> {
+        let saved_config = match self.get()? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        Ok(Some(match saved_config {
+            SavedChainBackendConfig::BitcoinCore(saved_config) => {
+                let rpc_password = self
+                    .get_rpc_password_entry()?
+                    .get_password()
+                    .map_err(keyring_error)?;
+                ChainBackendConfig::BitcoinCore(BitcoinCoreConfig {
+                    host: saved_config.host,
+                    port: saved_config.port,
+                    rpc_user: saved_config.rpc_user,
+                    rpc_password,
+                })
+            }
+            SavedChainBackendConfig::Esplora(esplora_config) => {
+                ChainBackendConfig::Esplora(esplora_config)
+            }
+        }))
+    }
+
+    pub fn save(&self, chain_backend_config: &ChainBackendConfig) -> Result<(), ResolvrError> {
+        let saved_config = match chain_backend_config {
+            ChainBackendConfig::BitcoinCore(bitcoin_core_config) => {
+                self.get_rpc_password_entry()?
+                    .set_password(&bitcoin_core_config.rpc_password)
+                    .map_err(keyring_error)?;
+                SavedChainBackendConfig::BitcoinCore(SavedBitcoinCoreConfig {
+                    host: bitcoin_core_config.host.clone(),
+                    port: bitcoin_core_config.port,
+                    rpc_user: bitcoin_core_config.rpc_user.clone(),
+                })
+            }
+            ChainBackendConfig::Esplora(esplora_config) => {
+                self.clear_rpc_password()?;
+                SavedChainBackendConfig::Esplora(esplora_config.clone())
+            }
+        };
+
+        let bytes = serde_json::to_vec(&saved_config).map_err(|e| {
+            ResolvrError::Storage(format!("Error encoding chain backend config: {}", e))
+        })?;
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                ResolvrError::Storage(format!("Error creating app config dir: {}", e))
+            })?;
+        }
+        std::fs::write(&self.path, bytes)
+            .map_err(|e| ResolvrError::Storage(format!("Error saving chain backend config: {}", e)))
+    }
+
+    /// Forgets the saved config and RPC password, so that the app no longer
+    /// connects on startup.
+    pub fn clear(&self) -> Result<(), ResolvrError> {
+        self.clear_rpc_password()?;
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error removing chain backend config: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Takes over a config saved before networks were kept apart, along with
+    /// its RPC password, and removes the old copy. Also takes the plain
+    /// `SavedBitcoinCoreConfig` that was saved before there were other chain
+    /// backends.
+    pub fn import_legacy_config(&self, legacy_path: &Path) -> Result<(), ResolvrError> {
+        if self.path.exists() {
+            return Err(ResolvrError::Storage(format!(
+                "Can't move {} to {} since it already exists. Move or remove one of them.",
+                legacy_path.display(),
+                self.path.display()
+            )));
+        }
+
+        let bytes = std::fs::read(legacy_path).map_err(|e| {
+            ResolvrError::Storage(format!("Error reading legacy chain backend config: {}", e))
+        })?;
+        let saved_config = match serde_json::from_slice::<SavedChainBackendConfig>(&bytes) {
+            Ok(v) => v,
+            Err(_) => SavedChainBackendConfig::BitcoinCore(
+                serde_json::from_slice::<SavedBitcoinCoreConfig>(&bytes).map_err(|e| {
+                    ResolvrError::Storage(format!(
+                        "Error decoding legacy chain backend config: {}",
+                        e
+                    ))
+                })?,
+            ),
+        };
+
+        let legacy_rpc_password_entry =
+            Entry::new(RESOLVR_KEYRING_SERVICE, RPC_PASSWORD_KEYRING_USER_PREFIX)
+                .map_err(keyring_error)?;
+        let chain_backend_config = match saved_config {
+            SavedChainBackendConfig::BitcoinCore(saved_config) => {
+                ChainBackendConfig::BitcoinCore(BitcoinCoreConfig {
+                    host: saved_config.host,
+                    port: saved_config.port,
+                    rpc_user: saved_config.rpc_user,
+                    rpc_password: legacy_rpc_password_entry
+                        .get_password()
+                        .map_err(keyring_error)?,
+                })
+            }
+            SavedChainBackendConfig::Esplora(esplora_config) => {
+                ChainBackendConfig::Esplora(esplora_config)
+            }
+        };
+        self.save(&chain_backend_config)?;
+
+        match legacy_rpc_password_entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(e) => return Err(keyring_error(e)),
+        }
+        std::fs::remove_file(legacy_path).map_err(|e| {
+            ResolvrError::Storage(format!("Error removing legacy chain backend config: {}", e))
+        })
+    }
+
+    fn get_rpc_password_entry(&self) -> Result<Entry, ResolvrError> {
+        Entry::new(
+            RESOLVR_KEYRING_SERVICE,
+            &format!("{}_{}", RPC_PASSWORD_KEYRING_USER_PREFIX, self.network),
+        )
+        .map_err(keyring_error)
+    }
+
+    fn clear_rpc_password(&self) -> Result<(), ResolvrError> {
+        match self.get_rpc_password_entry()?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+}
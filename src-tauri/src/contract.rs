@@ -1,4 +1,10 @@
-use dlc_manager::contract::Contract;
+use bitcoin::{OutPoint, Transaction};
+use dlc_manager::contract::accepted_contract::AcceptedContract;
+use dlc_manager::contract::offered_contract::OfferedContract;
+use dlc_manager::contract::{Contract, ContractDescriptor, FundingInputInfo};
+use dlc_manager::ContractId;
+use dlc_messages::oracle_msgs::OracleAttestation;
+use escrow_agent_messages::error::ResolvrError;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -16,30 +22,17 @@ pub struct JsonContract {
 
 impl From<&Contract> for JsonContract {
     fn from(contract: &Contract) -> Self {
-        let stage = match contract {
-            Contract::Offered(_) => JsonContractStage::Offered,
-            Contract::Accepted(_) => JsonContractStage::Accepted,
-            Contract::Signed(_) => JsonContractStage::Signed,
-            Contract::Confirmed(_) => JsonContractStage::Confirmed,
-            Contract::PreClosed(_) => JsonContractStage::PreClosed,
-            Contract::Closed(_) => JsonContractStage::Closed,
-            Contract::FailedAccept(_) => JsonContractStage::FailedAccept,
-            Contract::FailedSign(_) => JsonContractStage::FailedSign,
-            Contract::Refunded(_) => JsonContractStage::Refunded,
-            Contract::Rejected(_) => JsonContractStage::Rejected,
-        };
-
         JsonContract {
             id: hex::encode(contract.get_id()),
             counter_party_id: contract.get_counter_party_id().to_string(),
             is_offer_party: is_offer_party(contract),
-            stage,
+            stage: contract.into(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-enum JsonContractStage {
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JsonContractStage {
     Offered,
     Accepted,
     Signed,
@@ -52,6 +45,443 @@ enum JsonContractStage {
     Rejected,
 }
 
+impl From<&Contract> for JsonContractStage {
+    fn from(contract: &Contract) -> Self {
+        match contract {
+            Contract::Offered(_) => JsonContractStage::Offered,
+            Contract::Accepted(_) => JsonContractStage::Accepted,
+            Contract::Signed(_) => JsonContractStage::Signed,
+            Contract::Confirmed(_) => JsonContractStage::Confirmed,
+            Contract::PreClosed(_) => JsonContractStage::PreClosed,
+            Contract::Closed(_) => JsonContractStage::Closed,
+            Contract::FailedAccept(_) => JsonContractStage::FailedAccept,
+            Contract::FailedSign(_) => JsonContractStage::FailedSign,
+            Contract::Refunded(_) => JsonContractStage::Refunded,
+            Contract::Rejected(_) => JsonContractStage::Rejected,
+        }
+    }
+}
+
+/// A contract that moved to a new stage.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JsonContractStageChange {
+    pub contract_id: String,
+
+    /// The ID the contract had before, if it changed along with the stage.
+    /// Contracts are identified by a temporary ID until they're accepted, and
+    /// again once they're rejected.
+    pub previous_contract_id: Option<String>,
+
+    /// The stage the contract was in before. Is only None for contracts that
+    /// didn't exist before, such as offers received from a counterparty.
+    pub old_stage: Option<JsonContractStage>,
+
+    pub new_stage: JsonContractStage,
+}
+
+/// Everything known about a contract, for showing it in detail. Fields that
+/// don't apply to the contract's stage are None or empty.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JsonContractDetail {
+    id: String,
+
+    /// The ID the contract was identified by before it was accepted.
+    temporary_id: String,
+
+    counter_party_id: String,
+
+    /// Whether the local party is the original offerer of the contract.
+    is_offer_party: Option<bool>,
+
+    stage: JsonContractStage,
+
+    /// Unix timestamp (in seconds) of when the contract moved to its current
+    /// stage. None for contracts that haven't changed stage since the app
+    /// started keeping track.
+    #[serde(default)]
+    stage_changed_at: Option<u64>,
+
+    offer_collateral_sats: Option<u64>,
+
+    accept_collateral_sats: Option<u64>,
+
+    fee_rate_sats_per_vbyte: Option<u64>,
+
+    /// Hex-encoded public keys of the oracles the contract depends on.
+    oracle_public_keys: Vec<String>,
+
+    oracle_event_id: Option<String>,
+
+    /// How many of the oracles must attest to the same outcome for the
+    /// contract to close.
+    #[serde(default)]
+    oracle_threshold: Option<usize>,
+
+    /// What each party is paid for each outcome. Outcomes of numeric
+    /// contracts are the values that can be attested to, in base 10.
+    outcome_payouts: Vec<JsonOutcomePayout>,
+
+    /// Base that the oracles attest to the outcome of a numeric contract in,
+    /// one digit per nonce. None for enum contracts.
+    #[serde(default)]
+    numeric_outcome_base: Option<u64>,
+
+    funding_txid: Option<String>,
+
+    funding_vout: Option<u32>,
+
+    /// IDs of the contract execution transactions, one per outcome.
+    cet_txids: Vec<String>,
+
+    /// ID of the contract execution transaction that closed the contract.
+    closing_cet_txid: Option<String>,
+
+    refund_txid: Option<String>,
+
+    /// Unix timestamp (in seconds) after which the refund transaction can be
+    /// broadcast.
+    refund_locktime: Option<u32>,
+
+    /// Unix timestamp (in seconds) by which the bounty must be completed.
+    /// Only known to the party that offered the contract, since it isn't part
+    /// of the offer.
+    #[serde(default)]
+    bounty_deadline: Option<u32>,
+
+    /// The outcome attested to by the oracles.
+    attested_outcome: Option<String>,
+
+    /// What the local party was paid once the contract closed or refunded.
+    local_payout_sats: Option<u64>,
+
+    /// Why the offer was declined, if it was and a reason was given.
+    #[serde(default)]
+    rejection_reason: Option<String>,
+
+    /// Whether the contract was rejected because it waited on the
+    /// counterparty for too long, rather than by either party.
+    #[serde(default)]
+    expired: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JsonOutcomePayout {
+    outcome: String,
+    offer_payout_sats: u64,
+    accept_payout_sats: u64,
+}
+
+impl JsonContractDetail {
+    /// Builds the detail of a contract. Closed contracts only keep their ID,
+    /// counterparty and closing transaction in DLC storage, so anything else
+    /// is taken from `previous_detail`, a detail saved while the contract was
+    /// still open.
+    pub fn new(contract: &Contract, previous_detail: Option<JsonContractDetail>) -> Self {
+        let mut detail = JsonContractDetail {
+            id: hex::encode(contract.get_id()),
+            temporary_id: hex::encode(get_temporary_contract_id(contract)),
+            counter_party_id: contract.get_counter_party_id().to_string(),
+            is_offer_party: is_offer_party(contract),
+            stage: contract.into(),
+            stage_changed_at: previous_detail
+                .as_ref()
+                .and_then(|previous_detail| previous_detail.stage_changed_at),
+            offer_collateral_sats: None,
+            accept_collateral_sats: None,
+            fee_rate_sats_per_vbyte: None,
+            oracle_public_keys: Vec::new(),
+            oracle_event_id: None,
+            oracle_threshold: None,
+            outcome_payouts: Vec::new(),
+            numeric_outcome_base: None,
+            funding_txid: None,
+            funding_vout: None,
+            cet_txids: Vec::new(),
+            closing_cet_txid: None,
+            refund_txid: None,
+            refund_locktime: None,
+            bounty_deadline: previous_detail
+                .as_ref()
+                .and_then(|previous_detail| previous_detail.bounty_deadline),
+            attested_outcome: None,
+            local_payout_sats: None,
+            rejection_reason: None,
+            expired: false,
+        };
+
+        if let Some(offered_contract) = get_offered_contract(contract) {
+            detail.set_offered_contract_fields(offered_contract);
+        }
+
+        if let Some(accepted_contract) = get_accepted_contract(contract) {
+            let dlc_transactions = &accepted_contract.dlc_transactions;
+            detail.funding_txid = Some(dlc_transactions.fund.txid().to_string());
+            detail.funding_vout = Some(dlc_transactions.get_fund_output_index() as u32);
+            detail.cet_txids = dlc_transactions
+                .cets
+                .iter()
+                .map(|cet| cet.txid().to_string())
+                .collect();
+            detail.refund_txid = Some(dlc_transactions.refund.txid().to_string());
+        }
+
+        match contract {
+            Contract::PreClosed(c) => {
+                detail.closing_cet_txid = Some(c.signed_cet.txid().to_string());
+                detail.attested_outcome =
+                    get_attested_outcome(&c.attestations, detail.numeric_outcome_base);
+                detail.local_payout_sats = detail.get_local_payout_for_attested_outcome();
+            }
+            Contract::Closed(c) => {
+                if let Some(previous_detail) = previous_detail {
+                    detail.fill_missing_fields_from(previous_detail);
+                }
+                detail.closing_cet_txid = c.signed_cet.as_ref().map(|cet| cet.txid().to_string());
+                detail.attested_outcome =
+                    get_attested_outcome(&c.attestations, detail.numeric_outcome_base);
+                detail.local_payout_sats = detail
+                    .get_local_collateral()
+                    .map(|collateral| (collateral as i64 + c.pnl).max(0) as u64);
+            }
+            Contract::Refunded(_) => {
+                detail.local_payout_sats = detail.get_local_collateral();
+            }
+            // A contract that's rejected after it was accepted keeps its funding
+            // transaction's ID, so that it can still be spotted on the chain.
+            Contract::Rejected(_) => {
+                if let Some(previous_detail) = previous_detail {
+                    detail.rejection_reason = previous_detail.rejection_reason;
+                    detail.expired = previous_detail.expired;
+                    detail.funding_txid = previous_detail.funding_txid;
+                    detail.funding_vout = previous_detail.funding_vout;
+                }
+            }
+            _ => {}
+        };
+
+        detail
+    }
+
+    pub fn get_stage_changed_at(&self) -> Option<u64> {
+        self.stage_changed_at
+    }
+
+    pub fn set_stage_changed_at(&mut self, stage_changed_at: u64) {
+        self.stage_changed_at = Some(stage_changed_at);
+    }
+
+    pub fn set_rejection_reason(&mut self, rejection_reason: Option<String>) {
+        self.rejection_reason = rejection_reason;
+    }
+
+    pub fn set_expired(&mut self, expired: bool) {
+        self.expired = expired;
+    }
+
+    /// Whether the detail records how the contract closed or what it paid
+    /// out, which DLC storage doesn't keep.
+    pub fn has_closing_data(&self) -> bool {
+        self.closing_cet_txid.is_some() || self.local_payout_sats.is_some()
+    }
+
+    pub fn set_bounty_deadline(&mut self, bounty_deadline: u32) {
+        self.bounty_deadline = Some(bounty_deadline);
+    }
+
+    fn set_offered_contract_fields(&mut self, offered_contract: &OfferedContract) {
+        let offer_collateral = offered_contract.offer_params.collateral;
+        self.offer_collateral_sats = Some(offer_collateral);
+        self.accept_collateral_sats = Some(offered_contract.total_collateral - offer_collateral);
+        self.fee_rate_sats_per_vbyte = Some(offered_contract.fee_rate_per_vb);
+        self.refund_locktime = Some(offered_contract.refund_locktime);
+
+        for contract_info in &offered_contract.contract_info {
+            self.oracle_threshold = Some(contract_info.threshold);
+            for announcement in &contract_info.oracle_announcements {
+                self.oracle_public_keys
+                    .push(announcement.oracle_public_key.to_string());
+                if self.oracle_event_id.is_none() {
+                    self.oracle_event_id = Some(announcement.oracle_event.event_id.clone());
+                }
+            }
+
+            if let ContractDescriptor::Numerical(numerical_descriptor) =
+                &contract_info.contract_descriptor
+            {
+                self.numeric_outcome_base =
+                    Some(numerical_descriptor.oracle_numeric_infos.base as u64);
+            }
+            self.outcome_payouts.extend(get_outcome_payouts(
+                &contract_info.contract_descriptor,
+                offered_contract.total_collateral,
+            ));
+        }
+    }
+
+    fn fill_missing_fields_from(&mut self, previous_detail: JsonContractDetail) {
+        self.is_offer_party = self.is_offer_party.or(previous_detail.is_offer_party);
+        self.offer_collateral_sats = previous_detail.offer_collateral_sats;
+        self.accept_collateral_sats = previous_detail.accept_collateral_sats;
+        self.fee_rate_sats_per_vbyte = previous_detail.fee_rate_sats_per_vbyte;
+        self.oracle_public_keys = previous_detail.oracle_public_keys;
+        self.oracle_event_id = previous_detail.oracle_event_id;
+        self.oracle_threshold = previous_detail.oracle_threshold;
+        self.outcome_payouts = previous_detail.outcome_payouts;
+        self.numeric_outcome_base = previous_detail.numeric_outcome_base;
+        self.funding_txid = previous_detail.funding_txid;
+        self.funding_vout = previous_detail.funding_vout;
+        self.cet_txids = previous_detail.cet_txids;
+        self.refund_txid = previous_detail.refund_txid;
+        self.refund_locktime = previous_detail.refund_locktime;
+    }
+
+    fn get_local_collateral(&self) -> Option<u64> {
+        if self.is_offer_party? {
+            self.offer_collateral_sats
+        } else {
+            self.accept_collateral_sats
+        }
+    }
+
+    fn get_local_payout_for_attested_outcome(&self) -> Option<u64> {
+        let attested_outcome = self.attested_outcome.as_ref()?;
+        let outcome_payout = self
+            .outcome_payouts
+            .iter()
+            .find(|outcome_payout| &outcome_payout.outcome == attested_outcome)?;
+
+        if self.is_offer_party? {
+            Some(outcome_payout.offer_payout_sats)
+        } else {
+            Some(outcome_payout.accept_payout_sats)
+        }
+    }
+}
+
+/// Returns what each party is paid for each outcome. Outcomes of numeric
+/// contracts are the values that can be attested to, in base 10.
+pub fn get_outcome_payouts(
+    contract_descriptor: &ContractDescriptor,
+    total_collateral: u64,
+) -> Vec<JsonOutcomePayout> {
+    match contract_descriptor {
+        ContractDescriptor::Enum(enum_descriptor) => enum_descriptor
+            .outcome_payouts
+            .iter()
+            .map(|outcome_payout| JsonOutcomePayout {
+                outcome: outcome_payout.outcome.clone(),
+                offer_payout_sats: outcome_payout.payout.offer,
+                accept_payout_sats: outcome_payout.payout.accept,
+            })
+            .collect(),
+        ContractDescriptor::Numerical(numerical_descriptor) => {
+            let range_payouts = numerical_descriptor
+                .get_range_payouts(total_collateral)
+                .unwrap_or_default();
+            let mut outcome_payouts = Vec::new();
+            for range_payout in range_payouts {
+                for outcome in range_payout.start..range_payout.start + range_payout.count {
+                    outcome_payouts.push(JsonOutcomePayout {
+                        outcome: outcome.to_string(),
+                        offer_payout_sats: range_payout.payout.offer,
+                        accept_payout_sats: range_payout.payout.accept,
+                    });
+                }
+            }
+            outcome_payouts
+        }
+    }
+}
+
+/// Returns our own inputs to a contract's funding transaction if the contract
+/// hasn't been funded yet, during which time the wallet keeps them reserved.
+pub fn get_reserved_funding_inputs(contract: &Contract) -> &[FundingInputInfo] {
+    let accepted_contract_or = match contract {
+        Contract::Offered(_) => None,
+        Contract::Accepted(_) | Contract::Signed(_) => get_accepted_contract(contract),
+        _ => return &[],
+    };
+    match (get_offered_contract(contract), accepted_contract_or) {
+        (Some(offered_contract), _) if offered_contract.is_offer_party => {
+            &offered_contract.funding_inputs_info
+        }
+        (_, Some(accepted_contract)) => &accepted_contract.funding_inputs,
+        _ => &[],
+    }
+}
+
+/// Returns the outpoints that funding inputs spend.
+pub fn get_funding_outpoints(
+    funding_inputs: &[FundingInputInfo],
+) -> Result<Vec<OutPoint>, ResolvrError> {
+    funding_inputs
+        .iter()
+        .map(|funding_input_info| {
+            let funding_input = &funding_input_info.funding_input;
+            let prev_tx: Transaction = bitcoin::consensus::deserialize(&funding_input.prev_tx)
+                .map_err(|e| {
+                    ResolvrError::Storage(format!("Error decoding funding input: {}", e))
+                })?;
+            Ok(OutPoint::new(prev_tx.txid(), funding_input.prev_tx_vout))
+        })
+        .collect()
+}
+
+fn get_offered_contract(contract: &Contract) -> Option<&OfferedContract> {
+    match contract {
+        Contract::Offered(c) | Contract::Rejected(c) => Some(c),
+        Contract::FailedAccept(c) => Some(&c.offered_contract),
+        _ => get_accepted_contract(contract).map(|c| &c.offered_contract),
+    }
+}
+
+fn get_accepted_contract(contract: &Contract) -> Option<&AcceptedContract> {
+    match contract {
+        Contract::Accepted(c) => Some(c),
+        Contract::Signed(c) | Contract::Confirmed(c) | Contract::Refunded(c) => {
+            Some(&c.accepted_contract)
+        }
+        Contract::PreClosed(c) => Some(&c.signed_contract.accepted_contract),
+        Contract::FailedSign(c) => Some(&c.accepted_contract),
+        _ => None,
+    }
+}
+
+/// Returns the outcome attested to by the first oracle. Numeric outcomes are
+/// attested to one digit at a time, so they're converted to a base 10 value.
+fn get_attested_outcome(
+    attestations: &Option<Vec<OracleAttestation>>,
+    numeric_outcome_base: Option<u64>,
+) -> Option<String> {
+    let attestation = attestations.as_ref()?.first()?;
+    match numeric_outcome_base {
+        Some(base) => attestation
+            .outcomes
+            .iter()
+            .try_fold(0u64, |value, digit| {
+                Some(value * base + digit.parse::<u64>().ok()?)
+            })
+            .map(|value| value.to_string()),
+        None => Some(attestation.outcomes.join("")),
+    }
+}
+
+/// Returns the ID the contract was identified by while it was being offered,
+/// which is replaced by the final contract ID once it's accepted.
+pub fn get_temporary_contract_id(contract: &Contract) -> ContractId {
+    match contract {
+        Contract::Offered(c) | Contract::Rejected(c) => c.id,
+        Contract::Accepted(c) => c.offered_contract.id,
+        Contract::Signed(c) | Contract::Confirmed(c) | Contract::Refunded(c) => {
+            c.accepted_contract.offered_contract.id
+        }
+        Contract::PreClosed(c) => c.signed_contract.accepted_contract.offered_contract.id,
+        Contract::Closed(c) => c.temporary_contract_id,
+        Contract::FailedAccept(c) => c.offered_contract.id,
+        Contract::FailedSign(c) => c.accepted_contract.offered_contract.id,
+    }
+}
+
 fn is_offer_party(contract: &Contract) -> Option<bool> {
     match contract {
         Contract::Offered(c) | Contract::Rejected(c) => Some(c.is_offer_party),
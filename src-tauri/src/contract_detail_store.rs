@@ -0,0 +1,66 @@
+use crate::contract::JsonContractDetail;
+use dlc_manager::ContractId;
+use escrow_agent_messages::error::ResolvrError;
+
+/// Keeps the last detail of every contract seen before it closed. DLC storage
+/// drops almost everything about a contract once it closes, so this is where
+/// the detail of closed contracts is filled in from.
+pub struct ContractDetailStore {
+    db: sled::Db,
+}
+
+impl ContractDetailStore {
+    pub fn new(path: &str) -> Result<Self, ResolvrError> {
+        match sled::open(path) {
+            Ok(db) => Ok(Self { db }),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error opening contract detail store: {}",
+                e
+            ))),
+        }
+    }
+
+    pub fn get(
+        &self,
+        contract_id: &ContractId,
+    ) -> Result<Option<JsonContractDetail>, ResolvrError> {
+        match self.db.get(contract_id) {
+            Ok(Some(v)) => serde_json::from_slice(&v).map(Some).map_err(|e| {
+                ResolvrError::Storage(format!("Error decoding contract detail: {}", e))
+            }),
+            Ok(None) => Ok(None),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error reading contract detail: {}",
+                e
+            ))),
+        }
+    }
+
+    pub fn save(
+        &self,
+        contract_id: &ContractId,
+        contract_detail: &JsonContractDetail,
+    ) -> Result<(), ResolvrError> {
+        let bytes = serde_json::to_vec(contract_detail)
+            .map_err(|e| ResolvrError::Storage(format!("Error encoding contract detail: {}", e)))?;
+        self.db
+            .insert(contract_id, bytes)
+            .map_err(|e| ResolvrError::Storage(format!("Error saving contract detail: {}", e)))?;
+
+        // Closing data can't be rebuilt from DLC storage, so it's written out
+        // straight away rather than whenever sled next flushes.
+        if contract_detail.has_closing_data() {
+            self.db.flush().map_err(|e| {
+                ResolvrError::Storage(format!("Error flushing contract detail: {}", e))
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, contract_id: &ContractId) -> Result<(), ResolvrError> {
+        self.db
+            .remove(contract_id)
+            .map(|_| ())
+            .map_err(|e| ResolvrError::Storage(format!("Error removing contract detail: {}", e)))
+    }
+}
@@ -0,0 +1,2002 @@
+use crate::chain_backend::{ChainBackend, ChainBackendConfig, WalletBalance, WalletUtxo};
+use crate::contract::{
+    get_funding_outpoints, get_outcome_payouts, get_reserved_funding_inputs,
+    get_temporary_contract_id, JsonContractDetail, JsonContractStage, JsonContractStageChange,
+    JsonOutcomePayout,
+};
+use crate::contract_detail_store::ContractDetailStore;
+use crate::dlc_msg_handler::NostrNip4DlcMessageHandler;
+use crate::dlc_msg_queue::QueuedDlcMsg;
+use crate::escrow_agent_registry::EscrowAgentOracles;
+use crate::fee_service::{
+    estimate_funding_tx_vbytes, estimate_party_fees, FeeRateInput, FeeRateSuggestions, FeeService,
+    FeeTarget, PartyFeeEstimate,
+};
+use crate::network::{check_counterparty_msg, get_chain_hash, parse_address};
+use crate::now_epoch_secs;
+use crate::resolvr_oracle::NostrNip4ResolvrOracle;
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use bitcoin::{Network, Transaction};
+use dlc_manager::contract::contract_input::ContractInput;
+use dlc_manager::contract::offered_contract::OfferedContract;
+use dlc_manager::contract::{Contract, ContractDescriptor};
+use dlc_manager::contract_updater;
+use dlc_manager::manager::REFUND_DELAY;
+use dlc_manager::{Blockchain, ContractId, Oracle, Storage, SystemTimeProvider, Wallet};
+use dlc_messages::oracle_msgs::{EventDescriptor, OracleAnnouncement};
+use dlc_sled_storage_provider::SledStorageProvider;
+use escrow_agent_messages::dlc_msg_codec::{DlcMsg, RejectDlc};
+use escrow_agent_messages::error::ResolvrError;
+use lightning::chain::chaininterface::FeeEstimator;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+
+/// How many commands can be waiting for the manager before callers have to
+/// wait to send more.
+const COMMAND_CHANNEL_CAPACITY: usize = 32;
+
+/// How many contract stage changes are buffered for each subscriber before the
+/// oldest are dropped.
+const STAGE_CHANGE_CHANNEL_CAPACITY: usize = 64;
+
+/// How long to wait before the first attempt to reconnect to the chain
+/// backend after RPC calls start failing. The wait doubles after every failed
+/// attempt, up to `MAX_RECONNECT_DELAY`.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// Longest reason that can be given when rejecting an offer.
+const MAX_REJECTION_REASON_CHARS: usize = 500;
+
+pub type ResolvrDlcManager = dlc_manager::manager::Manager<
+    Arc<dyn Wallet + Send + Sync>,
+    Arc<dyn Blockchain + Send + Sync>,
+    Arc<SledStorageProvider>,
+    Arc<NostrNip4ResolvrOracle>,
+    Arc<SystemTimeProvider>,
+    Arc<dyn FeeEstimator + Send + Sync>,
+>;
+
+type Responder<T> = oneshot::Sender<Result<T, ResolvrError>>;
+
+/// When a bounty must be completed by, and how long after that the maker can
+/// take their funds back if the escrow agent never attests to an outcome.
+pub struct BountyTimeout {
+    /// Unix timestamp (in seconds) by which the bounty must be completed. Must
+    /// not be after the escrow agent's oracle event matures.
+    pub deadline: u32,
+
+    /// How long after the oracle event matures the contract can be refunded.
+    /// Must be between `REFUND_DELAY` and twice that, which is the range
+    /// counterparties accept.
+    pub refund_delay_secs: u32,
+}
+
+/// How long a contract can wait on the counterparty before it's rejected,
+/// counted from when it moved to its current stage.
+pub struct NegotiationExpiry {
+    /// How long an offer can go without being accepted.
+    pub offered_secs: u64,
+
+    /// How long an accepted contract can go without the offerer signing it.
+    pub accepted_secs: u64,
+}
+
+/// What offering a contract would cost and pay out, worked out without sending
+/// the offer or reserving any UTXOs.
+#[derive(Serialize)]
+pub struct OfferPreview {
+    pub fee_rate_sats_per_vbyte: u64,
+
+    /// Estimated size of the funding transaction, assuming the taker funds it
+    /// with a single input.
+    pub funding_tx_vbytes: u64,
+
+    pub funding_tx_fee_sats: u64,
+
+    /// What the maker pays in fees.
+    pub offer_fees: PartyFeeEstimate,
+
+    /// What the taker is estimated to pay in fees.
+    pub accept_fees: PartyFeeEstimate,
+
+    pub outcome_payouts: Vec<JsonOutcomePayout>,
+
+    /// Unix timestamp (in seconds) after which the contract can be refunded.
+    pub refund_locktime: u32,
+
+    /// The maker's UTXOs that would fund the contract.
+    pub funding_utxos: Vec<WalletUtxo>,
+}
+
+/// What `check_offer` works out for an offer it accepts.
+struct CheckedOffer {
+    oracle_announcements: Vec<Vec<OracleAnnouncement>>,
+
+    /// Unix timestamp (in seconds) after which the contract can be refunded.
+    refund_locktime: u32,
+
+    /// What to offer the contract with for `dlc_manager` to land on
+    /// `refund_locktime`.
+    refund_delay: u32,
+}
+
+enum DlcManagerCommand {
+    Connect {
+        chain_backend_config: ChainBackendConfig,
+        oracles: EscrowAgentOracles,
+        /// Whether to leave the reconnect scheduler retrying if the backend
+        /// can't be reached.
+        retry_if_unreachable: bool,
+        responder: Responder<()>,
+    },
+    Reconnect {
+        /// Config to reconnect with, or None to reuse the last one.
+        chain_backend_config: Option<ChainBackendConfig>,
+        responder: Responder<()>,
+    },
+    Disconnect {
+        responder: Responder<()>,
+    },
+    SetOracles {
+        oracles: EscrowAgentOracles,
+        responder: Responder<()>,
+    },
+    OfferContract {
+        contract_input: ContractInput,
+        counter_party: PublicKey,
+        bounty_timeout: BountyTimeout,
+        responder: Responder<()>,
+    },
+    PreviewOffer {
+        contract_input: ContractInput,
+        bounty_timeout: BountyTimeout,
+        responder: Responder<OfferPreview>,
+    },
+    AcceptContract {
+        contract_id: ContractId,
+        responder: Responder<Contract>,
+    },
+    RejectContract {
+        contract_id: ContractId,
+        reason: Option<String>,
+        responder: Responder<()>,
+    },
+    GetContracts {
+        responder: Responder<Vec<Contract>>,
+    },
+    GetContract {
+        contract_id: ContractId,
+        responder: Responder<JsonContractDetail>,
+    },
+    DeleteContract {
+        contract_id: ContractId,
+        responder: Responder<()>,
+    },
+    PeriodicCheck {
+        /// Whether to fail if the manager hasn't been created yet, rather than
+        /// report no changes.
+        require_dlc_manager: bool,
+        responder: Responder<Vec<JsonContractStageChange>>,
+    },
+    ExpireStaleNegotiations {
+        responder: Responder<Vec<JsonContractStageChange>>,
+    },
+    ProcessIncomingMsgs {
+        responder: Responder<()>,
+    },
+    GetWalletBalance {
+        responder: Responder<WalletBalance>,
+    },
+    GetNewAddress {
+        responder: Responder<String>,
+    },
+    GetWalletUtxos {
+        responder: Responder<Vec<WalletUtxo>>,
+    },
+    SendToAddress {
+        address: String,
+        amount_sats: u64,
+        responder: Responder<String>,
+    },
+    GetFeeRateSuggestions {
+        responder: Responder<FeeRateSuggestions>,
+    },
+    ResolveFeeRate {
+        fee_rate_input: FeeRateInput,
+        responder: Responder<u64>,
+    },
+}
+
+/// A handle to the task that owns the DLC manager. The manager is only ever
+/// touched by that task, which runs the commands sent through this handle one
+/// at a time, so callers never contend on a lock or block the async runtime.
+#[derive(Clone)]
+pub struct DlcManagerHandle {
+    command_sender: mpsc::Sender<DlcManagerCommand>,
+    stage_change_sender: broadcast::Sender<JsonContractStageChange>,
+    rpc_failure_notify: Arc<Notify>,
+    network: Network,
+}
+
+impl DlcManagerHandle {
+    /// Starts the task that owns the DLC manager. The manager itself isn't
+    /// created until `connect` is called, and only with a chain backend on
+    /// `network`.
+    pub fn spawn(
+        network: Network,
+        fee_service: FeeService,
+        negotiation_expiry: NegotiationExpiry,
+        dlc_storage: Arc<SledStorageProvider>,
+        contract_detail_store: ContractDetailStore,
+        dlc_msg_handler: Arc<NostrNip4DlcMessageHandler>,
+    ) -> Self {
+        let (command_sender, command_receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        let (stage_change_sender, _) = broadcast::channel(STAGE_CHANGE_CHANNEL_CAPACITY);
+        let rpc_failure_notify = Arc::new(Notify::new());
+
+        let actor = DlcManagerActor {
+            dlc_manager_or: None,
+            chain_backend_config_or: None,
+            chain_backend_or: None,
+            is_rpc_failing: false,
+            oracles: HashMap::new(),
+            network,
+            fee_service,
+            negotiation_expiry,
+            dlc_storage,
+            contract_detail_store,
+            dlc_msg_handler,
+            stage_change_sender: stage_change_sender.clone(),
+            rpc_failure_notify: rpc_failure_notify.clone(),
+            runtime: tokio::runtime::Handle::current(),
+        };
+
+        // Manager calls block on RPC and relay requests, so the actor gets its
+        // own thread rather than tying up a runtime worker.
+        std::thread::Builder::new()
+            .name(String::from("dlc-manager"))
+            .spawn(move || actor.run(command_receiver))
+            .expect("Error spawning DLC manager thread.");
+
+        Self {
+            command_sender,
+            stage_change_sender,
+            rpc_failure_notify,
+            network,
+        }
+    }
+
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    /// Subscribes to contract stage changes, whether they're caused by
+    /// commands, incoming messages or periodic checks.
+    pub fn subscribe_to_stage_changes(&self) -> broadcast::Receiver<JsonContractStageChange> {
+        self.stage_change_sender.subscribe()
+    }
+
+    pub async fn connect(
+        &self,
+        chain_backend_config: ChainBackendConfig,
+        oracles: EscrowAgentOracles,
+    ) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::Connect {
+            chain_backend_config,
+            oracles,
+            retry_if_unreachable: false,
+            responder,
+        })
+        .await
+    }
+
+    /// Like `connect`, but if the backend can't be reached, such as when the
+    /// app starts before the node, the reconnect scheduler keeps retrying with
+    /// backoff until it's reached or `disconnect` is called. Still returns the
+    /// first attempt's error.
+    pub async fn connect_with_retry(
+        &self,
+        chain_backend_config: ChainBackendConfig,
+        oracles: EscrowAgentOracles,
+    ) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::Connect {
+            chain_backend_config,
+            oracles,
+            retry_if_unreachable: true,
+            responder,
+        })
+        .await
+    }
+
+    /// Reconnects to the chain backend, with a new config or the last one used.
+    /// The manager is recreated around the new connection, and contracts are
+    /// kept.
+    pub async fn reconnect(
+        &self,
+        chain_backend_config: Option<ChainBackendConfig>,
+    ) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::Reconnect {
+            chain_backend_config,
+            responder,
+        })
+        .await
+    }
+
+    /// Drops the connection to the chain backend along with the manager, which
+    /// stop being used until `connect` or `reconnect` is called. Contracts are
+    /// kept.
+    pub async fn disconnect(&self) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::Disconnect { responder })
+            .await
+    }
+
+    /// Replaces the oracles that contracts can be adjudicated by, such as after
+    /// an escrow agent is added or removed.
+    pub async fn set_oracles(&self, oracles: EscrowAgentOracles) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::SetOracles { oracles, responder })
+            .await
+    }
+
+    /// Creates an offer for the given contract and sends it to the
+    /// counterparty. The contract can be refunded once the given timeout has
+    /// passed.
+    pub async fn offer_contract(
+        &self,
+        contract_input: ContractInput,
+        counter_party: PublicKey,
+        bounty_timeout: BountyTimeout,
+    ) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::OfferContract {
+            contract_input,
+            counter_party,
+            bounty_timeout,
+            responder,
+        })
+        .await
+    }
+
+    /// Works out what offering a contract would cost and pay out, without
+    /// sending anything or reserving any UTXOs.
+    pub async fn preview_offer(
+        &self,
+        contract_input: ContractInput,
+        bounty_timeout: BountyTimeout,
+    ) -> Result<OfferPreview, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::PreviewOffer {
+            contract_input,
+            bounty_timeout,
+            responder,
+        })
+        .await
+    }
+
+    /// Accepts an offered contract, sends the acceptance to the counterparty
+    /// and returns the updated contract.
+    pub async fn accept_contract(&self, contract_id: ContractId) -> Result<Contract, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::AcceptContract {
+            contract_id,
+            responder,
+        })
+        .await
+    }
+
+    /// Declines a contract offered by a counterparty, and lets them know along
+    /// with the reason, if one is given.
+    pub async fn reject_contract(
+        &self,
+        contract_id: ContractId,
+        reason: Option<String>,
+    ) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::RejectContract {
+            contract_id,
+            reason,
+            responder,
+        })
+        .await
+    }
+
+    pub async fn get_wallet_balance(&self) -> Result<WalletBalance, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::GetWalletBalance { responder })
+            .await
+    }
+
+    /// Returns a new address to receive funds to, e.g. before offering a
+    /// contract.
+    pub async fn get_new_address(&self) -> Result<String, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::GetNewAddress { responder })
+            .await
+    }
+
+    /// Lists the wallet's UTXOs, including the ones reserved to fund contracts.
+    pub async fn get_wallet_utxos(&self) -> Result<Vec<WalletUtxo>, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::GetWalletUtxos { responder })
+            .await
+    }
+
+    /// Sends funds from the wallet to an address on the app's network and
+    /// returns the ID of the transaction.
+    pub async fn send_to_address(
+        &self,
+        address: String,
+        amount_sats: u64,
+    ) -> Result<String, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::SendToAddress {
+            address,
+            amount_sats,
+            responder,
+        })
+        .await
+    }
+
+    /// Returns the chain backend's fee rate estimates for each target.
+    pub async fn get_fee_rate_suggestions(&self) -> Result<FeeRateSuggestions, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::GetFeeRateSuggestions { responder })
+            .await
+    }
+
+    /// Returns the fee rate to fund a contract at, in sats per vbyte. Fails
+    /// if the rate is above the configured maximum.
+    pub async fn resolve_fee_rate(
+        &self,
+        fee_rate_input: FeeRateInput,
+    ) -> Result<u64, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::ResolveFeeRate {
+            fee_rate_input,
+            responder,
+        })
+        .await
+    }
+
+    pub async fn get_contracts(&self) -> Result<Vec<Contract>, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::GetContracts { responder })
+            .await
+    }
+
+    pub async fn get_contract(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<JsonContractDetail, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::GetContract {
+            contract_id,
+            responder,
+        })
+        .await
+    }
+
+    pub async fn delete_contract(&self, contract_id: ContractId) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::DeleteContract {
+            contract_id,
+            responder,
+        })
+        .await
+    }
+
+    /// Checks on the chain and oracles for every contract, advancing them to
+    /// Confirmed, Closed or Refunded where possible, and returns the contracts
+    /// that changed stage.
+    pub async fn periodic_check(&self) -> Result<Vec<JsonContractStageChange>, ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::PeriodicCheck {
+            require_dlc_manager: true,
+            responder,
+        })
+        .await
+    }
+
+    /// Starts a task that runs `periodic_check` every `interval` for as long as
+    /// the app runs, and rejects contracts that have waited on the counterparty
+    /// for too long. Checks are skipped until the manager has been created,
+    /// but contracts expire regardless, even if the check fails.
+    pub fn spawn_periodic_check_scheduler(&self, interval: Duration) {
+        let dlc_manager = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+
+                match dlc_manager
+                    .send_command(|responder| DlcManagerCommand::PeriodicCheck {
+                        require_dlc_manager: false,
+                        responder,
+                    })
+                    .await
+                {
+                    Ok(stage_changes) => print_stage_changes(&stage_changes),
+                    Err(e) => println!("Error running scheduled periodic check: {}", e),
+                };
+
+                match dlc_manager
+                    .send_command(|responder| DlcManagerCommand::ExpireStaleNegotiations {
+                        responder,
+                    })
+                    .await
+                {
+                    Ok(stage_changes) => print_stage_changes(&stage_changes),
+                    Err(e) => println!("Error expiring stale contracts: {}", e),
+                };
+            }
+        });
+    }
+
+    /// Starts a task that reconnects to the chain backend whenever RPC calls
+    /// start failing, retrying with exponential backoff until it succeeds or the
+    /// connection is dropped with `disconnect`.
+    pub fn spawn_reconnect_scheduler(&self) {
+        let dlc_manager = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                dlc_manager.rpc_failure_notify.notified().await;
+
+                let mut delay = INITIAL_RECONNECT_DELAY;
+                loop {
+                    tokio::time::sleep(delay).await;
+                    match dlc_manager.reconnect(None).await {
+                        Ok(()) => {
+                            println!("Reconnected to the chain backend.");
+                            break;
+                        }
+                        Err(ResolvrError::Rpc(e)) => {
+                            println!("Error reconnecting to the chain backend: {}", e);
+                            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                        // Anything else, such as having been disconnected in
+                        // the meantime, won't be fixed by retrying.
+                        Err(e) => {
+                            println!("Stopped reconnecting to the chain backend: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Processes any incoming DLC messages that are due. Does nothing if the
+    /// manager hasn't been created yet, leaving the messages queued.
+    pub async fn process_incoming_msgs(&self) -> Result<(), ResolvrError> {
+        self.send_command(|responder| DlcManagerCommand::ProcessIncomingMsgs { responder })
+            .await
+    }
+
+    async fn send_command<T>(
+        &self,
+        make_command: impl FnOnce(Responder<T>) -> DlcManagerCommand,
+    ) -> Result<T, ResolvrError> {
+        let (responder, response_receiver) = oneshot::channel();
+        if self
+            .command_sender
+            .send(make_command(responder))
+            .await
+            .is_err()
+        {
+            return Err(ResolvrError::Internal(String::from(
+                "DLC manager task has stopped.",
+            )));
+        }
+
+        match response_receiver.await {
+            Ok(response) => response,
+            Err(_) => Err(ResolvrError::Internal(String::from(
+                "DLC manager task dropped the command.",
+            ))),
+        }
+    }
+}
+
+struct DlcManagerActor {
+    dlc_manager_or: Option<ResolvrDlcManager>,
+    chain_backend_config_or: Option<ChainBackendConfig>,
+    chain_backend_or: Option<ChainBackend>,
+
+    /// Whether an RPC call has failed since the last successful connection.
+    is_rpc_failing: bool,
+
+    oracles: EscrowAgentOracles,
+    network: Network,
+    fee_service: FeeService,
+    negotiation_expiry: NegotiationExpiry,
+    dlc_storage: Arc<SledStorageProvider>,
+    contract_detail_store: ContractDetailStore,
+    dlc_msg_handler: Arc<NostrNip4DlcMessageHandler>,
+    stage_change_sender: broadcast::Sender<JsonContractStageChange>,
+    rpc_failure_notify: Arc<Notify>,
+
+    /// Runtime that messages are published on. The actor's own thread isn't
+    /// part of it, so it can block on them.
+    runtime: tokio::runtime::Handle,
+}
+
+impl DlcManagerActor {
+    fn run(mut self, mut command_receiver: mpsc::Receiver<DlcManagerCommand>) {
+        if let Err(e) = self.backfill_contract_details() {
+            println!("Error backfilling contract details: {}", e);
+        }
+
+        while let Some(command) = command_receiver.blocking_recv() {
+            self.handle_command(command);
+        }
+    }
+
+    fn handle_command(&mut self, command: DlcManagerCommand) {
+        // A dropped responder only means the caller stopped waiting, so
+        // failures to respond are ignored.
+        match command {
+            DlcManagerCommand::Connect {
+                chain_backend_config,
+                oracles,
+                retry_if_unreachable,
+                responder,
+            } => {
+                let _ = responder.send(self.connect(
+                    chain_backend_config,
+                    oracles,
+                    retry_if_unreachable,
+                ));
+            }
+            DlcManagerCommand::Reconnect {
+                chain_backend_config,
+                responder,
+            } => {
+                let _ = responder.send(self.reconnect(chain_backend_config));
+            }
+            DlcManagerCommand::Disconnect { responder } => {
+                self.disconnect();
+                let _ = responder.send(Ok(()));
+            }
+            DlcManagerCommand::SetOracles { oracles, responder } => {
+                let _ = responder.send(self.set_oracles(oracles));
+            }
+            DlcManagerCommand::OfferContract {
+                contract_input,
+                counter_party,
+                bounty_timeout,
+                responder,
+            } => {
+                let _ = responder.send(
+                    self.track_stage_changes(|actor| {
+                        actor.offer_contract(&contract_input, counter_party, &bounty_timeout)
+                    })
+                    .map(|(v, _)| v),
+                );
+            }
+            DlcManagerCommand::PreviewOffer {
+                contract_input,
+                bounty_timeout,
+                responder,
+            } => {
+                let _ = responder.send(self.preview_offer(&contract_input, &bounty_timeout));
+            }
+            DlcManagerCommand::AcceptContract {
+                contract_id,
+                responder,
+            } => {
+                let _ = responder.send(
+                    self.track_stage_changes(|actor| actor.accept_contract(&contract_id))
+                        .map(|(v, _)| v),
+                );
+            }
+            DlcManagerCommand::RejectContract {
+                contract_id,
+                reason,
+                responder,
+            } => {
+                let _ = responder.send(
+                    self.track_stage_changes(|actor| actor.reject_contract(&contract_id, reason))
+                        .map(|_| ()),
+                );
+            }
+            DlcManagerCommand::GetContracts { responder } => {
+                let _ =
+                    responder.send(self.dlc_storage.get_contracts().map_err(|e| {
+                        ResolvrError::Storage(format!("Error getting contracts: {}", e))
+                    }));
+            }
+            DlcManagerCommand::GetContract {
+                contract_id,
+                responder,
+            } => {
+                let _ = responder.send(self.get_contract(&contract_id));
+            }
+            DlcManagerCommand::DeleteContract {
+                contract_id,
+                responder,
+            } => {
+                let _ = responder.send(self.delete_contract(&contract_id));
+            }
+            DlcManagerCommand::PeriodicCheck {
+                require_dlc_manager,
+                responder,
+            } => {
+                if self.dlc_manager_or.is_none() && !require_dlc_manager {
+                    let _ = responder.send(Ok(Vec::new()));
+                } else {
+                    let _ = responder.send(
+                        self.track_stage_changes(|actor| actor.periodic_check())
+                            .map(|(_, stage_changes)| stage_changes),
+                    );
+                }
+            }
+            DlcManagerCommand::ExpireStaleNegotiations { responder } => {
+                let _ = responder.send(
+                    self.track_stage_changes(|actor| {
+                        actor.expire_stale_negotiations(now_epoch_secs());
+                        Ok(())
+                    })
+                    .map(|(_, stage_changes)| stage_changes),
+                );
+            }
+            DlcManagerCommand::ProcessIncomingMsgs { responder } => {
+                let _ = responder.send(self.process_incoming_msgs());
+            }
+            DlcManagerCommand::GetWalletBalance { responder } => {
+                let _ = responder.send(self.get_wallet_balance());
+            }
+            DlcManagerCommand::GetNewAddress { responder } => {
+                let _ = responder.send(
+                    self.get_chain_backend()
+                        .and_then(|chain_backend| chain_backend.get_new_address())
+                        .map(|address| address.to_string()),
+                );
+            }
+            DlcManagerCommand::GetWalletUtxos { responder } => {
+                let _ = responder.send(self.get_wallet_utxos());
+            }
+            DlcManagerCommand::SendToAddress {
+                address,
+                amount_sats,
+                responder,
+            } => {
+                let _ = responder.send(self.send_to_address(&address, amount_sats));
+            }
+            DlcManagerCommand::GetFeeRateSuggestions { responder } => {
+                let _ = responder.send(self.get_chain_backend().map(|chain_backend| {
+                    self.fee_service
+                        .get_fee_rate_suggestions(chain_backend.fee_estimator.as_ref())
+                }));
+            }
+            DlcManagerCommand::ResolveFeeRate {
+                fee_rate_input,
+                responder,
+            } => {
+                let _ = responder.send(self.get_chain_backend().and_then(|chain_backend| {
+                    self.fee_service
+                        .resolve_fee_rate(chain_backend.fee_estimator.as_ref(), fee_rate_input)
+                }));
+            }
+        }
+    }
+
+    fn get_dlc_manager(&mut self) -> Result<&mut ResolvrDlcManager, ResolvrError> {
+        match self.dlc_manager_or.as_mut() {
+            Some(m) => Ok(m),
+            None => Err(ResolvrError::NotInitialized(String::from(
+                "DLC manager not initialized.",
+            ))),
+        }
+    }
+
+    fn get_chain_backend(&self) -> Result<&ChainBackend, ResolvrError> {
+        match &self.chain_backend_or {
+            Some(chain_backend) => Ok(chain_backend),
+            None => Err(ResolvrError::NotInitialized(String::from(
+                "Not connected to a chain backend.",
+            ))),
+        }
+    }
+
+    fn connect(
+        &mut self,
+        chain_backend_config: ChainBackendConfig,
+        oracles: EscrowAgentOracles,
+        retry_if_unreachable: bool,
+    ) -> Result<(), ResolvrError> {
+        if self.dlc_manager_or.is_some() {
+            return Err(ResolvrError::AlreadyInitialized(String::from(
+                "DLC manager already initialized.",
+            )));
+        }
+
+        self.oracles = oracles;
+        let res = self.reconnect(Some(chain_backend_config.clone()));
+
+        // Remembering the config without a manager makes the reconnect
+        // scheduler's retries use it, and `disconnect` stop them.
+        if let Err(ResolvrError::Rpc(_)) = &res {
+            if retry_if_unreachable {
+                self.chain_backend_config_or = Some(chain_backend_config);
+                if !self.is_rpc_failing {
+                    self.is_rpc_failing = true;
+                    self.rpc_failure_notify.notify_one();
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Replaces the chain backend and recreates the manager around it. The
+    /// current connection, if any, is only replaced once the new one works.
+    fn reconnect(
+        &mut self,
+        chain_backend_config: Option<ChainBackendConfig>,
+    ) -> Result<(), ResolvrError> {
+        let chain_backend_config =
+            match chain_backend_config.or_else(|| self.chain_backend_config_or.clone()) {
+                Some(v) => v,
+                None => {
+                    return Err(ResolvrError::NotInitialized(String::from(
+                        "Not connected to a chain backend.",
+                    )))
+                }
+            };
+
+        let chain_backend = ChainBackend::connect(
+            &chain_backend_config,
+            self.network,
+            self.dlc_storage.clone(),
+        )?;
+        // The node may have restarted, or be a different one, since contracts
+        // reserved their UTXOs.
+        self.reserve_contract_utxos(&chain_backend)?;
+        self.dlc_manager_or = Some(self.create_dlc_manager(&chain_backend, &self.oracles)?);
+        self.chain_backend_config_or = Some(chain_backend_config);
+        self.chain_backend_or = Some(chain_backend);
+        self.is_rpc_failing = false;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.dlc_manager_or = None;
+        self.chain_backend_config_or = None;
+        self.chain_backend_or = None;
+        self.is_rpc_failing = false;
+    }
+
+    /// `dlc_manager` has no way to change the oracles of an existing manager,
+    /// so the manager is recreated with the new oracles. All of its state is
+    /// in storage, so nothing is lost.
+    fn set_oracles(&mut self, oracles: EscrowAgentOracles) -> Result<(), ResolvrError> {
+        if let Some(chain_backend) = &self.chain_backend_or {
+            self.dlc_manager_or = Some(self.create_dlc_manager(chain_backend, &oracles)?);
+        }
+        self.oracles = oracles;
+        Ok(())
+    }
+
+    fn create_dlc_manager(
+        &self,
+        chain_backend: &ChainBackend,
+        oracles: &EscrowAgentOracles,
+    ) -> Result<ResolvrDlcManager, ResolvrError> {
+        dlc_manager::manager::Manager::new(
+            chain_backend.wallet.clone(),
+            chain_backend.blockchain.clone(),
+            self.dlc_storage.clone(),
+            oracles.clone(),
+            Arc::new(dlc_manager::SystemTimeProvider {}),
+            chain_backend.fee_estimator.clone(),
+        )
+        .map_err(|e| ResolvrError::from_dlc_manager_error("Error creating DLC manager", e))
+    }
+
+    /// Brings a local wallet's UTXOs up to date before they're needed.
+    fn refresh_wallet(&self) -> Result<(), ResolvrError> {
+        match &self.chain_backend_or {
+            Some(chain_backend) => chain_backend.refresh_wallet(),
+            None => Ok(()),
+        }
+    }
+
+    fn get_wallet_balance(&self) -> Result<WalletBalance, ResolvrError> {
+        self.refresh_wallet()?;
+        self.get_chain_backend()?.get_balance()
+    }
+
+    /// Lists the wallet's UTXOs and flags the ones that contracts are waiting
+    /// to be funded with. Wallets that leave those out of their own list, like
+    /// Bitcoin Core does for locked UTXOs, get them added from the contracts.
+    fn get_wallet_utxos(&self) -> Result<Vec<WalletUtxo>, ResolvrError> {
+        self.refresh_wallet()?;
+        let mut utxos = self.get_chain_backend()?.list_utxos()?;
+
+        let contracts = match self.dlc_storage.get_contracts() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contracts: {}",
+                    e
+                )))
+            }
+        };
+        for contract in &contracts {
+            for funding_input_info in get_reserved_funding_inputs(contract) {
+                let funding_input = &funding_input_info.funding_input;
+                let prev_tx: Transaction =
+                    match bitcoin::consensus::deserialize(&funding_input.prev_tx) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Err(ResolvrError::Storage(format!(
+                                "Error decoding funding input: {}",
+                                e
+                            )))
+                        }
+                    };
+                let txid = prev_tx.txid().to_string();
+                let vout = funding_input.prev_tx_vout;
+
+                match utxos
+                    .iter_mut()
+                    .find(|utxo| utxo.txid == txid && utxo.vout == vout)
+                {
+                    Some(utxo) => utxo.reserved = true,
+                    None => utxos.push(WalletUtxo {
+                        value_sats: prev_tx
+                            .output
+                            .get(vout as usize)
+                            .map_or(0, |tx_out| tx_out.value),
+                        txid,
+                        vout,
+                        address: funding_input_info
+                            .address
+                            .as_ref()
+                            .map(|address| address.to_string()),
+                        confirmations: None,
+                        reserved: true,
+                    }),
+                }
+            }
+        }
+
+        Ok(utxos)
+    }
+
+    fn send_to_address(&self, address: &str, amount_sats: u64) -> Result<String, ResolvrError> {
+        let address = parse_address(address, self.network)?;
+        let chain_backend = self.get_chain_backend()?;
+
+        // Bitcoin Core forgets its locks when it restarts, which can happen
+        // without the app noticing, so they're made again before every send.
+        self.reserve_contract_utxos(chain_backend)?;
+        self.refresh_wallet()?;
+
+        let fee_rate = self.fee_service.resolve_fee_rate(
+            chain_backend.fee_estimator.as_ref(),
+            FeeRateInput::Target {
+                target: FeeTarget::Normal,
+            },
+        )?;
+        chain_backend
+            .send_to_address(&address, amount_sats, fee_rate)
+            .map(|txid| txid.to_string())
+    }
+
+    /// Reserves the UTXOs of every contract that's waiting to be funded. UTXOs
+    /// that can't be reserved, such as ones spent by a funding transaction
+    /// that's already been broadcast, are logged and skipped.
+    fn reserve_contract_utxos(&self, chain_backend: &ChainBackend) -> Result<(), ResolvrError> {
+        let contracts = match self.dlc_storage.get_contracts() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contracts: {}",
+                    e
+                )))
+            }
+        };
+        let mut outpoints = Vec::new();
+        for contract in &contracts {
+            outpoints.extend(get_funding_outpoints(get_reserved_funding_inputs(
+                contract,
+            ))?);
+        }
+        if outpoints.is_empty() {
+            return Ok(());
+        }
+
+        for (outpoint, e) in chain_backend.reserve_utxos(&outpoints)? {
+            println!("Error reserving UTXO {}: {}", outpoint, e);
+        }
+        Ok(())
+    }
+
+    /// Checks everything about an offer that the counterparty would reject it
+    /// for, and works out the announcements and refund locktime to offer it
+    /// with.
+    fn check_offer(
+        &self,
+        contract_input: &ContractInput,
+        bounty_timeout: &BountyTimeout,
+    ) -> Result<CheckedOffer, ResolvrError> {
+        if !(REFUND_DELAY..=REFUND_DELAY * 2).contains(&bounty_timeout.refund_delay_secs) {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Refund delay must be between {} and {} seconds.",
+                REFUND_DELAY,
+                REFUND_DELAY * 2
+            )));
+        }
+
+        if bounty_timeout.deadline as u64 <= now_epoch_secs() {
+            return Err(ResolvrError::InvalidInput(String::from(
+                "Bounty deadline has already passed.",
+            )));
+        }
+
+        self.fee_service.check_fee_rate(contract_input.fee_rate)?;
+        let oracle_announcements = self.get_oracle_announcements(contract_input)?;
+        let maturities = oracle_announcements
+            .iter()
+            .flatten()
+            .map(|announcement| announcement.oracle_event.event_maturity_epoch);
+        let (closest_maturity, latest_maturity) = match (maturities.clone().min(), maturities.max())
+        {
+            (Some(closest_maturity), Some(latest_maturity)) => (closest_maturity, latest_maturity),
+            _ => {
+                return Err(ResolvrError::InvalidInput(String::from(
+                    "Contract doesn't use any oracles.",
+                )))
+            }
+        };
+        if bounty_timeout.deadline > closest_maturity {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Bounty deadline {} is after the escrow agent's event matures at {}.",
+                bounty_timeout.deadline, closest_maturity
+            )));
+        }
+
+        // The counterparty checks the refund locktime against the earliest
+        // event, but `dlc_manager` adds the refund delay to the latest one.
+        let refund_locktime = closest_maturity + bounty_timeout.refund_delay_secs;
+        let refund_delay = match refund_locktime.checked_sub(latest_maturity) {
+            Some(v) => v,
+            None => {
+                return Err(ResolvrError::InvalidInput(String::from(
+                    "Oracle events mature too far apart for the refund delay.",
+                )))
+            }
+        };
+
+        Ok(CheckedOffer {
+            oracle_announcements,
+            refund_locktime,
+            refund_delay,
+        })
+    }
+
+    /// Works out what offering a contract would cost and pay out, without
+    /// sending it or reserving any UTXOs. The taker's side is estimated as if
+    /// they fund the contract with a single input.
+    fn preview_offer(
+        &self,
+        contract_input: &ContractInput,
+        bounty_timeout: &BountyTimeout,
+    ) -> Result<OfferPreview, ResolvrError> {
+        contract_input
+            .validate()
+            .map_err(|e| ResolvrError::from_dlc_manager_error("Invalid contract", e))?;
+        let checked_offer = self.check_offer(contract_input, bounty_timeout)?;
+
+        self.refresh_wallet()?;
+        let fee_rate = contract_input.fee_rate;
+        let utxos = match self.get_chain_backend()?.wallet.get_utxos_for_amount(
+            contract_input.offer_collateral + estimate_party_fees(1, fee_rate).total_sats(),
+            Some(fee_rate),
+            false,
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::from_dlc_manager_error(
+                    "Error selecting UTXOs",
+                    e,
+                ))
+            }
+        };
+
+        let offer_fees = estimate_party_fees(utxos.len() as u64, fee_rate);
+        let accept_fees = estimate_party_fees(1, fee_rate);
+        let funding_tx_vbytes = estimate_funding_tx_vbytes(utxos.len() as u64 + 1);
+        let total_collateral = contract_input.offer_collateral + contract_input.accept_collateral;
+
+        Ok(OfferPreview {
+            fee_rate_sats_per_vbyte: fee_rate,
+            funding_tx_vbytes,
+            funding_tx_fee_sats: funding_tx_vbytes * fee_rate,
+            offer_fees,
+            accept_fees,
+            outcome_payouts: contract_input
+                .contract_infos
+                .iter()
+                .flat_map(|contract_info| {
+                    get_outcome_payouts(&contract_info.contract_descriptor, total_collateral)
+                })
+                .collect(),
+            refund_locktime: checked_offer.refund_locktime,
+            funding_utxos: utxos
+                .into_iter()
+                .map(|utxo| WalletUtxo {
+                    txid: utxo.outpoint.txid.to_string(),
+                    vout: utxo.outpoint.vout,
+                    value_sats: utxo.tx_out.value,
+                    address: Some(utxo.address.to_string()),
+                    confirmations: None,
+                    reserved: false,
+                })
+                .collect(),
+        })
+    }
+
+    fn offer_contract(
+        &mut self,
+        contract_input: &ContractInput,
+        counter_party: PublicKey,
+        bounty_timeout: &BountyTimeout,
+    ) -> Result<(), ResolvrError> {
+        let checked_offer = self.check_offer(contract_input, bounty_timeout)?;
+
+        // This is what `Manager::send_offer` does, except that it always offers
+        // a refund delay of `REFUND_DELAY`.
+        self.refresh_wallet()?;
+        let chain_backend = self.get_chain_backend()?;
+        let (offered_contract, mut offer_dlc) = match contract_updater::offer_contract(
+            &Secp256k1::new(),
+            contract_input,
+            checked_offer.oracle_announcements,
+            checked_offer.refund_delay,
+            &counter_party,
+            &chain_backend.wallet,
+            &chain_backend.blockchain,
+            &Arc::new(SystemTimeProvider {}),
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::from_dlc_manager_error(
+                    "Error creating contract offer",
+                    e,
+                ))
+            }
+        };
+        // Creating the offer reserved the UTXOs that fund it, so from here on
+        // a failure has to release them, and delete the contract if it was
+        // already stored, rather than leave them locked for an offer that was
+        // never sent.
+        if let Err(e) = offered_contract.validate() {
+            self.discard_offer(&offered_contract, false);
+            return Err(ResolvrError::from_dlc_manager_error(
+                "Invalid contract offer",
+                e,
+            ));
+        }
+        if let Err(e) = self.dlc_storage.create_contract(&offered_contract) {
+            self.discard_offer(&offered_contract, false);
+            return Err(ResolvrError::Storage(format!(
+                "Error creating contract: {}",
+                e
+            )));
+        }
+
+        // The deadline isn't part of the offer, so it's only kept in the
+        // detail, which `track_stage_changes` carries over when it picks up the
+        // new contract.
+        let mut contract_detail =
+            JsonContractDetail::new(&Contract::Offered(offered_contract.clone()), None);
+        contract_detail.set_bounty_deadline(bounty_timeout.deadline);
+        if let Err(e) = self
+            .contract_detail_store
+            .save(&offer_dlc.temporary_contract_id, &contract_detail)
+        {
+            self.discard_offer(&offered_contract, true);
+            return Err(e);
+        }
+
+        // `dlc_manager` hardcodes the mainnet chain hash in every offer, so it's
+        // replaced with the hash of the network the contract is really on. The
+        // chain hash isn't part of the stored contract, so nothing else needs to
+        // change. Since incoming offers are checked against the same hash, offers
+        // from peers on an older version, which still carry the mainnet hash, are
+        // rejected unless both sides run on mainnet.
+        offer_dlc.chain_hash = get_chain_hash(self.network);
+
+        // Once the offer is queued it's sent until it goes through, like any
+        // other message in the outbox.
+        if let Err(e) = self.dlc_msg_handler.incoming_msg_queue().add_to_outbox(
+            &DlcMsg::Protocol(dlc_messages::Message::Offer(offer_dlc)),
+            &counter_party,
+        ) {
+            self.discard_offer(&offered_contract, true);
+            return Err(e);
+        }
+        self.send_outbox_msgs();
+
+        Ok(())
+    }
+
+    /// Undoes an offer that failed before it could be queued, by releasing the
+    /// UTXOs reserved to fund it and deleting the stored contract, if any.
+    /// Failures are only logged, so that the error that caused the offer to
+    /// fail is the one reported.
+    fn discard_offer(&self, offered_contract: &OfferedContract, is_stored: bool) {
+        if is_stored {
+            if let Err(e) = self.dlc_storage.delete_contract(&offered_contract.id) {
+                println!(
+                    "Error deleting failed offer {}: {}",
+                    hex::encode(offered_contract.id),
+                    e
+                );
+            }
+            if let Err(e) = self.contract_detail_store.remove(&offered_contract.id) {
+                println!(
+                    "Error deleting detail of failed offer {}: {}",
+                    hex::encode(offered_contract.id),
+                    e
+                );
+            }
+        }
+
+        let res = get_funding_outpoints(&offered_contract.funding_inputs_info)
+            .and_then(|outpoints| self.get_chain_backend()?.release_utxos(&outpoints));
+        if let Err(e) = res {
+            println!(
+                "Error releasing UTXOs of failed offer {}: {}",
+                hex::encode(offered_contract.id),
+                e
+            );
+        }
+    }
+
+    /// Fetches the announcements the contract depends on, one list per
+    /// contract info, and checks that they announce exactly the outcomes the
+    /// contract has payouts for.
+    fn get_oracle_announcements(
+        &self,
+        contract_input: &ContractInput,
+    ) -> Result<Vec<Vec<OracleAnnouncement>>, ResolvrError> {
+        let mut oracle_announcements = Vec::new();
+        for contract_info in &contract_input.contract_infos {
+            let mut contract_info_announcements = Vec::new();
+            for public_key in &contract_info.oracles.public_keys {
+                let oracle = match self.oracles.get(public_key) {
+                    Some(v) => v,
+                    None => {
+                        return Err(ResolvrError::InvalidInput(format!(
+                            "Unknown oracle {}.",
+                            public_key
+                        )))
+                    }
+                };
+                let announcement = match oracle.get_announcement(&contract_info.oracles.event_id) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return Err(ResolvrError::from_dlc_manager_error(
+                            "Error getting oracle announcement",
+                            e,
+                        ))
+                    }
+                };
+
+                check_announced_outcomes(
+                    &contract_info.contract_descriptor,
+                    &announcement.oracle_event.event_descriptor,
+                )?;
+                contract_info_announcements.push(announcement);
+            }
+            oracle_announcements.push(contract_info_announcements);
+        }
+
+        Ok(oracle_announcements)
+    }
+
+    fn accept_contract(&mut self, contract_id: &ContractId) -> Result<Contract, ResolvrError> {
+        // Whoever accepts pays their share of the funding fee at the offered
+        // rate, so offers at absurd rates are refused like our own would be.
+        if let Ok(Some(Contract::Offered(offered_contract))) =
+            self.dlc_storage.get_contract(contract_id)
+        {
+            self.fee_service
+                .check_fee_rate(offered_contract.fee_rate_per_vb)?;
+        }
+
+        self.refresh_wallet()?;
+        let (accepted_contract_id, counter_party, accept_dlc) =
+            match self.get_dlc_manager()?.accept_contract_offer(contract_id) {
+                Ok(res) => res,
+                Err(e) => {
+                    return Err(ResolvrError::from_dlc_manager_error(
+                        "Error accepting contract",
+                        e,
+                    ))
+                }
+            };
+        let accepted_contract = match self.dlc_storage.get_contract(&accepted_contract_id) {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(ResolvrError::NotFound(String::from("Contract not found."))),
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contract: {}",
+                    e
+                )))
+            }
+        };
+
+        // The contract is already stored as accepted, so the Accept is queued
+        // durably and sent until it goes through. If it can't even be queued,
+        // the acceptance is undone, rather than leaving a contract that the
+        // counterparty will never hear about.
+        if let Err(e) = self.dlc_msg_handler.incoming_msg_queue().add_to_outbox(
+            &DlcMsg::Protocol(dlc_messages::Message::Accept(accept_dlc)),
+            &counter_party,
+        ) {
+            self.cancel_negotiation(accepted_contract, None, false)?;
+            return Err(e);
+        }
+        self.send_outbox_msgs();
+
+        Ok(accepted_contract)
+    }
+
+    fn reject_contract(
+        &self,
+        contract_id: &ContractId,
+        reason: Option<String>,
+    ) -> Result<(), ResolvrError> {
+        let reason = reason
+            .map(|reason| reason.trim().to_string())
+            .filter(|reason| !reason.is_empty());
+        if let Some(reason) = &reason {
+            if reason.chars().count() > MAX_REJECTION_REASON_CHARS {
+                return Err(ResolvrError::InvalidInput(format!(
+                    "Reason must be at most {} characters.",
+                    MAX_REJECTION_REASON_CHARS
+                )));
+            }
+        }
+
+        let contract = match self.dlc_storage.get_contract(contract_id) {
+            Ok(Some(Contract::Offered(v))) if !v.is_offer_party => Contract::Offered(v),
+            Ok(_) => {
+                return Err(ResolvrError::NotFound(String::from(
+                    "No offer from a counterparty with that ID.",
+                )))
+            }
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contract: {}",
+                    e
+                )))
+            }
+        };
+
+        // The reject is queued durably before the offer is marked as rejected,
+        // so that it's sent until it goes through, and is taken back out if
+        // the offer can't be rejected after all.
+        let queue = self.dlc_msg_handler.incoming_msg_queue();
+        let outbox_msg_id = queue.add_to_outbox(
+            &DlcMsg::Reject(RejectDlc {
+                temporary_contract_id: *contract_id,
+                reason: reason.clone(),
+            }),
+            &contract.get_counter_party_id(),
+        )?;
+        if let Err(e) = self.cancel_negotiation(contract, reason, false) {
+            queue.remove_from_outbox(outbox_msg_id)?;
+            return Err(e);
+        }
+        self.send_outbox_msgs();
+
+        Ok(())
+    }
+
+    /// Rejects our side of a contract once the counterparty declines or
+    /// withdraws it before it's funded. A reject for a contract that was
+    /// already rejected, such as one that crossed with our own, is ignored.
+    fn on_reject(&self, reject: &RejectDlc, counter_party: PublicKey) -> Result<(), ResolvrError> {
+        let contracts = match self.dlc_storage.get_contracts() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contracts: {}",
+                    e
+                )))
+            }
+        };
+
+        // Accepted and signed contracts are stored under their final ID, so
+        // they have to be found by the temporary ID that both parties know them
+        // by.
+        let mut contract_or = None;
+        for contract in contracts {
+            if get_temporary_contract_id(&contract) != reject.temporary_contract_id
+                || contract.get_counter_party_id() != counter_party
+            {
+                continue;
+            }
+            match &contract {
+                Contract::Rejected(_) => return Ok(()),
+                Contract::Offered(_) | Contract::Accepted(_) => contract_or = Some(contract),
+                // The offerer's contract is signed as soon as it sends its
+                // signatures, but it's only funded once the accepting party
+                // broadcasts the funding transaction, which it won't do after
+                // rejecting the contract.
+                Contract::Signed(c) if c.accepted_contract.offered_contract.is_offer_party => {
+                    contract_or = Some(contract)
+                }
+                _ => {}
+            }
+        }
+        let contract = match contract_or {
+            Some(v) => v,
+            None => {
+                return Err(ResolvrError::NotFound(String::from(
+                    "No unfunded contract with the counterparty with that ID.",
+                )))
+            }
+        };
+
+        if let Contract::Signed(c) = &contract {
+            let funding_txid = c.accepted_contract.dlc_transactions.fund.txid();
+            let confirmations = self
+                .get_chain_backend()?
+                .blockchain
+                .get_transaction_confirmations(&funding_txid)
+                .map_err(|e| {
+                    ResolvrError::from_dlc_manager_error("Error checking funding transaction", e)
+                })?;
+            if confirmations > 0 {
+                return Err(ResolvrError::InvalidInput(String::from(
+                    "Contract was already funded, so it can't be rejected.",
+                )));
+            }
+        }
+
+        self.cancel_negotiation(contract, reject.reason.clone(), false)
+    }
+
+    /// Moves an offered, accepted or unfunded signed contract to Rejected and
+    /// releases the UTXOs we reserved to fund it. The reason, and whether the
+    /// contract expired, are saved with the contract's detail, since DLC
+    /// storage has nowhere to keep them.
+    ///
+    /// A rejected contract goes back to its temporary ID. Its detail is left
+    /// under its current ID for `track_stage_changes`, which this must be run
+    /// in, to move over and report as a change from that ID.
+    fn cancel_negotiation(
+        &self,
+        contract: Contract,
+        reason: Option<String>,
+        expired: bool,
+    ) -> Result<(), ResolvrError> {
+        let outpoints = get_funding_outpoints(get_reserved_funding_inputs(&contract))?;
+        if !outpoints.is_empty() {
+            self.get_chain_backend()?.release_utxos(&outpoints)?;
+        }
+
+        let contract_id = contract.get_id();
+        let mut contract_detail =
+            JsonContractDetail::new(&contract, self.contract_detail_store.get(&contract_id)?);
+        contract_detail.set_rejection_reason(reason);
+        contract_detail.set_expired(expired);
+        self.contract_detail_store
+            .save(&contract_id, &contract_detail)?;
+
+        let rejected_contract = match contract {
+            Contract::Offered(c) => Contract::Rejected(c),
+            Contract::Accepted(c) => Contract::Rejected(c.offered_contract),
+            Contract::Signed(c) => Contract::Rejected(c.accepted_contract.offered_contract),
+            _ => {
+                return Err(ResolvrError::InvalidInput(String::from(
+                    "Only offered, accepted or signed contracts can be rejected.",
+                )))
+            }
+        };
+
+        self.dlc_storage
+            .update_contract(&rejected_contract)
+            .map_err(|e| ResolvrError::Storage(format!("Error updating contract: {}", e)))?;
+
+        // Contracts that were accepted are left over under their final ID.
+        if contract_id != rejected_contract.get_id() {
+            if let Err(e) = self.dlc_storage.delete_contract(&contract_id) {
+                return Err(ResolvrError::Storage(format!(
+                    "Error deleting contract: {}",
+                    e
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rejects contracts that have waited on the counterparty for longer than
+    /// `negotiation_expiry` allows, and lets the counterparty know. A contract
+    /// that fails to expire is logged and retried next time, without holding
+    /// up the rest.
+    fn expire_stale_negotiations(&self, now: u64) {
+        let contracts = match self.dlc_storage.get_contracts() {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error getting contracts to expire: {}", e);
+                return;
+            }
+        };
+
+        for contract in contracts {
+            let contract_id = contract.get_id();
+            if let Err(e) = self.expire_if_stale(contract, now) {
+                println!(
+                    "Error expiring contract {}: {}",
+                    hex::encode(contract_id),
+                    e
+                );
+            }
+        }
+
+        self.send_outbox_msgs();
+    }
+
+    fn expire_if_stale(&self, contract: Contract, now: u64) -> Result<(), ResolvrError> {
+        let (expiry_secs, reason) = match &contract {
+            Contract::Offered(_) => (
+                self.negotiation_expiry.offered_secs,
+                "Offer expired before it was accepted.",
+            ),
+            Contract::Accepted(_) => (
+                self.negotiation_expiry.accepted_secs,
+                "Contract expired before it was signed.",
+            ),
+            _ => return Ok(()),
+        };
+
+        let contract_id = contract.get_id();
+        let contract_detail_or = self.contract_detail_store.get(&contract_id)?;
+        let stage_changed_at = match contract_detail_or
+            .as_ref()
+            .and_then(JsonContractDetail::get_stage_changed_at)
+        {
+            Some(v) => v,
+            None => {
+                // Contracts from before stage changes were timestamped start
+                // their expiry window now.
+                let mut contract_detail = JsonContractDetail::new(&contract, contract_detail_or);
+                contract_detail.set_stage_changed_at(now);
+                return self
+                    .contract_detail_store
+                    .save(&contract_id, &contract_detail);
+            }
+        };
+        if now < stage_changed_at.saturating_add(expiry_secs) {
+            return Ok(());
+        }
+
+        let counter_party = contract.get_counter_party_id();
+        let temporary_contract_id = get_temporary_contract_id(&contract);
+        let reason = Some(String::from(reason));
+        self.cancel_negotiation(contract, reason.clone(), true)?;
+
+        // The notice is sent from the outbox with the rest once every stale
+        // contract is expired. The counterparty expires the contract on their
+        // own clock anyway, so failing to queue it isn't an error.
+        if let Err(e) = self.dlc_msg_handler.incoming_msg_queue().add_to_outbox(
+            &DlcMsg::Reject(RejectDlc {
+                temporary_contract_id,
+                reason,
+            }),
+            &counter_party,
+        ) {
+            println!(
+                "Error telling counterparty that contract {} expired: {}",
+                hex::encode(contract_id),
+                e
+            );
+        }
+
+        Ok(())
+    }
+
+    fn get_contract(&self, contract_id: &ContractId) -> Result<JsonContractDetail, ResolvrError> {
+        let contract = match self.dlc_storage.get_contract(contract_id) {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(ResolvrError::NotFound(String::from("Contract not found."))),
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contract: {}",
+                    e
+                )))
+            }
+        };
+
+        Ok(JsonContractDetail::new(
+            &contract,
+            self.contract_detail_store.get(contract_id)?,
+        ))
+    }
+
+    fn delete_contract(&self, contract_id: &ContractId) -> Result<(), ResolvrError> {
+        if let Err(e) = self.dlc_storage.delete_contract(contract_id) {
+            return Err(ResolvrError::Storage(format!(
+                "Error deleting contract: {}",
+                e
+            )));
+        }
+
+        self.contract_detail_store.remove(contract_id)
+    }
+
+    fn periodic_check(&mut self) -> Result<(), ResolvrError> {
+        self.refresh_wallet()?;
+        self.get_dlc_manager()?
+            .periodic_check()
+            .map_err(|e| ResolvrError::from_dlc_manager_error("Error running periodic check", e))
+    }
+
+    /// Runs `f` and publishes the contract stage changes it caused, which are
+    /// also returned alongside its result. Changes are published even if `f`
+    /// fails, since it may have moved some contracts before failing.
+    fn track_stage_changes<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, ResolvrError>,
+    ) -> Result<(T, Vec<JsonContractStageChange>), ResolvrError> {
+        let old_stages = self.get_contract_stages()?;
+        let res = f(self);
+
+        // Only the first failure is reported, so that the reconnect scheduler
+        // isn't woken again by calls made while it's already retrying.
+        if let Err(ResolvrError::Rpc(_)) = &res {
+            if !self.is_rpc_failing {
+                self.is_rpc_failing = true;
+                self.rpc_failure_notify.notify_one();
+            }
+        }
+
+        let stage_changes = self.get_contract_stage_changes(&old_stages)?;
+
+        for stage_change in &stage_changes {
+            // Sending only fails if nobody is subscribed.
+            let _ = self.stage_change_sender.send(stage_change.clone());
+        }
+
+        res.map(|v| (v, stage_changes))
+    }
+
+    /// Returns the temporary ID and stage of every stored contract, by ID.
+    fn get_contract_stages(
+        &self,
+    ) -> Result<HashMap<ContractId, (ContractId, JsonContractStage)>, ResolvrError> {
+        match self.dlc_storage.get_contracts() {
+            Ok(contracts) => Ok(contracts
+                .iter()
+                .map(|contract| {
+                    (
+                        contract.get_id(),
+                        (get_temporary_contract_id(contract), contract.into()),
+                    )
+                })
+                .collect()),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error getting contracts: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Compares the stored contracts against a snapshot taken with
+    /// `get_contract_stages` and returns the ones whose stage differs.
+    /// Contracts whose ID changed on acceptance, or back to their temporary ID
+    /// on rejection, are matched up by their temporary ID. The detail of every
+    /// changed contract is saved, so that it's still available once the
+    /// contract closes.
+    fn get_contract_stage_changes(
+        &self,
+        old_stages: &HashMap<ContractId, (ContractId, JsonContractStage)>,
+    ) -> Result<Vec<JsonContractStageChange>, ResolvrError> {
+        let contracts = match self.dlc_storage.get_contracts() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contracts: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut stage_changes = Vec::new();
+        for contract in &contracts {
+            let contract_id = contract.get_id();
+            let new_stage = JsonContractStage::from(contract);
+
+            let (previous_contract_id, old_stage) = match old_stages.get(&contract_id) {
+                Some((_, old_stage)) => (None, Some(*old_stage)),
+                None => {
+                    let temporary_contract_id = get_temporary_contract_id(contract);
+                    match old_stages.iter().find(
+                        |(old_contract_id, (old_temporary_contract_id, _))| {
+                            **old_contract_id == temporary_contract_id
+                                || *old_temporary_contract_id == contract_id
+                        },
+                    ) {
+                        Some((old_contract_id, (_, old_stage))) => {
+                            (Some(*old_contract_id), Some(*old_stage))
+                        }
+                        None => (None, None),
+                    }
+                }
+            };
+            if previous_contract_id.is_none() && old_stage == Some(new_stage) {
+                continue;
+            }
+
+            self.save_contract_detail(contract, previous_contract_id)?;
+
+            stage_changes.push(JsonContractStageChange {
+                contract_id: hex::encode(contract_id),
+                previous_contract_id: previous_contract_id.map(hex::encode),
+                old_stage,
+                new_stage,
+            });
+        }
+
+        Ok(stage_changes)
+    }
+
+    /// Saves a detail for every contract that doesn't have one yet. Details are
+    /// otherwise only saved when a contract's stage changes, so contracts from
+    /// before the detail store existed would lose their offer once they close.
+    fn backfill_contract_details(&self) -> Result<(), ResolvrError> {
+        let contracts = match self.dlc_storage.get_contracts() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error getting contracts: {}",
+                    e
+                )))
+            }
+        };
+
+        for contract in &contracts {
+            let contract_id = contract.get_id();
+            if self.contract_detail_store.get(&contract_id)?.is_none() {
+                self.contract_detail_store
+                    .save(&contract_id, &JsonContractDetail::new(contract, None))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_contract_detail(
+        &self,
+        contract: &Contract,
+        previous_contract_id: Option<ContractId>,
+    ) -> Result<(), ResolvrError> {
+        let contract_id = contract.get_id();
+        let previous_detail = self
+            .contract_detail_store
+            .get(&previous_contract_id.unwrap_or(contract_id))?;
+
+        let mut contract_detail = JsonContractDetail::new(contract, previous_detail);
+        contract_detail.set_stage_changed_at(now_epoch_secs());
+        self.contract_detail_store
+            .save(&contract_id, &contract_detail)?;
+
+        match previous_contract_id {
+            Some(previous_contract_id) => self.contract_detail_store.remove(&previous_contract_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Processes all incoming DLC messages that are due. A message that fails
+    /// is retried later, and moved to the dead-letter store if it keeps
+    /// failing, so that one bad message can't block messages behind it.
+    fn process_incoming_msgs(&mut self) -> Result<(), ResolvrError> {
+        if self.dlc_manager_or.is_none() {
+            return Ok(());
+        }
+
+        let now = now_epoch_secs();
+        let queued_msgs = self
+            .dlc_msg_handler
+            .incoming_msg_queue()
+            .get_ready_msgs(now)?;
+        if queued_msgs.is_empty() {
+            // Replies that couldn't be sent before still need to go out.
+            self.send_outbox_msgs();
+            return Ok(());
+        }
+
+        self.track_stage_changes(|actor| actor.process_queued_msgs(queued_msgs, now))
+            .map(|_| ())
+    }
+
+    fn process_queued_msgs(
+        &mut self,
+        queued_msgs: Vec<QueuedDlcMsg>,
+        now: u64,
+    ) -> Result<(), ResolvrError> {
+        let dlc_msg_handler = self.dlc_msg_handler.clone();
+        let queue = dlc_msg_handler.incoming_msg_queue();
+        let network = self.network;
+
+        for queued_msg in queued_msgs {
+            let result = match &queued_msg.msg {
+                DlcMsg::Protocol(msg) => match check_counterparty_msg(msg, network) {
+                    Ok(()) => self
+                        .get_dlc_manager()?
+                        .on_dlc_message(msg, queued_msg.counter_party)
+                        .map(|reply_or| reply_or.map(DlcMsg::Protocol))
+                        .map_err(|e| format!("Error processing message: {}", e)),
+                    Err(e) => Err(format!("Rejected message: {}", e)),
+                },
+                DlcMsg::Reject(reject) => self
+                    .on_reject(reject, queued_msg.counter_party)
+                    .map(|_| None)
+                    .map_err(|e| format!("Error processing reject: {}", e)),
+            };
+            match result {
+                Ok(reply_or) => {
+                    // Remove the message from the queue since it was processed
+                    // successfully. Some messages (such as an accept) need a
+                    // reply to move the contract forward, which goes to the
+                    // outbox in the same step so that it can't be lost.
+                    if let Err(e) = queue.remove(
+                        queued_msg.id,
+                        reply_or
+                            .as_ref()
+                            .map(|reply| (reply, &queued_msg.counter_party)),
+                    ) {
+                        println!(
+                            "Error removing processed DLC message {}: {}",
+                            queued_msg.id, e
+                        );
+                    }
+                }
+                Err(error) => match queue.record_failure(queued_msg.id, &error, now) {
+                    Ok(true) => println!(
+                        "Moved incoming DLC message {} to dead letters. {}",
+                        queued_msg.id, error
+                    ),
+                    Ok(false) => {}
+                    Err(e) => println!(
+                        "Error recording failure of DLC message {}: {}",
+                        queued_msg.id, e
+                    ),
+                },
+            };
+        }
+
+        self.send_outbox_msgs();
+
+        Ok(())
+    }
+
+    /// Publishes a message to the counterparty, waiting until relays have
+    /// taken all of it.
+    fn send_msg(&self, msg: &DlcMsg, counter_party: PublicKey) -> Result<(), ResolvrError> {
+        self.runtime
+            .block_on(self.dlc_msg_handler.send_msg(msg, counter_party))
+    }
+
+    /// Sends the messages in the outbox, in order. A message is only removed
+    /// once relays have confirmed every chunk of it, so any that can't be sent
+    /// yet stay for the next time messages are processed.
+    fn send_outbox_msgs(&self) {
+        let queue = self.dlc_msg_handler.incoming_msg_queue();
+        let outbox_msgs = match queue.get_outbox_msgs() {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error reading DLC message outbox: {}", e);
+                return;
+            }
+        };
+
+        for outbox_msg in outbox_msgs {
+            if let Err(e) = self.send_msg(&outbox_msg.msg, outbox_msg.counter_party) {
+                println!("Error sending DLC message {}: {}", outbox_msg.id, e);
+                // Later replies mustn't overtake this one.
+                return;
+            }
+            if let Err(e) = queue.remove_from_outbox(outbox_msg.id) {
+                println!(
+                    "Error removing sent DLC message {} from outbox: {}",
+                    outbox_msg.id, e
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Checks that an enum event announces exactly the outcomes that an enum
+/// contract has payouts for. Other kinds of contracts and events aren't
+/// checked here.
+fn check_announced_outcomes(
+    contract_descriptor: &ContractDescriptor,
+    event_descriptor: &EventDescriptor,
+) -> Result<(), ResolvrError> {
+    if let (ContractDescriptor::Enum(enum_descriptor), EventDescriptor::EnumEvent(enum_event)) =
+        (contract_descriptor, event_descriptor)
+    {
+        let payout_outcomes: HashSet<&String> = enum_descriptor
+            .outcome_payouts
+            .iter()
+            .map(|outcome_payout| &outcome_payout.outcome)
+            .collect();
+        let announced_outcomes: HashSet<&String> = enum_event.outcomes.iter().collect();
+        if payout_outcomes != announced_outcomes
+            || enum_descriptor.outcome_payouts.len() != enum_event.outcomes.len()
+        {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Announced outcomes ({}) don't match the contract's payouts.",
+                enum_event.outcomes.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn print_stage_changes(stage_changes: &[JsonContractStageChange]) {
+    for stage_change in stage_changes {
+        println!(
+            "Contract {} moved from {:?} to {:?}.",
+            stage_change.contract_id, stage_change.old_stage, stage_change.new_stage
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dlc::{EnumerationPayout, Payout};
+    use dlc_manager::contract::enum_descriptor::EnumDescriptor;
+    use dlc_messages::oracle_msgs::EnumEventDescriptor;
+
+    fn get_contract_descriptor(outcomes: &[&str]) -> ContractDescriptor {
+        ContractDescriptor::Enum(EnumDescriptor {
+            outcome_payouts: outcomes
+                .iter()
+                .map(|outcome| EnumerationPayout {
+                    outcome: outcome.to_string(),
+                    payout: Payout {
+                        offer: 1,
+                        accept: 1,
+                    },
+                })
+                .collect(),
+        })
+    }
+
+    fn get_event_descriptor(outcomes: &[&str]) -> EventDescriptor {
+        EventDescriptor::EnumEvent(EnumEventDescriptor {
+            outcomes: outcomes.iter().map(|outcome| outcome.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn announced_outcomes_may_be_in_any_order() {
+        assert!(check_announced_outcomes(
+            &get_contract_descriptor(&["a", "b", "c"]),
+            &get_event_descriptor(&["c", "a", "b"]),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn announced_outcomes_must_match_payouts() {
+        for (payout_outcomes, announced_outcomes) in [
+            // An outcome that the contract doesn't pay out for.
+            (vec!["a", "b"], vec!["a", "b", "c"]),
+            // An outcome that was never announced.
+            (vec!["a", "b", "c"], vec!["a", "b"]),
+            (vec!["a", "b"], vec!["a", "c"]),
+            // The same outcomes, but one of them announced twice.
+            (vec!["a", "b"], vec!["a", "b", "b"]),
+            (vec!["a", "b", "b"], vec!["a", "b"]),
+        ] {
+            let res = check_announced_outcomes(
+                &get_contract_descriptor(&payout_outcomes),
+                &get_event_descriptor(&announced_outcomes),
+            );
+            assert!(matches!(res, Err(ResolvrError::InvalidInput(_))));
+        }
+    }
+}
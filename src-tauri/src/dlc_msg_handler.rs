@@ -0,0 +1,336 @@
+use crate::dlc_msg_queue::DlcMsgQueue;
+use bitcoin::secp256k1::PublicKey;
+use escrow_agent_messages::dlc_msg_codec::{
+    encode_dlc_msg, is_dlc_msg_chunk, DlcMsg, DlcMsgReassembler,
+};
+use escrow_agent_messages::error::ResolvrError;
+use nostr_sdk::nips::nip04;
+use nostr_sdk::{Client, EventId, Filter, Keys, Kind, Options, RelayPoolNotification, Timestamp};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// How far before the newest received message to re-read from relays when
+/// connecting. Covers events that relays received late, and chunks of messages
+/// that were only partially received before the app was closed.
+const RECEIVE_CURSOR_SLACK_SECS: u64 = 60 * 60;
+
+/// Transports DLC messages between counterparties as NIP-04 encrypted Nostr
+/// DMs.
+///
+/// Counterparties are identified by their Nostr public key. Since Nostr keys
+/// are x-only, the corresponding `PublicKey` used by `dlc_manager` is always
+/// the even-parity lift of the Nostr key.
+///
+/// Incoming messages are kept in a durable queue (see `incoming_msg_queue`).
+pub struct NostrNip4DlcMessageHandler {
+    client: Mutex<Option<Client>>,
+    incoming_msgs: DlcMsgQueue,
+}
+
+impl NostrNip4DlcMessageHandler {
+    pub fn new(incoming_msgs: DlcMsgQueue) -> Self {
+        Self {
+            client: Mutex::new(None),
+            incoming_msgs,
+        }
+    }
+
+    /// Connects to the given relays using the given Nostr keys, and starts
+    /// listening for incoming DLC messages addressed to those keys. Any
+    /// previous connection is replaced.
+    pub async fn connect(
+        self: &Arc<Self>,
+        keys: Keys,
+        relays: Vec<String>,
+    ) -> Result<(), ResolvrError> {
+        // Publishing waits for relays to take each event, so that `send_msg`
+        // only succeeds once a message has really gone out.
+        let client = Client::with_opts(&keys, Options::new().wait_for_send(true));
+        for relay in relays {
+            if let Err(e) = client.add_relay(relay.as_str(), None).await {
+                return Err(ResolvrError::Transport(format!(
+                    "Error adding relay {}: {}",
+                    relay, e
+                )));
+            }
+        }
+        client.connect().await;
+
+        // Pick up where we left off, so that messages sent while the app was
+        // closed are received.
+        let now = Timestamp::now().as_u64();
+        let since = match self.incoming_msgs.get_receive_cursor()? {
+            Some(cursor) => cursor.saturating_sub(RECEIVE_CURSOR_SLACK_SECS),
+            None => now,
+        };
+        self.incoming_msgs.prune_received_msg_ids(now)?;
+
+        let filter = Filter::new()
+            .kind(Kind::EncryptedDirectMessage)
+            .pubkey(keys.public_key())
+            .since(Timestamp::from(since));
+        client.subscribe(vec![filter]).await;
+
+        let previous_client = self.client.lock().unwrap().replace(client.clone());
+        if let Some(previous_client) = previous_client {
+            if let Err(e) = previous_client.shutdown().await {
+                println!("Error shutting down previous Nostr client: {}", e);
+            }
+        }
+
+        tokio::task::spawn(self.clone().run_incoming_loop(client, keys));
+
+        Ok(())
+    }
+
+    /// Sends a message to the given counterparty to progress the state of a DLC
+    /// contract. Only returns once every chunk of the message has been
+    /// published, so a message that fails part way through can be sent again
+    /// in full.
+    pub async fn send_msg(
+        &self,
+        msg: &DlcMsg,
+        counter_party: PublicKey,
+    ) -> Result<(), ResolvrError> {
+        let chunks = encode_dlc_msg(msg).map_err(ResolvrError::InvalidInput)?;
+        let receiver = to_nostr_public_key(&counter_party)?;
+
+        let client = match self.client.lock().unwrap().clone() {
+            Some(v) => v,
+            None => {
+                return Err(ResolvrError::Transport(String::from(
+                    "Not connected to any Nostr relays.",
+                )))
+            }
+        };
+        for chunk in chunks {
+            if let Err(e) = client.send_direct_msg(receiver, chunk, None).await {
+                return Err(ResolvrError::Transport(format!(
+                    "Error sending DLC message: {}",
+                    e
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the queue of received messages. Messages should only be removed
+    /// from it once they've been successfully processed.
+    pub fn incoming_msg_queue(&self) -> &DlcMsgQueue {
+        &self.incoming_msgs
+    }
+
+    async fn run_incoming_loop(self: Arc<Self>, client: Client, keys: Keys) {
+        let secret_key = match keys.secret_key() {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Error getting Nostr secret key: {}", e);
+                return;
+            }
+        };
+
+        // Relays may deliver the same event more than once.
+        let mut seen_event_ids: HashSet<EventId> = HashSet::new();
+        let mut reassembler = DlcMsgReassembler::new();
+
+        let mut notifications = client.notifications();
+        while let Ok(notification) = notifications.recv().await {
+            let event = match notification {
+                RelayPoolNotification::Event(_, event) => event,
+                RelayPoolNotification::Shutdown => break,
+                _ => continue,
+            };
+
+            if event.kind != Kind::EncryptedDirectMessage || !seen_event_ids.insert(event.id) {
+                continue;
+            }
+
+            let content = match nip04::decrypt(&secret_key, &event.pubkey, &event.content) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Error decrypting DM {}: {}", event.id, e);
+                    continue;
+                }
+            };
+
+            // Not every DM we receive is a DLC message.
+            if !is_dlc_msg_chunk(&content) {
+                continue;
+            }
+
+            let msg = match reassembler.add_chunk(event.pubkey, &content) {
+                Ok(Some(v)) => v,
+                // The message still has chunks that haven't arrived yet.
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("Error decoding DLC message: {}", e);
+                    continue;
+                }
+            };
+
+            let counter_party = match from_nostr_public_key(&event.pubkey) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Error converting sender public key: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self
+                .incoming_msgs
+                .push(&msg, &counter_party, event.created_at.as_u64())
+            {
+                println!("Error queueing DLC message: {}", e);
+            }
+        }
+    }
+}
+
+/// Converts a DLC counterparty public key into the x-only Nostr public key
+/// that its messages are addressed to.
+pub fn to_nostr_public_key(
+    public_key: &PublicKey,
+) -> Result<nostr_sdk::secp256k1::XOnlyPublicKey, ResolvrError> {
+    nostr_sdk::secp256k1::XOnlyPublicKey::from_slice(&public_key.serialize()[1..])
+        .map_err(|e| ResolvrError::InvalidInput(format!("Error converting public key: {}", e)))
+}
+
+/// Converts a Nostr public key into the DLC counterparty public key, which is
+/// always the even-parity lift of the x-only key.
+pub fn from_nostr_public_key(
+    public_key: &nostr_sdk::secp256k1::XOnlyPublicKey,
+) -> Result<PublicKey, ResolvrError> {
+    let mut bytes = [0x02; 33];
+    bytes[1..].copy_from_slice(&public_key.serialize());
+    PublicKey::from_slice(&bytes)
+        .map_err(|e| ResolvrError::InvalidInput(format!("Error converting public key: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_relay::TestRelay;
+    use dlc_manager::contract::Contract;
+    use dlc_manager::Storage;
+    use dlc_messages::Message;
+    use escrow_agent_messages::test_utils::create_mock_dlc_managers;
+    use std::time::Duration;
+
+    /// How long to wait for a message to arrive through the relay.
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Creates a handler with a fresh queue and keys, connected to the given
+    /// relay.
+    async fn connect_test_handler(
+        name: &str,
+        relay_url: String,
+    ) -> (Arc<NostrNip4DlcMessageHandler>, PublicKey) {
+        let queue_path = std::env::temp_dir().join(format!(
+            "resolvr_dlc_msg_handler_test_{}_{}_{}",
+            name,
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let handler = Arc::new(NostrNip4DlcMessageHandler::new(
+            DlcMsgQueue::new(queue_path.to_str().unwrap()).expect("Error opening message queue."),
+        ));
+
+        let keys = Keys::generate();
+        let public_key = from_nostr_public_key(&keys.public_key()).unwrap();
+        handler.connect(keys, vec![relay_url]).await.unwrap();
+        (handler, public_key)
+    }
+
+    /// Waits for the next message in the handler's queue, checks that it's
+    /// from the expected sender and removes it.
+    async fn receive_msg(
+        handler: &NostrNip4DlcMessageHandler,
+        expected_counter_party: &PublicKey,
+    ) -> Message {
+        let queue = handler.incoming_msg_queue();
+        let queued_msg = tokio::time::timeout(RECEIVE_TIMEOUT, async {
+            loop {
+                if let Some(queued_msg) = queue
+                    .get_ready_msgs(crate::now_epoch_secs())
+                    .unwrap()
+                    .into_iter()
+                    .next()
+                {
+                    return queued_msg;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("Timed out waiting for DLC message.");
+
+        assert_eq!(&queued_msg.counter_party, expected_counter_party);
+        queue.remove(queued_msg.id, None).unwrap();
+        match queued_msg.msg {
+            DlcMsg::Protocol(msg) => msg,
+            DlcMsg::Reject(_) => panic!("Expected a protocol message."),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn offer_accept_sign_over_nip04() {
+        let relay = TestRelay::start().await;
+        let (alice, alice_public_key) = connect_test_handler("alice", relay.url()).await;
+        let (bob, bob_public_key) = connect_test_handler("bob", relay.url()).await;
+        // Give both clients time to subscribe before anything is sent.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (mut alice_manager, mut bob_manager, contract_input) = create_mock_dlc_managers();
+
+        let offer_dlc = alice_manager
+            .send_offer(&contract_input, bob_public_key)
+            .unwrap();
+        alice
+            .send_msg(&DlcMsg::Protocol(Message::Offer(offer_dlc)), bob_public_key)
+            .await
+            .unwrap();
+        let offer_msg = receive_msg(&bob, &alice_public_key).await;
+        let temporary_contract_id = match &offer_msg {
+            Message::Offer(offer_dlc) => offer_dlc.temporary_contract_id,
+            _ => panic!("Expected an offer."),
+        };
+        assert!(bob_manager
+            .on_dlc_message(&offer_msg, alice_public_key)
+            .unwrap()
+            .is_none());
+
+        let (contract_id, _, accept_dlc) = bob_manager
+            .accept_contract_offer(&temporary_contract_id)
+            .unwrap();
+        bob.send_msg(
+            &DlcMsg::Protocol(Message::Accept(accept_dlc)),
+            alice_public_key,
+        )
+        .await
+        .unwrap();
+        let accept_msg = receive_msg(&alice, &bob_public_key).await;
+        let sign_msg = alice_manager
+            .on_dlc_message(&accept_msg, bob_public_key)
+            .unwrap()
+            .expect("Expected a sign message.");
+
+        alice
+            .send_msg(&DlcMsg::Protocol(sign_msg), bob_public_key)
+            .await
+            .unwrap();
+        let sign_msg = receive_msg(&bob, &alice_public_key).await;
+        bob_manager
+            .on_dlc_message(&sign_msg, alice_public_key)
+            .unwrap();
+
+        for manager_store in [alice_manager.get_store(), bob_manager.get_store()] {
+            match manager_store.get_contract(&contract_id) {
+                Ok(Some(Contract::Signed(_))) => {}
+                _ => panic!("Expected a signed contract."),
+            }
+        }
+    }
+}
@@ -0,0 +1,751 @@
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::PublicKey;
+use dlc_messages::Message;
+use escrow_agent_messages::dlc_msg_codec::{deserialize_dlc_msg, serialize_dlc_msg, DlcMsg};
+use escrow_agent_messages::error::ResolvrError;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+const QUEUE_TREE: &[u8] = b"incoming_msgs";
+const RECEIVED_MSG_IDS_TREE: &[u8] = b"received_msg_ids";
+const FAILURES_TREE: &[u8] = b"failures";
+const DEAD_LETTERS_TREE: &[u8] = b"dead_letters";
+const OUTBOX_TREE: &[u8] = b"outbox";
+const META_TREE: &[u8] = b"meta";
+const RECEIVE_CURSOR_KEY: &[u8] = b"receive_cursor";
+
+/// How long to remember which messages were already received. Must be longer
+/// than the window that `NostrNip4DlcMessageHandler` re-reads from relays on
+/// startup.
+const RECEIVED_MSG_ID_RETENTION_SECS: u64 = 60 * 60 * 24 * 7;
+
+/// How many times processing a message may fail before it is moved to the
+/// dead-letter store.
+const MAX_PROCESSING_ATTEMPTS: u32 = 6;
+
+/// Delay before the first retry of a failed message. Doubles with every
+/// further failure.
+const RETRY_BASE_DELAY_SECS: u64 = 2;
+
+/// An incoming message waiting to be processed, or a message in the outbox
+/// waiting to be sent.
+pub struct QueuedDlcMsg {
+    pub id: u64,
+    pub msg: DlcMsg,
+    pub counter_party: PublicKey,
+}
+
+/// A message that failed processing too many times, kept so that the user can
+/// inspect it and decide whether to retry or discard it.
+#[derive(Serialize, Deserialize)]
+pub struct DeadLetterDlcMsg {
+    pub id: u64,
+
+    pub counter_party_id: String,
+
+    /// The kind of DLC message, e.g. `Offer` or `Accept`.
+    pub msg_type: String,
+
+    pub attempts: u32,
+
+    pub last_error: String,
+
+    /// Unix timestamp (in seconds) of when the message was dead-lettered.
+    pub failed_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredDeadLetter {
+    dead_letter: DeadLetterDlcMsg,
+
+    /// The message, encoded the same way as in the queue.
+    msg_bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProcessingFailure {
+    attempts: u32,
+    last_error: String,
+    next_attempt_at: u64,
+}
+
+/// A durable FIFO queue of incoming DLC messages, so that messages survive app
+/// restarts until they've been processed by the DLC manager.
+///
+/// The queue also remembers which messages it has already received, so that
+/// messages re-delivered by relays aren't processed twice, and tracks the
+/// creation time of the newest event that a message was received from.
+///
+/// Messages that fail processing are retried with exponential backoff without
+/// holding up the rest of the queue, and are moved to a dead-letter store once
+/// they run out of attempts.
+///
+/// Replies to processed messages are kept in an outbox, which they're added to
+/// in the same transaction that removes the message they reply to. That way a
+/// reply is never lost if it can't be sent right away, since the message it
+/// answers can't be processed a second time. Messages we start, such as an
+/// Accept, go through the same outbox, so that they're sent until relays take
+/// them.
+pub struct DlcMsgQueue {
+    db: sled::Db,
+    queue: sled::Tree,
+    received_msg_ids: sled::Tree,
+    failures: sled::Tree,
+    dead_letters: sled::Tree,
+    outbox: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl DlcMsgQueue {
+    pub fn new(path: &str) -> Result<Self, ResolvrError> {
+        let db = sled::open(path)
+            .map_err(|e| ResolvrError::Storage(format!("Error opening message queue: {}", e)))?;
+        let open_tree = |name: &[u8]| {
+            db.open_tree(name)
+                .map_err(|e| ResolvrError::Storage(format!("Error opening message queue: {}", e)))
+        };
+
+        Ok(Self {
+            queue: open_tree(QUEUE_TREE)?,
+            received_msg_ids: open_tree(RECEIVED_MSG_IDS_TREE)?,
+            failures: open_tree(FAILURES_TREE)?,
+            dead_letters: open_tree(DEAD_LETTERS_TREE)?,
+            outbox: open_tree(OUTBOX_TREE)?,
+            meta: open_tree(META_TREE)?,
+            db,
+        })
+    }
+
+    /// Adds a message to the back of the queue, unless the same message was
+    /// already received from the same counterparty. `received_at` is the
+    /// creation time of the event the message arrived in. Returns whether the
+    /// message was added.
+    pub fn push(
+        &self,
+        msg: &DlcMsg,
+        counter_party: &PublicKey,
+        received_at: u64,
+    ) -> Result<bool, ResolvrError> {
+        let value = encode_queue_value(msg, counter_party)?;
+        let msg_id = sha256::Hash::hash(&value).into_inner();
+
+        // Sled IDs are monotonic, so big-endian keys keep the queue in order.
+        let key = self
+            .db
+            .generate_id()
+            .map_err(|e| ResolvrError::Storage(format!("Error generating message ID: {}", e)))?
+            .to_be_bytes();
+
+        let result: Result<bool, TransactionError<()>> =
+            (&self.queue, &self.received_msg_ids, &self.meta).transaction(
+                |(queue, received_msg_ids, meta)| {
+                    if received_msg_ids.get(msg_id)?.is_some() {
+                        return Ok(false);
+                    }
+
+                    queue.insert(&key[..], value.as_slice())?;
+                    received_msg_ids.insert(&msg_id[..], &received_at.to_be_bytes()[..])?;
+
+                    let cursor = meta.get(RECEIVE_CURSOR_KEY)?.map(|v| decode_u64(&v));
+                    if cursor.map_or(true, |cursor| cursor < received_at) {
+                        meta.insert(RECEIVE_CURSOR_KEY, &received_at.to_be_bytes()[..])?;
+                    }
+
+                    Ok::<_, ConflictableTransactionError<()>>(true)
+                },
+            );
+
+        let added = result.map_err(|e| {
+            ResolvrError::Storage(format!("Error saving incoming message: {:?}", e))
+        })?;
+        self.flush()?;
+        Ok(added)
+    }
+
+    /// Returns all queued messages that are due to be processed, in the order
+    /// they were received. Messages that recently failed are skipped until
+    /// their backoff has elapsed. Messages that can't be decoded are moved to
+    /// the dead-letter store, rather than holding up the rest of the queue.
+    pub fn get_ready_msgs(&self, now: u64) -> Result<Vec<QueuedDlcMsg>, ResolvrError> {
+        let mut msgs = Vec::new();
+        for entry in self.queue.iter() {
+            let (key, value) = entry.map_err(|e| {
+                ResolvrError::Storage(format!("Error reading incoming message: {}", e))
+            })?;
+            if let Some(failure) = self.get_failure(&key)? {
+                if failure.next_attempt_at > now {
+                    continue;
+                }
+            }
+
+            let id = decode_u64(&key);
+            match decode_queue_value(&value) {
+                Ok((msg, counter_party)) => msgs.push(QueuedDlcMsg {
+                    id,
+                    msg,
+                    counter_party,
+                }),
+                Err(e) => {
+                    let error = format!("Error decoding incoming message: {}", e);
+                    self.dead_letter(id, &value, None, &error, now)?;
+                    println!(
+                        "Moved incoming DLC message {} to dead letters. {}",
+                        id, error
+                    );
+                }
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// Removes a message from the queue once it has been processed, and adds
+    /// the reply to it, if any, to the outbox.
+    pub fn remove(
+        &self,
+        id: u64,
+        reply_or: Option<(&DlcMsg, &PublicKey)>,
+    ) -> Result<(), ResolvrError> {
+        let key = id.to_be_bytes();
+        let reply_or = match reply_or {
+            Some((reply, counter_party)) => {
+                let reply_key = self
+                    .db
+                    .generate_id()
+                    .map_err(|e| {
+                        ResolvrError::Storage(format!("Error generating message ID: {}", e))
+                    })?
+                    .to_be_bytes();
+                Some((reply_key, encode_queue_value(reply, counter_party)?))
+            }
+            None => None,
+        };
+
+        let result: Result<(), TransactionError<()>> = (&self.queue, &self.failures, &self.outbox)
+            .transaction(|(queue, failures, outbox)| {
+                queue.remove(&key[..])?;
+                failures.remove(&key[..])?;
+                if let Some((reply_key, reply_value)) = &reply_or {
+                    outbox.insert(&reply_key[..], reply_value.as_slice())?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            });
+        result.map_err(|e| {
+            ResolvrError::Storage(format!("Error removing incoming message: {:?}", e))
+        })?;
+        self.flush()
+    }
+
+    /// Adds a message we started, rather than a reply, to the outbox. It's
+    /// sent in order with the replies. Returns its ID in the outbox.
+    pub fn add_to_outbox(
+        &self,
+        msg: &DlcMsg,
+        counter_party: &PublicKey,
+    ) -> Result<u64, ResolvrError> {
+        let id = self
+            .db
+            .generate_id()
+            .map_err(|e| ResolvrError::Storage(format!("Error generating message ID: {}", e)))?;
+        self.outbox
+            .insert(
+                &id.to_be_bytes()[..],
+                encode_queue_value(msg, counter_party)?,
+            )
+            .map_err(|e| ResolvrError::Storage(format!("Error adding to outbox: {}", e)))?;
+        self.flush()?;
+        Ok(id)
+    }
+
+    /// Returns the messages waiting to be sent, in the order they were added.
+    pub fn get_outbox_msgs(&self) -> Result<Vec<QueuedDlcMsg>, ResolvrError> {
+        let mut msgs = Vec::new();
+        for entry in self.outbox.iter() {
+            let (key, value) =
+                entry.map_err(|e| ResolvrError::Storage(format!("Error reading outbox: {}", e)))?;
+            let (msg, counter_party) = decode_queue_value(&value)?;
+            msgs.push(QueuedDlcMsg {
+                id: decode_u64(&key),
+                msg,
+                counter_party,
+            });
+        }
+        Ok(msgs)
+    }
+
+    /// Removes a message from the outbox once it has been sent.
+    pub fn remove_from_outbox(&self, id: u64) -> Result<(), ResolvrError> {
+        self.outbox
+            .remove(&id.to_be_bytes()[..])
+            .map_err(|e| ResolvrError::Storage(format!("Error removing from outbox: {}", e)))?;
+        self.flush()
+    }
+
+    /// Records that processing a message failed. Schedules a retry, or moves
+    /// the message to the dead-letter store if it has run out of attempts.
+    /// Returns whether the message was dead-lettered.
+    pub fn record_failure(&self, id: u64, error: &str, now: u64) -> Result<bool, ResolvrError> {
+        let key = id.to_be_bytes();
+        let attempts = self.get_failure(&key)?.map_or(0, |f| f.attempts) + 1;
+
+        if attempts < MAX_PROCESSING_ATTEMPTS {
+            let failure = ProcessingFailure {
+                attempts,
+                last_error: error.to_string(),
+                next_attempt_at: now + (RETRY_BASE_DELAY_SECS << (attempts - 1)),
+            };
+            let value = serde_json::to_vec(&failure).map_err(|e| {
+                ResolvrError::Storage(format!("Error encoding processing failure: {}", e))
+            })?;
+            self.failures.insert(&key[..], value).map_err(|e| {
+                ResolvrError::Storage(format!("Error saving processing failure: {}", e))
+            })?;
+            self.flush()?;
+            return Ok(false);
+        }
+
+        let value = match self.queue.get(&key[..]) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return Err(ResolvrError::NotFound(format!(
+                    "Incoming message {} not found.",
+                    id
+                )))
+            }
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error reading incoming message: {}",
+                    e
+                )))
+            }
+        };
+        self.dead_letter(id, &value, Some(attempts), error, now)?;
+        Ok(true)
+    }
+
+    pub fn get_dead_letters(&self) -> Result<Vec<DeadLetterDlcMsg>, ResolvrError> {
+        self.dead_letters
+            .iter()
+            .values()
+            .map(|v| match v {
+                Ok(v) => serde_json::from_slice::<StoredDeadLetter>(&v)
+                    .map(|stored| stored.dead_letter)
+                    .map_err(|e| {
+                        ResolvrError::Storage(format!("Error decoding dead letter: {}", e))
+                    }),
+                Err(e) => Err(ResolvrError::Storage(format!(
+                    "Error reading dead letter: {}",
+                    e
+                ))),
+            })
+            .collect()
+    }
+
+    /// Moves a dead-lettered message back into the queue with a fresh retry
+    /// budget. It keeps its original position in the queue.
+    pub fn retry_dead_letter(&self, id: u64) -> Result<(), ResolvrError> {
+        let key = id.to_be_bytes();
+        let dead_letter = self.get_dead_letter(&key)?;
+
+        let result: Result<(), TransactionError<()>> = (&self.queue, &self.dead_letters)
+            .transaction(|(queue, dead_letters)| {
+                queue.insert(&key[..], dead_letter.msg_bytes.as_slice())?;
+                dead_letters.remove(&key[..])?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            });
+        result
+            .map_err(|e| ResolvrError::Storage(format!("Error retrying dead letter: {:?}", e)))?;
+        self.flush()
+    }
+
+    /// Permanently deletes a dead-lettered message.
+    pub fn discard_dead_letter(&self, id: u64) -> Result<(), ResolvrError> {
+        let key = id.to_be_bytes();
+        self.get_dead_letter(&key)?;
+        self.dead_letters
+            .remove(&key[..])
+            .map_err(|e| ResolvrError::Storage(format!("Error discarding dead letter: {}", e)))?;
+        self.flush()
+    }
+
+    /// Returns the creation time of the newest event that a message was
+    /// received from, if any.
+    pub fn get_receive_cursor(&self) -> Result<Option<u64>, ResolvrError> {
+        match self.meta.get(RECEIVE_CURSOR_KEY) {
+            Ok(v) => Ok(v.map(|v| decode_u64(&v))),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error reading receive cursor: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Forgets about received messages that are too old to be re-delivered.
+    pub fn prune_received_msg_ids(&self, now: u64) -> Result<(), ResolvrError> {
+        let cutoff = now.saturating_sub(RECEIVED_MSG_ID_RETENTION_SECS);
+        for entry in self.received_msg_ids.iter() {
+            let (key, value) = entry.map_err(|e| {
+                ResolvrError::Storage(format!("Error reading received messages: {}", e))
+            })?;
+            if decode_u64(&value) < cutoff {
+                self.received_msg_ids.remove(key).map_err(|e| {
+                    ResolvrError::Storage(format!("Error pruning received messages: {}", e))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a message from the queue to the dead-letter store. Whatever can't
+    /// be decoded from the stored message is recorded as unknown.
+    fn dead_letter(
+        &self,
+        id: u64,
+        value: &[u8],
+        attempts_or: Option<u32>,
+        error: &str,
+        now: u64,
+    ) -> Result<(), ResolvrError> {
+        let key = id.to_be_bytes();
+        let (counter_party_id, msg_type) = match decode_queue_value(value) {
+            Ok((msg, counter_party)) => (counter_party.to_string(), get_msg_type_name(&msg)),
+            Err(_) => (
+                value
+                    .get(..33)
+                    .and_then(|bytes| PublicKey::from_slice(bytes).ok())
+                    .map_or(String::from("Unknown"), |counter_party| {
+                        counter_party.to_string()
+                    }),
+                String::from("Unknown"),
+            ),
+        };
+        let dead_letter = StoredDeadLetter {
+            dead_letter: DeadLetterDlcMsg {
+                id,
+                counter_party_id,
+                msg_type,
+                attempts: attempts_or.unwrap_or(0),
+                last_error: error.to_string(),
+                failed_at: now,
+            },
+            msg_bytes: value.to_vec(),
+        };
+        let dead_letter_value = serde_json::to_vec(&dead_letter)
+            .map_err(|e| ResolvrError::Storage(format!("Error encoding dead letter: {}", e)))?;
+
+        let result: Result<(), TransactionError<()>> =
+            (&self.queue, &self.failures, &self.dead_letters).transaction(
+                |(queue, failures, dead_letters)| {
+                    queue.remove(&key[..])?;
+                    failures.remove(&key[..])?;
+                    dead_letters.insert(&key[..], dead_letter_value.as_slice())?;
+                    Ok::<_, ConflictableTransactionError<()>>(())
+                },
+            );
+        result.map_err(|e| {
+            ResolvrError::Storage(format!("Error dead-lettering incoming message: {:?}", e))
+        })?;
+        self.flush()
+    }
+
+    fn get_failure(&self, key: &[u8]) -> Result<Option<ProcessingFailure>, ResolvrError> {
+        match self.failures.get(key) {
+            Ok(Some(v)) => serde_json::from_slice(&v).map(Some).map_err(|e| {
+                ResolvrError::Storage(format!("Error decoding processing failure: {}", e))
+            }),
+            Ok(None) => Ok(None),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error reading processing failure: {}",
+                e
+            ))),
+        }
+    }
+
+    fn get_dead_letter(&self, key: &[u8]) -> Result<StoredDeadLetter, ResolvrError> {
+        match self.dead_letters.get(key) {
+            Ok(Some(v)) => serde_json::from_slice(&v)
+                .map_err(|e| ResolvrError::Storage(format!("Error decoding dead letter: {}", e))),
+            Ok(None) => Err(ResolvrError::NotFound(String::from(
+                "Dead letter not found.",
+            ))),
+            Err(e) => Err(ResolvrError::Storage(format!(
+                "Error reading dead letter: {}",
+                e
+            ))),
+        }
+    }
+
+    fn flush(&self) -> Result<(), ResolvrError> {
+        self.db
+            .flush()
+            .map(|_| ())
+            .map_err(|e| ResolvrError::Storage(format!("Error flushing message queue: {}", e)))
+    }
+}
+
+fn encode_queue_value(msg: &DlcMsg, counter_party: &PublicKey) -> Result<Vec<u8>, ResolvrError> {
+    let mut value = counter_party.serialize().to_vec();
+    value.extend(serialize_dlc_msg(msg).map_err(ResolvrError::InvalidInput)?);
+    Ok(value)
+}
+
+fn decode_queue_value(value: &[u8]) -> Result<(DlcMsg, PublicKey), ResolvrError> {
+    if value.len() < 33 {
+        return Err(ResolvrError::Storage(String::from(
+            "Stored incoming message is truncated.",
+        )));
+    }
+    let (counter_party, msg) = value.split_at(33);
+    let counter_party = PublicKey::from_slice(counter_party)
+        .map_err(|e| ResolvrError::Storage(format!("Error decoding counterparty: {}", e)))?;
+    Ok((
+        deserialize_dlc_msg(msg).map_err(ResolvrError::Storage)?,
+        counter_party,
+    ))
+}
+
+fn get_msg_type_name(msg: &DlcMsg) -> String {
+    match msg {
+        DlcMsg::Protocol(Message::Offer(_)) => String::from("Offer"),
+        DlcMsg::Protocol(Message::Accept(_)) => String::from("Accept"),
+        DlcMsg::Protocol(Message::Sign(_)) => String::from("Sign"),
+        DlcMsg::Protocol(_) => String::from("Other"),
+        DlcMsg::Reject(_) => String::from("Reject"),
+    }
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use escrow_agent_messages::dlc_msg_codec::RejectDlc;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn open_test_queue() -> DlcMsgQueue {
+        let path = std::env::temp_dir().join(format!(
+            "resolvr_dlc_msg_queue_test_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        DlcMsgQueue::new(path.to_str().unwrap()).unwrap()
+    }
+
+    fn get_test_counter_party() -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap())
+    }
+
+    /// A reject stands in for any message, since the queue treats them all
+    /// the same.
+    fn get_test_msg(seed: u8) -> DlcMsg {
+        DlcMsg::Reject(RejectDlc {
+            temporary_contract_id: [seed; 32],
+            reason: None,
+        })
+    }
+
+    fn get_contract_id(msg: &DlcMsg) -> [u8; 32] {
+        match msg {
+            DlcMsg::Reject(reject) => reject.temporary_contract_id,
+            DlcMsg::Protocol(_) => panic!("Expected a reject message."),
+        }
+    }
+
+    fn get_ready_ids(queue: &DlcMsgQueue, now: u64) -> Vec<u64> {
+        queue
+            .get_ready_msgs(now)
+            .unwrap()
+            .iter()
+            .map(|msg| msg.id)
+            .collect()
+    }
+
+    /// Pushes a message and fails it until it's dead-lettered. Returns its ID.
+    fn push_and_dead_letter(queue: &DlcMsgQueue) -> u64 {
+        queue
+            .push(&get_test_msg(1), &get_test_counter_party(), NOW)
+            .unwrap();
+        let id = get_ready_ids(queue, NOW)[0];
+        for _ in 1..MAX_PROCESSING_ATTEMPTS {
+            assert!(!queue.record_failure(id, "Error", NOW).unwrap());
+        }
+        assert!(queue.record_failure(id, "Last error", NOW).unwrap());
+        id
+    }
+
+    #[test]
+    fn push_ignores_redelivered_msgs() {
+        let queue = open_test_queue();
+        let counter_party = get_test_counter_party();
+
+        assert!(queue.push(&get_test_msg(1), &counter_party, NOW).unwrap());
+        assert!(!queue
+            .push(&get_test_msg(1), &counter_party, NOW + 10)
+            .unwrap());
+        assert!(queue
+            .push(&get_test_msg(2), &counter_party, NOW - 10)
+            .unwrap());
+
+        let msgs = queue.get_ready_msgs(NOW).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(get_contract_id(&msgs[0].msg), [1; 32]);
+        assert_eq!(get_contract_id(&msgs[1].msg), [2; 32]);
+        assert_eq!(msgs[0].counter_party, counter_party);
+        assert_eq!(queue.get_receive_cursor().unwrap(), Some(NOW));
+    }
+
+    #[test]
+    fn failed_msgs_back_off_exponentially() {
+        let queue = open_test_queue();
+        queue
+            .push(&get_test_msg(1), &get_test_counter_party(), NOW)
+            .unwrap();
+        queue
+            .push(&get_test_msg(2), &get_test_counter_party(), NOW)
+            .unwrap();
+        let ids = get_ready_ids(&queue, NOW);
+
+        // A failed message waits out its backoff without holding up the next.
+        let mut failed_at = NOW;
+        for attempt in 1..MAX_PROCESSING_ATTEMPTS {
+            assert!(!queue.record_failure(ids[0], "Error", failed_at).unwrap());
+            let next_attempt_at = failed_at + (RETRY_BASE_DELAY_SECS << (attempt - 1));
+            assert_eq!(get_ready_ids(&queue, next_attempt_at - 1), vec![ids[1]]);
+            assert_eq!(get_ready_ids(&queue, next_attempt_at), ids);
+            failed_at = next_attempt_at;
+        }
+    }
+
+    #[test]
+    fn msgs_are_dead_lettered_once_out_of_attempts() {
+        let queue = open_test_queue();
+        let id = push_and_dead_letter(&queue);
+
+        assert!(get_ready_ids(&queue, u64::MAX).is_empty());
+        let dead_letters = queue.get_dead_letters().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].id, id);
+        assert_eq!(dead_letters[0].msg_type, "Reject");
+        assert_eq!(dead_letters[0].attempts, MAX_PROCESSING_ATTEMPTS);
+        assert_eq!(dead_letters[0].last_error, "Last error");
+        assert_eq!(
+            dead_letters[0].counter_party_id,
+            get_test_counter_party().to_string()
+        );
+    }
+
+    #[test]
+    fn undecodable_msgs_are_dead_lettered() {
+        let queue = open_test_queue();
+        queue
+            .queue
+            .insert(&u64::MAX.to_be_bytes()[..], &[0xff; 40][..])
+            .unwrap();
+        queue
+            .push(&get_test_msg(1), &get_test_counter_party(), NOW)
+            .unwrap();
+
+        let msgs = queue.get_ready_msgs(NOW).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(get_contract_id(&msgs[0].msg), [1; 32]);
+
+        let dead_letters = queue.get_dead_letters().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].id, u64::MAX);
+        assert_eq!(dead_letters[0].msg_type, "Unknown");
+        assert_eq!(dead_letters[0].attempts, 0);
+    }
+
+    #[test]
+    fn retried_dead_letters_get_a_fresh_budget() {
+        let queue = open_test_queue();
+        let id = push_and_dead_letter(&queue);
+
+        queue.retry_dead_letter(id).unwrap();
+        assert!(queue.get_dead_letters().unwrap().is_empty());
+        assert_eq!(get_ready_ids(&queue, NOW), vec![id]);
+        assert!(!queue.record_failure(id, "Error", NOW).unwrap());
+
+        assert!(matches!(
+            queue.retry_dead_letter(id),
+            Err(ResolvrError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn discarded_dead_letters_are_gone() {
+        let queue = open_test_queue();
+        let id = push_and_dead_letter(&queue);
+
+        queue.discard_dead_letter(id).unwrap();
+        assert!(queue.get_dead_letters().unwrap().is_empty());
+        assert!(get_ready_ids(&queue, u64::MAX).is_empty());
+        assert!(matches!(
+            queue.discard_dead_letter(id),
+            Err(ResolvrError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn replies_stay_in_outbox_until_removed() {
+        let queue = open_test_queue();
+        let counter_party = get_test_counter_party();
+        queue.push(&get_test_msg(1), &counter_party, NOW).unwrap();
+        queue.push(&get_test_msg(2), &counter_party, NOW).unwrap();
+        let ids = get_ready_ids(&queue, NOW);
+
+        // Removing a message that failed before also clears its failure.
+        queue.record_failure(ids[0], "Error", NOW).unwrap();
+        queue
+            .remove(ids[0], Some((&get_test_msg(3), &counter_party)))
+            .unwrap();
+        queue.remove(ids[1], None).unwrap();
+        queue.push(&get_test_msg(4), &counter_party, NOW).unwrap();
+        let id = get_ready_ids(&queue, NOW)[0];
+        queue
+            .remove(id, Some((&get_test_msg(5), &counter_party)))
+            .unwrap();
+        assert!(get_ready_ids(&queue, u64::MAX).is_empty());
+        assert!(queue.get_failure(&ids[0].to_be_bytes()).unwrap().is_none());
+
+        let outbox_msgs = queue.get_outbox_msgs().unwrap();
+        assert_eq!(outbox_msgs.len(), 2);
+        assert_eq!(get_contract_id(&outbox_msgs[0].msg), [3; 32]);
+        assert_eq!(get_contract_id(&outbox_msgs[1].msg), [5; 32]);
+        assert_eq!(outbox_msgs[0].counter_party, counter_party);
+
+        queue.remove_from_outbox(outbox_msgs[0].id).unwrap();
+        let outbox_msgs = queue.get_outbox_msgs().unwrap();
+        assert_eq!(outbox_msgs.len(), 1);
+        assert_eq!(get_contract_id(&outbox_msgs[0].msg), [5; 32]);
+    }
+
+    #[test]
+    fn sent_msgs_queue_up_behind_replies() {
+        let queue = open_test_queue();
+        let counter_party = get_test_counter_party();
+        queue.push(&get_test_msg(1), &counter_party, NOW).unwrap();
+        let id = get_ready_ids(&queue, NOW)[0];
+        queue
+            .remove(id, Some((&get_test_msg(2), &counter_party)))
+            .unwrap();
+        let id = queue
+            .add_to_outbox(&get_test_msg(3), &counter_party)
+            .unwrap();
+
+        let outbox_msgs = queue.get_outbox_msgs().unwrap();
+        assert_eq!(outbox_msgs.len(), 2);
+        assert_eq!(get_contract_id(&outbox_msgs[0].msg), [2; 32]);
+        assert_eq!(get_contract_id(&outbox_msgs[1].msg), [3; 32]);
+        assert_eq!(outbox_msgs[1].id, id);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::resolvr_oracle::NostrNip4ResolvrOracle;
+use bitcoin::XOnlyPublicKey;
+use dlc_manager::Oracle;
+use escrow_agent_messages::error::ResolvrError;
+use nostr_sdk::Keys;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Oracles of the escrow agents that contracts can be adjudicated by, keyed by
+/// oracle public key.
+pub type EscrowAgentOracles = HashMap<XOnlyPublicKey, Arc<NostrNip4ResolvrOracle>>;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegisteredEscrowAgent {
+    pub npub: String,
+
+    /// Hex-encoded public key the agent signs announcements and attestations
+    /// with.
+    pub oracle_public_key: String,
+
+    pub label: String,
+
+    /// Relays the agent's events are read from and requests are sent to.
+    pub relays: Vec<String>,
+}
+
+/// The escrow agents the user trusts, persisted across restarts, along with an
+/// oracle for each of them.
+pub struct EscrowAgentRegistry {
+    db: sled::Db,
+    oracles: Mutex<EscrowAgentOracles>,
+
+    /// Keys that requests to every agent are sent from.
+    requester_keys: Keys,
+}
+
+impl EscrowAgentRegistry {
+    /// Opens the registry and creates an oracle for every agent in it, sending
+    /// requests from `requester_keys`. Must be called from within a Tokio
+    /// runtime, which the oracles later use to query relays.
+    pub async fn new(path: &str, requester_keys: Keys) -> Result<Self, ResolvrError> {
+        let db = match sled::open(path) {
+            Ok(db) => db,
+            Err(e) => {
+                return Err(ResolvrError::Storage(format!(
+                    "Error opening escrow agent registry: {}",
+                    e
+                )))
+            }
+        };
+
+        let registry = Self {
+            db,
+            oracles: Mutex::new(HashMap::new()),
+            requester_keys,
+        };
+        for agent in registry.get_agents()? {
+            let oracle = Arc::new(
+                NostrNip4ResolvrOracle::new_from_npub(
+                    &agent.npub,
+                    agent.relays.clone(),
+                    registry.requester_keys.clone(),
+                )
+                .await?,
+            );
+            registry
+                .oracles
+                .lock()
+                .unwrap()
+                .insert(oracle.get_public_key(), oracle);
+        }
+        Ok(registry)
+    }
+
+    pub fn get_agents(&self) -> Result<Vec<RegisteredEscrowAgent>, ResolvrError> {
+        self.db
+            .iter()
+            .map(|entry| {
+                let (_, v) = entry.map_err(|e| {
+                    ResolvrError::Storage(format!("Error reading escrow agent: {}", e))
+                })?;
+                serde_json::from_slice(&v).map_err(|e| {
+                    ResolvrError::Storage(format!("Error decoding escrow agent: {}", e))
+                })
+            })
+            .collect()
+    }
+
+    /// Adds the agent with the given npub, connecting to its relays.
+    pub async fn add_agent(
+        &self,
+        npub: &str,
+        label: String,
+        relays: Vec<String>,
+    ) -> Result<RegisteredEscrowAgent, ResolvrError> {
+        let oracle = Arc::new(
+            NostrNip4ResolvrOracle::new_from_npub(
+                npub,
+                relays.clone(),
+                self.requester_keys.clone(),
+            )
+            .await?,
+        );
+        let oracle_public_key = oracle.get_public_key();
+        let agent = RegisteredEscrowAgent {
+            npub: npub.to_string(),
+            oracle_public_key: oracle_public_key.to_string(),
+            label,
+            relays,
+        };
+
+        let bytes = serde_json::to_vec(&agent)
+            .map_err(|e| ResolvrError::Storage(format!("Error encoding escrow agent: {}", e)))?;
+        let mut oracles = self.oracles.lock().unwrap();
+        if oracles.contains_key(&oracle_public_key) {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Escrow agent {} is already registered.",
+                npub
+            )));
+        }
+        self.db
+            .insert(oracle_public_key.serialize(), bytes)
+            .map_err(|e| ResolvrError::Storage(format!("Error saving escrow agent: {}", e)))?;
+        oracles.insert(oracle_public_key, oracle);
+
+        Ok(agent)
+    }
+
+    /// Removes an agent. Open contracts adjudicated by the agent can't close
+    /// until it's added back, but can still be refunded.
+    pub fn remove_agent(&self, oracle_public_key: &XOnlyPublicKey) -> Result<(), ResolvrError> {
+        let mut oracles = self.oracles.lock().unwrap();
+        if !oracles.contains_key(oracle_public_key) {
+            return Err(ResolvrError::NotFound(format!(
+                "Unknown escrow agent {}.",
+                oracle_public_key
+            )));
+        }
+        // The agent is only dropped from memory once it's gone from the
+        // database, so that a failed removal doesn't come back on restart.
+        self.db
+            .remove(oracle_public_key.serialize())
+            .map_err(|e| ResolvrError::Storage(format!("Error removing escrow agent: {}", e)))?;
+        oracles.remove(oracle_public_key);
+        Ok(())
+    }
+
+    pub fn get_oracle(
+        &self,
+        oracle_public_key: &XOnlyPublicKey,
+    ) -> Result<Arc<NostrNip4ResolvrOracle>, ResolvrError> {
+        self.oracles
+            .lock()
+            .unwrap()
+            .get(oracle_public_key)
+            .cloned()
+            .ok_or_else(|| {
+                ResolvrError::NotFound(format!("Unknown escrow agent {}.", oracle_public_key))
+            })
+    }
+
+    /// Returns the oracles of every registered agent, to be used by the DLC
+    /// manager.
+    pub fn get_oracles(&self) -> EscrowAgentOracles {
+        self.oracles.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,281 @@
+use escrow_agent_messages::error::ResolvrError;
+use lightning::chain::chaininterface::{ConfirmationTarget, FeeEstimator};
+use serde::{Deserialize, Serialize};
+
+/// Weight of the parts of the funding transaction that both parties share,
+/// including the funding output, per the DLC spec.
+const FUND_TX_BASE_WEIGHT: u64 = 214;
+
+/// Weight of the parts of a CET or refund transaction that both parties share.
+const CET_BASE_WEIGHT: u64 = 500;
+
+/// Weight of a P2WPKH input, with the largest possible witness.
+const P2WPKH_INPUT_WEIGHT: u64 = 164 + 107;
+
+/// Weight of a P2WPKH output, used for both change and payouts.
+const P2WPKH_OUTPUT_WEIGHT: u64 = 36 + 4 * 22;
+
+/// Weight of a segwit transaction's version, locktime, input and output
+/// counts, marker and flag.
+const TX_BASE_WEIGHT: u64 = 4 * (4 + 4 + 1 + 1) + 2;
+
+/// How quickly a funding transaction should confirm.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum FeeTarget {
+    Economy,
+    Normal,
+    Priority,
+}
+
+/// A fee rate as given by the user, either picked from the estimator's targets
+/// or set explicitly.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "type")]
+pub enum FeeRateInput {
+    Target { target: FeeTarget },
+    Explicit { sats_per_vbyte: u64 },
+}
+
+#[derive(Serialize)]
+pub struct FeeRateSuggestions {
+    pub economy_sats_per_vbyte: u64,
+    pub normal_sats_per_vbyte: u64,
+    pub priority_sats_per_vbyte: u64,
+
+    /// The highest rate that contracts can be offered or accepted at.
+    pub max_sats_per_vbyte: u64,
+}
+
+/// Turns the chain backend's fee estimates into rates for funding contracts,
+/// and refuses rates above a ceiling so that a typo or a misbehaving estimator
+/// can't burn a contract's collateral on fees.
+pub struct FeeService {
+    max_fee_rate_sats_per_vbyte: u64,
+}
+
+impl FeeService {
+    pub fn new(max_fee_rate_sats_per_vbyte: u64) -> Self {
+        Self {
+            max_fee_rate_sats_per_vbyte,
+        }
+    }
+
+    pub fn get_fee_rate_suggestions(&self, fee_estimator: &dyn FeeEstimator) -> FeeRateSuggestions {
+        FeeRateSuggestions {
+            economy_sats_per_vbyte: estimate_fee_rate(fee_estimator, FeeTarget::Economy),
+            normal_sats_per_vbyte: estimate_fee_rate(fee_estimator, FeeTarget::Normal),
+            priority_sats_per_vbyte: estimate_fee_rate(fee_estimator, FeeTarget::Priority),
+            max_sats_per_vbyte: self.max_fee_rate_sats_per_vbyte,
+        }
+    }
+
+    /// Returns the rate to use for `fee_rate_input`, in sats per vbyte.
+    pub fn resolve_fee_rate(
+        &self,
+        fee_estimator: &dyn FeeEstimator,
+        fee_rate_input: FeeRateInput,
+    ) -> Result<u64, ResolvrError> {
+        let fee_rate_sats_per_vbyte = match fee_rate_input {
+            FeeRateInput::Target { target } => estimate_fee_rate(fee_estimator, target),
+            FeeRateInput::Explicit { sats_per_vbyte } => sats_per_vbyte,
+        };
+        self.check_fee_rate(fee_rate_sats_per_vbyte)?;
+        Ok(fee_rate_sats_per_vbyte)
+    }
+
+    pub fn check_fee_rate(&self, fee_rate_sats_per_vbyte: u64) -> Result<(), ResolvrError> {
+        if fee_rate_sats_per_vbyte == 0 {
+            return Err(ResolvrError::InvalidInput(String::from(
+                "Fee rate must be at least 1 sat/vbyte.",
+            )));
+        }
+        if fee_rate_sats_per_vbyte > self.max_fee_rate_sats_per_vbyte {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Fee rate of {} sats/vbyte is above the maximum of {} sats/vbyte.",
+                fee_rate_sats_per_vbyte, self.max_fee_rate_sats_per_vbyte
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What one party of a contract pays in fees, assuming P2WPKH inputs and
+/// outputs.
+#[derive(Serialize)]
+pub struct PartyFeeEstimate {
+    /// Share of the funding transaction's fee.
+    pub funding_fee_sats: u64,
+
+    /// Share of the fee of whichever CET or refund transaction closes the
+    /// contract.
+    pub closing_fee_sats: u64,
+}
+
+impl PartyFeeEstimate {
+    pub fn total_sats(&self) -> u64 {
+        self.funding_fee_sats + self.closing_fee_sats
+    }
+}
+
+/// Estimates one party's fees the same way `dlc` splits them: each party pays
+/// for half of the shared weight plus their own inputs and outputs.
+pub fn estimate_party_fees(nb_inputs: u64, fee_rate_sats_per_vbyte: u64) -> PartyFeeEstimate {
+    PartyFeeEstimate {
+        funding_fee_sats: weight_to_fee(
+            FUND_TX_BASE_WEIGHT / 2 + nb_inputs * P2WPKH_INPUT_WEIGHT + P2WPKH_OUTPUT_WEIGHT,
+            fee_rate_sats_per_vbyte,
+        ),
+        closing_fee_sats: weight_to_fee(
+            CET_BASE_WEIGHT / 2 + P2WPKH_OUTPUT_WEIGHT,
+            fee_rate_sats_per_vbyte,
+        ),
+    }
+}
+
+/// Estimates the size of a funding transaction with the given number of
+/// inputs from both parties, each of which also gets a change output.
+pub fn estimate_funding_tx_vbytes(nb_inputs: u64) -> u64 {
+    (FUND_TX_BASE_WEIGHT + nb_inputs * P2WPKH_INPUT_WEIGHT + 2 * P2WPKH_OUTPUT_WEIGHT).div_ceil(4)
+}
+
+/// Estimates the size of a transaction that spends P2WPKH inputs to a single
+/// recipient, optionally with a P2WPKH change output.
+pub fn estimate_send_tx_vbytes(nb_inputs: u64, recipient_script_len: u64, has_change: bool) -> u64 {
+    let recipient_output_weight = 4 * (8 + 1 + recipient_script_len);
+    let change_output_weight = if has_change { P2WPKH_OUTPUT_WEIGHT } else { 0 };
+    (TX_BASE_WEIGHT
+        + nb_inputs * P2WPKH_INPUT_WEIGHT
+        + recipient_output_weight
+        + change_output_weight)
+        .div_ceil(4)
+}
+
+fn weight_to_fee(weight: u64, fee_rate_sats_per_vbyte: u64) -> u64 {
+    weight.div_ceil(4) * fee_rate_sats_per_vbyte
+}
+
+/// Estimates return sats per 1000 weight units, which are rounded up to whole
+/// sats per vbyte.
+fn estimate_fee_rate(fee_estimator: &dyn FeeEstimator, target: FeeTarget) -> u64 {
+    let confirmation_target = match target {
+        FeeTarget::Economy => ConfirmationTarget::Background,
+        FeeTarget::Normal => ConfirmationTarget::Normal,
+        FeeTarget::Priority => ConfirmationTarget::HighPriority,
+    };
+    let sats_per_kw = fee_estimator.get_est_sat_per_1000_weight(confirmation_target) as u64;
+    (sats_per_kw * 4).div_ceil(1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a different estimate for each confirmation target, in sats per
+    /// 1000 weight units.
+    struct TestFeeEstimator;
+
+    impl FeeEstimator for TestFeeEstimator {
+        fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u32 {
+            match confirmation_target {
+                ConfirmationTarget::Background => 1000,
+                ConfirmationTarget::Normal => 2500,
+                ConfirmationTarget::HighPriority => 5001,
+                _ => 253,
+            }
+        }
+    }
+
+    #[test]
+    fn funding_tx_size_rounds_up_to_whole_vbytes() {
+        // 214 + 2 * 271 + 2 * 124 weight units is exactly 251 vbytes.
+        assert_eq!(estimate_funding_tx_vbytes(2), 251);
+        // 214 + 271 + 2 * 124 weight units is 183.25 vbytes.
+        assert_eq!(estimate_funding_tx_vbytes(1), 184);
+    }
+
+    #[test]
+    fn send_tx_size_counts_change_only_if_there_is_any() {
+        // 42 + 271 + 4 * (8 + 1 + 22) + 124 weight units is 140.25 vbytes.
+        assert_eq!(estimate_send_tx_vbytes(1, 22, true), 141);
+        // Without change, it's 109.25 vbytes.
+        assert_eq!(estimate_send_tx_vbytes(1, 22, false), 110);
+        // Each extra input adds 271 weight units, or 67.75 vbytes.
+        assert_eq!(estimate_send_tx_vbytes(2, 22, false), 177);
+    }
+
+    #[test]
+    fn party_fees_cover_half_the_shared_weight() {
+        let fees = estimate_party_fees(1, 2);
+        // 107 + 271 + 124 weight units is 125.5 vbytes.
+        assert_eq!(fees.funding_fee_sats, 126 * 2);
+        // 250 + 124 weight units is 93.5 vbytes.
+        assert_eq!(fees.closing_fee_sats, 94 * 2);
+        assert_eq!(fees.total_sats(), 440);
+
+        // 107 + 3 * 271 + 124 weight units is exactly 261 vbytes.
+        assert_eq!(estimate_party_fees(3, 1).funding_fee_sats, 261);
+    }
+
+    #[test]
+    fn targets_map_to_confirmation_targets() {
+        let suggestions = FeeService::new(100).get_fee_rate_suggestions(&TestFeeEstimator);
+        assert_eq!(suggestions.economy_sats_per_vbyte, 4);
+        assert_eq!(suggestions.normal_sats_per_vbyte, 10);
+        // 5001 sats per 1000 weight units is 20.004 sats per vbyte.
+        assert_eq!(suggestions.priority_sats_per_vbyte, 21);
+        assert_eq!(suggestions.max_sats_per_vbyte, 100);
+    }
+
+    #[test]
+    fn estimates_are_at_least_one_sat_per_vbyte() {
+        struct EmptyFeeEstimator;
+        impl FeeEstimator for EmptyFeeEstimator {
+            fn get_est_sat_per_1000_weight(&self, _: ConfirmationTarget) -> u32 {
+                0
+            }
+        }
+
+        assert_eq!(estimate_fee_rate(&EmptyFeeEstimator, FeeTarget::Economy), 1);
+    }
+
+    #[test]
+    fn fee_rates_above_the_ceiling_are_refused() {
+        let fee_service = FeeService::new(20);
+        assert!(fee_service.check_fee_rate(1).is_ok());
+        assert!(fee_service.check_fee_rate(20).is_ok());
+        assert!(matches!(
+            fee_service.check_fee_rate(21),
+            Err(ResolvrError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            fee_service.check_fee_rate(0),
+            Err(ResolvrError::InvalidInput(_))
+        ));
+
+        assert_eq!(
+            fee_service
+                .resolve_fee_rate(
+                    &TestFeeEstimator,
+                    FeeRateInput::Target {
+                        target: FeeTarget::Normal
+                    }
+                )
+                .unwrap(),
+            10
+        );
+        assert!(fee_service
+            .resolve_fee_rate(
+                &TestFeeEstimator,
+                FeeRateInput::Target {
+                    target: FeeTarget::Priority
+                }
+            )
+            .is_err());
+        assert!(fee_service
+            .resolve_fee_rate(
+                &TestFeeEstimator,
+                FeeRateInput::Explicit { sats_per_vbyte: 25 }
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::chain_backend_config_store::ChainBackendConfigStore;
+use bitcoin::Network;
+use escrow_agent_messages::error::ResolvrError;
+use std::path::{Path, PathBuf};
+
+/// Data dirs that sat directly in the app local data dir before data was kept
+/// apart per network, with the name each has in a network's dir now.
+const LEGACY_DATA_DIRS: [(&str, &str); 3] = [
+    ("dlc_db_hackathon", "dlc_db"),
+    ("dlc_msg_queue", "dlc_msg_queue"),
+    ("contract_details", "contract_details"),
+];
+
+static CHAIN_BACKEND_CONFIG_FILE: &str = "chain_backend.json";
+
+/// Chain backend configs that sat directly in the app config dir before data
+/// was kept apart per network, newest first. `bitcoin_core.json` is from before
+/// there were chain backends other than Bitcoin Core.
+const LEGACY_CHAIN_BACKEND_CONFIG_FILES: [&str; 2] =
+    [CHAIN_BACKEND_CONFIG_FILE, "bitcoin_core.json"];
+
+/// Moves data saved before the app kept networks apart into the dirs of the
+/// network it was used on, so that existing contracts aren't lost on upgrade.
+///
+/// That network can't be told from the data itself, so it has to be given as
+/// `legacy_network_or`. Fails rather than starting with empty storage if there
+/// is data to move but no network was given.
+pub fn migrate_legacy_data(
+    app_local_data_dir: &Path,
+    app_config_dir: &Path,
+    legacy_network_or: Option<Network>,
+) -> Result<(), ResolvrError> {
+    let legacy_config_paths: Vec<PathBuf> = LEGACY_CHAIN_BACKEND_CONFIG_FILES
+        .iter()
+        .map(|legacy_name| app_config_dir.join(legacy_name))
+        .collect();
+    let mut legacy_paths: Vec<PathBuf> = LEGACY_DATA_DIRS
+        .iter()
+        .map(|(legacy_name, _)| app_local_data_dir.join(legacy_name))
+        .collect();
+    legacy_paths.extend(legacy_config_paths.iter().cloned());
+    legacy_paths.retain(|path| path.exists());
+    if legacy_paths.is_empty() {
+        return Ok(());
+    }
+
+    let legacy_network = match legacy_network_or {
+        Some(v) => v,
+        None => {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Found data from before networks were kept apart ({}). Set \
+                 RESOLVR_LEGACY_DATA_NETWORK to the network it was used on so that it \
+                 can be moved there.",
+                legacy_paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )))
+        }
+    };
+
+    let network_data_dir = app_local_data_dir.join(legacy_network.to_string());
+    for (legacy_name, name) in LEGACY_DATA_DIRS {
+        let legacy_path = app_local_data_dir.join(legacy_name);
+        if legacy_path.exists() {
+            move_legacy_path(&legacy_path, &network_data_dir.join(name))?;
+        }
+    }
+
+    // Only the newest legacy config is imported. Any older one was replaced by
+    // it when the app was last connected, so it's removed rather than left to
+    // block the next start.
+    let chain_backend_config_store = ChainBackendConfigStore::new(
+        app_config_dir
+            .join(legacy_network.to_string())
+            .join(CHAIN_BACKEND_CONFIG_FILE),
+        legacy_network,
+    );
+    let mut imported_config = false;
+    for legacy_config_path in legacy_config_paths {
+        if !legacy_config_path.exists() {
+            continue;
+        }
+        if imported_config {
+            std::fs::remove_file(&legacy_config_path).map_err(|e| {
+                ResolvrError::Storage(format!(
+                    "Error removing superseded chain backend config {}: {}",
+                    legacy_config_path.display(),
+                    e
+                ))
+            })?;
+            println!(
+                "Removed {}, which was superseded by a newer chain backend config.",
+                legacy_config_path.display()
+            );
+        } else {
+            chain_backend_config_store.import_legacy_config(&legacy_config_path)?;
+            imported_config = true;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves `legacy_path` to `path`, refusing to merge it into data that's already
+/// there.
+fn move_legacy_path(legacy_path: &Path, path: &Path) -> Result<(), ResolvrError> {
+    if path.exists() {
+        return Err(ResolvrError::Storage(format!(
+            "Can't move {} to {} since it already exists. Move or remove one of them.",
+            legacy_path.display(),
+            path.display()
+        )));
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| ResolvrError::Storage(format!("Error creating data dir: {}", e)))?;
+    }
+    std::fs::rename(legacy_path, path).map_err(|e| {
+        ResolvrError::Storage(format!(
+            "Error moving {} to {}: {}",
+            legacy_path.display(),
+            path.display(),
+            e
+        ))
+    })?;
+    println!("Moved {} to {}.", legacy_path.display(), path.display());
+
+    Ok(())
+}
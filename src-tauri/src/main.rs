@@ -1,293 +1,633 @@
 // Prevents additional console window on Windows in release. DO NOT REMOVE!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod chain_backend;
+mod chain_backend_config_store;
 mod contract;
+mod contract_detail_store;
+mod dlc_manager_service;
+mod dlc_msg_handler;
+mod dlc_msg_queue;
+mod escrow_agent_registry;
+mod fee_service;
+mod legacy_data;
+mod network;
 mod resolvr_oracle;
+#[cfg(test)]
+mod test_relay;
 
-use bitcoin::secp256k1::PublicKey;
 use bitcoin::XOnlyPublicKey;
-use bitcoin_rpc_provider::BitcoinCoreProvider;
-use contract::JsonContract;
+use chain_backend::{ChainBackendConfig, EsploraConfig, WalletBalance, WalletUtxo};
+use chain_backend_config_store::{ChainBackendConfigStore, SavedChainBackendConfig};
+use contract::{JsonContract, JsonContractDetail, JsonContractStageChange};
+use contract_detail_store::ContractDetailStore;
 use dlc::EnumerationPayout;
 use dlc_manager::contract::contract_input::{ContractInput, ContractInputInfo, OracleInput};
 use dlc_manager::contract::enum_descriptor::EnumDescriptor;
 use dlc_manager::contract::{Contract, ContractDescriptor};
-use dlc_manager::Oracle;
-use dlc_manager::Storage;
-use dlc_manager::SystemTimeProvider;
+use dlc_manager::ContractId;
+use dlc_manager_service::{BountyTimeout, DlcManagerHandle, NegotiationExpiry, OfferPreview};
+use dlc_msg_handler::NostrNip4DlcMessageHandler;
+use dlc_msg_queue::{DeadLetterDlcMsg, DlcMsgQueue};
 use dlc_sled_storage_provider::SledStorageProvider;
-use escrow_agent_messages::EscrowAgent;
+use escrow_agent_messages::error::ResolvrError;
+use escrow_agent_messages::{create_completion_percentage_bounty_contract, EscrowAgent};
 use escrow_agent_messages::{AdjudicationRequest, AdjudicationRequestStatus};
-use resolvr_oracle::{
-    NostrNip4ResolvrOracle, BOUNTY_COMPLETE_ORACLE_MESSAGE, BOUNTY_INSUFFICIENT_ORACLE_MESSAGE,
-};
+use escrow_agent_registry::{EscrowAgentRegistry, RegisteredEscrowAgent};
+use fee_service::{FeeRateInput, FeeRateSuggestions, FeeService};
+use network::parse_network;
+use resolvr_oracle::{BOUNTY_COMPLETE_ORACLE_MESSAGE, BOUNTY_INSUFFICIENT_ORACLE_MESSAGE};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::ops::DerefMut;
 use std::str::FromStr;
-use std::sync::MutexGuard;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tauri::Manager;
 
 use keyring::Entry;
 
 static RESOLVR_KEYRING_SERVICE: &str = "resolvr";
 
+/// Keychain user that the Nostr secret key escrow agent requests are sent
+/// from is saved under.
+static ESCROW_AGENT_REQUESTER_KEYRING_USER: &str = "escrow_agent_requester_nsec";
+
+/// Relays used to read escrow agent events when `RESOLVR_NOSTR_RELAYS` isn't
+/// set.
+static DEFAULT_NOSTR_RELAYS: [&str; 2] = ["wss://relay.damus.io", "wss://nos.lol"];
+
+/// How often contracts are checked against the chain and oracles when
+/// `RESOLVR_PERIODIC_CHECK_INTERVAL_SECS` isn't set.
+const DEFAULT_PERIODIC_CHECK_INTERVAL_SECS: u64 = 30;
+
+/// The network the app runs on when `RESOLVR_NETWORK` isn't set. Defaults to
+/// regtest so that real funds are only ever used when asked for explicitly.
+const DEFAULT_NETWORK: bitcoin::Network = bitcoin::Network::Regtest;
+
+/// The highest fee rate that contracts can be funded at when
+/// `RESOLVR_MAX_FEE_RATE_SATS_PER_VBYTE` isn't set.
+const DEFAULT_MAX_FEE_RATE_SATS_PER_VBYTE: u64 = 500;
+
+/// How long an offer can go without being accepted when
+/// `RESOLVR_OFFER_EXPIRY_SECS` isn't set.
+const DEFAULT_OFFER_EXPIRY_SECS: u64 = 60 * 60 * 24 * 3;
+
+/// How long an accepted contract can go without being signed when
+/// `RESOLVR_ACCEPT_EXPIRY_SECS` isn't set.
+const DEFAULT_ACCEPT_EXPIRY_SECS: u64 = 60 * 60 * 24;
+
+/// Event emitted to the frontend with a `JsonContractStageChange` payload
+/// whenever a contract moves to a new stage.
+static CONTRACT_STAGE_CHANGED_EVENT: &str = "contract_stage_changed";
+
 #[tauri::command]
-fn save_nostr_nsec_to_keychain(npub: &str, nsec: &str) -> Result<(), String> {
-    let entry = Entry::new(RESOLVR_KEYRING_SERVICE, npub).map_err(|e| e.to_string())?;
-    entry.set_password(nsec).map_err(|e| e.to_string())
+fn save_nostr_nsec_to_keychain(npub: &str, nsec: &str) -> Result<(), ResolvrError> {
+    let entry = Entry::new(RESOLVR_KEYRING_SERVICE, npub).map_err(keyring_error)?;
+    entry.set_password(nsec).map_err(keyring_error)
+}
+
+#[tauri::command]
+fn get_nostr_nsec_from_keychain(npub: &str) -> Result<String, ResolvrError> {
+    let entry = Entry::new(RESOLVR_KEYRING_SERVICE, npub).map_err(keyring_error)?;
+    entry.get_password().map_err(keyring_error)
+}
+
+/// Returns the Nostr keys that requests to escrow agents are sent from,
+/// creating and saving them on first use. The user's own keys aren't known
+/// until they connect to relays, but agents are queried from startup, so the
+/// app keeps a key of its own that stays the same across restarts.
+fn get_escrow_agent_requester_keys() -> Result<nostr_sdk::Keys, ResolvrError> {
+    let entry = Entry::new(RESOLVR_KEYRING_SERVICE, ESCROW_AGENT_REQUESTER_KEYRING_USER)
+        .map_err(keyring_error)?;
+    match entry.get_password() {
+        Ok(nsec) => nostr_sdk::Keys::from_sk_str(&nsec).map_err(|e| {
+            ResolvrError::Storage(format!(
+                "Error parsing escrow agent requester secret key: {}",
+                e
+            ))
+        }),
+        Err(keyring::Error::NoEntry) => {
+            let keys = nostr_sdk::Keys::generate();
+            let secret_key = keys.secret_key().map_err(|e| {
+                ResolvrError::Internal(format!("Error getting Nostr secret key: {}", e))
+            })?;
+            entry
+                .set_password(&secret_key.display_secret().to_string())
+                .map_err(keyring_error)?;
+            Ok(keys)
+        }
+        Err(e) => Err(keyring_error(e)),
+    }
+}
+
+fn keyring_error(e: keyring::Error) -> ResolvrError {
+    match e {
+        keyring::Error::NoEntry => {
+            ResolvrError::NotFound(String::from("No key found in keychain."))
+        }
+        e => ResolvrError::Storage(format!("Error accessing keychain: {}", e)),
+    }
 }
 
 #[tauri::command]
-fn get_nostr_nsec_from_keychain(npub: &str) -> Result<String, String> {
-    let entry = Entry::new(RESOLVR_KEYRING_SERVICE, npub).map_err(|e| e.to_string())?;
-    entry.get_password().map_err(|e| e.to_string())
+async fn connect_to_nostr_relays(
+    npub: String,
+    relays: Vec<String>,
+    dlc_msg_handler: tauri::State<'_, Arc<NostrNip4DlcMessageHandler>>,
+) -> Result<(), ResolvrError> {
+    let nsec = get_nostr_nsec_from_keychain(&npub)?;
+    let keys = match nostr_sdk::Keys::from_sk_str(&nsec) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Error parsing Nostr secret key: {}",
+                e
+            )))
+        }
+    };
+
+    dlc_msg_handler.connect(keys, relays).await
 }
 
+/// Requests adjudication of a bounty from one escrow agent. For contracts with
+/// several agents, every agent must be sent the same bounty template so that
+/// they all announce the same oracle event ID.
 #[tauri::command]
 fn request_oracle_adjudication(
     adjudication_request: AdjudicationRequest,
-    oracle: tauri::State<Arc<NostrNip4ResolvrOracle>>,
-) -> Result<AdjudicationRequestStatus, String> {
-    oracle.request_adjudication(adjudication_request)
+    oracle_public_key: &str,
+    escrow_agent_registry: tauri::State<EscrowAgentRegistry>,
+) -> Result<AdjudicationRequestStatus, ResolvrError> {
+    escrow_agent_registry
+        .get_oracle(&parse_oracle_public_key(oracle_public_key)?)?
+        .request_adjudication(adjudication_request)
 }
 
 #[tauri::command]
 fn get_oracle_adjudication_request_status(
     oracle_event_id: &str,
-    oracle: tauri::State<Arc<NostrNip4ResolvrOracle>>,
-) -> Result<AdjudicationRequestStatus, String> {
-    oracle.get_adjudication_request_status(oracle_event_id)
+    oracle_public_key: &str,
+    escrow_agent_registry: tauri::State<EscrowAgentRegistry>,
+) -> Result<AdjudicationRequestStatus, ResolvrError> {
+    escrow_agent_registry
+        .get_oracle(&parse_oracle_public_key(oracle_public_key)?)?
+        .get_adjudication_request_status(oracle_event_id)
 }
 
 #[tauri::command]
-fn connect_to_bitcoin_core(
-    bitcoin_core_config: BitcoinCoreConfig,
-    oracle: tauri::State<'_, Arc<NostrNip4ResolvrOracle>>,
-    dlc_storage: tauri::State<Arc<SledStorageProvider>>,
-    dlc_manager_or: tauri::State<Arc<Mutex<Option<ResolvrDlcManager>>>>,
-) -> Result<(), String> {
-    let bitcoin_core_provider = match BitcoinCoreProvider::new(
-        bitcoin_core_config.host.clone(),
-        bitcoin_core_config.port,
-        None,
-        bitcoin_core_config.rpc_user.clone(),
-        bitcoin_core_config.rpc_password.clone(),
-    ) {
-        Ok(v) => Arc::new(v),
-        Err(e) => return Err(format!("Error creating Bitcoin Core provider: {}", e)),
+fn get_escrow_agents(
+    escrow_agent_registry: tauri::State<EscrowAgentRegistry>,
+) -> Result<Vec<RegisteredEscrowAgent>, ResolvrError> {
+    escrow_agent_registry.get_agents()
+}
+
+/// Adds an escrow agent that contracts can be adjudicated by. Its events are
+/// read from the default relays if none are given.
+#[tauri::command]
+async fn add_escrow_agent(
+    npub: String,
+    label: String,
+    relays: Vec<String>,
+    escrow_agent_registry: tauri::State<'_, EscrowAgentRegistry>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<RegisteredEscrowAgent, ResolvrError> {
+    let relays = if relays.is_empty() {
+        get_default_nostr_relays()
+    } else {
+        relays
     };
+    let agent = escrow_agent_registry
+        .add_agent(&npub, label, relays)
+        .await?;
+    dlc_manager
+        .set_oracles(escrow_agent_registry.get_oracles())
+        .await?;
+    Ok(agent)
+}
 
-    let mut oracles: HashMap<XOnlyPublicKey, Arc<NostrNip4ResolvrOracle>> = HashMap::new();
-    oracles.insert(oracle.get_public_key(), oracle.inner().clone());
-
-    let mut dlc_manager_or: MutexGuard<Option<ResolvrDlcManager>> = dlc_manager_or.lock().unwrap();
-    let binding: &mut Option<ResolvrDlcManager> = dlc_manager_or.deref_mut();
-    match binding {
-        Some(_) => return Err(String::from("DLC manager already initialized.")),
-        None => {
-            let dlc_manager = match dlc_manager::manager::Manager::new(
-                bitcoin_core_provider.clone(),
-                bitcoin_core_provider.clone(),
-                dlc_storage.inner().clone(),
-                oracles,
-                Arc::new(dlc_manager::SystemTimeProvider {}),
-                bitcoin_core_provider,
-            ) {
-                Ok(v) => v,
-                Err(e) => return Err(format!("Error creating DLC manager: {}", e)),
-            };
+#[tauri::command]
+async fn remove_escrow_agent(
+    oracle_public_key: String,
+    escrow_agent_registry: tauri::State<'_, EscrowAgentRegistry>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    escrow_agent_registry.remove_agent(&parse_oracle_public_key(&oracle_public_key)?)?;
+    dlc_manager
+        .set_oracles(escrow_agent_registry.get_oracles())
+        .await
+}
 
-            *binding = Some(dlc_manager);
-        }
-    };
+/// Returns the network the app is running on, as set by `RESOLVR_NETWORK`.
+#[tauri::command]
+fn get_network(dlc_manager: tauri::State<DlcManagerHandle>) -> String {
+    dlc_manager.get_network().to_string()
+}
 
+#[tauri::command]
+async fn connect_to_bitcoin_core(
+    bitcoin_core_config: BitcoinCoreConfig,
+    escrow_agent_registry: tauri::State<'_, EscrowAgentRegistry>,
+    chain_backend_config_store: tauri::State<'_, ChainBackendConfigStore>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    connect_to_chain_backend(
+        ChainBackendConfig::BitcoinCore(bitcoin_core_config),
+        &escrow_agent_registry,
+        &chain_backend_config_store,
+        &dlc_manager,
+    )
+    .await
+}
+
+/// Connects to an Esplora server instead of Bitcoin Core, using a wallet kept
+/// in the app's own storage.
+#[tauri::command]
+async fn connect_to_esplora(
+    esplora_config: EsploraConfig,
+    escrow_agent_registry: tauri::State<'_, EscrowAgentRegistry>,
+    chain_backend_config_store: tauri::State<'_, ChainBackendConfigStore>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    connect_to_chain_backend(
+        ChainBackendConfig::Esplora(esplora_config),
+        &escrow_agent_registry,
+        &chain_backend_config_store,
+        &dlc_manager,
+    )
+    .await
+}
+
+async fn connect_to_chain_backend(
+    chain_backend_config: ChainBackendConfig,
+    escrow_agent_registry: &EscrowAgentRegistry,
+    chain_backend_config_store: &ChainBackendConfigStore,
+    dlc_manager: &DlcManagerHandle,
+) -> Result<(), ResolvrError> {
+    dlc_manager
+        .connect(
+            chain_backend_config.clone(),
+            escrow_agent_registry.get_oracles(),
+        )
+        .await?;
+    save_chain_backend_config(chain_backend_config_store, &chain_backend_config);
     Ok(())
 }
 
+/// Reconnects to the chain backend, such as after the node restarts or its
+/// details change, without losing any contracts. Reuses the last config if
+/// none is given.
 #[tauri::command]
-fn get_contracts(
-    dlc_storage: tauri::State<Arc<SledStorageProvider>>,
-) -> Result<Vec<JsonContract>, String> {
-    let contracts: Vec<Contract> = match dlc_storage.get_contracts() {
-        Ok(v) => v,
-        Err(e) => return Err(format!("Error getting contracts: {}", e)),
-    };
+async fn reconnect_to_chain_backend(
+    chain_backend_config: Option<ChainBackendConfig>,
+    chain_backend_config_store: tauri::State<'_, ChainBackendConfigStore>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    dlc_manager.reconnect(chain_backend_config.clone()).await?;
+    if let Some(chain_backend_config) = chain_backend_config {
+        save_chain_backend_config(&chain_backend_config_store, &chain_backend_config);
+    }
+    Ok(())
+}
+
+/// Disconnects from the chain backend and forgets it, so that the app no
+/// longer connects to it on startup.
+#[tauri::command]
+async fn disconnect_from_chain_backend(
+    chain_backend_config_store: tauri::State<'_, ChainBackendConfigStore>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    dlc_manager.disconnect().await?;
+    chain_backend_config_store.clear()
+}
+
+/// Returns the details of the chain backend the app connects to on startup,
+/// without the Bitcoin Core RPC password.
+#[tauri::command]
+fn get_saved_chain_backend_config(
+    chain_backend_config_store: tauri::State<ChainBackendConfigStore>,
+) -> Result<Option<SavedChainBackendConfig>, ResolvrError> {
+    chain_backend_config_store.get()
+}
+
+/// Saves the config of a chain backend that was connected to. The connection
+/// is already up at this point, so failing to save it is only logged.
+fn save_chain_backend_config(
+    chain_backend_config_store: &ChainBackendConfigStore,
+    chain_backend_config: &ChainBackendConfig,
+) {
+    if let Err(e) = chain_backend_config_store.save(chain_backend_config) {
+        println!("Error saving chain backend config: {}", e);
+    }
+}
+
+#[tauri::command]
+async fn get_wallet_balance(
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<WalletBalance, ResolvrError> {
+    dlc_manager.get_wallet_balance().await
+}
+
+#[tauri::command]
+async fn get_new_address(
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<String, ResolvrError> {
+    dlc_manager.get_new_address().await
+}
+
+#[tauri::command]
+async fn get_wallet_utxos(
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<Vec<WalletUtxo>, ResolvrError> {
+    dlc_manager.get_wallet_utxos().await
+}
+
+/// Sends funds from the wallet and returns the ID of the transaction. UTXOs
+/// reserved by contracts are never spent.
+#[tauri::command]
+async fn send_to_address(
+    address: String,
+    amount_sats: u64,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<String, ResolvrError> {
+    dlc_manager.send_to_address(address, amount_sats).await
+}
+
+#[tauri::command]
+async fn get_contracts(
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<Vec<JsonContract>, ResolvrError> {
+    let contracts: Vec<Contract> = dlc_manager.get_contracts().await?;
 
     Ok(contracts.iter().map(JsonContract::from).collect())
 }
 
 #[tauri::command]
-fn offer_contract(
+async fn get_contract(
+    contract_id: String,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<JsonContractDetail, ResolvrError> {
+    dlc_manager
+        .get_contract(parse_contract_id(&contract_id)?)
+        .await
+}
+
+/// Returns fee rates for funding a contract to confirm at each target, along
+/// with the highest rate that's allowed.
+#[tauri::command]
+async fn get_fee_rate_suggestions(
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<FeeRateSuggestions, ResolvrError> {
+    dlc_manager.get_fee_rate_suggestions().await
+}
+
+#[tauri::command]
+async fn offer_contract(
     bounty_amount_sats: u64,
     taker_collateral_sats: u64,
-    fee_rate_sats_per_vbyte: u64,
+    fee_rate: FeeRateInput,
+    partial_outcomes: Vec<PartialBountyOutcome>,
     oracle_event_id: String,
-    counter_party_public_key: &str,
-    dlc_manager_or: tauri::State<Arc<Mutex<Option<ResolvrDlcManager>>>>,
-    oracle: tauri::State<Arc<NostrNip4ResolvrOracle>>,
-) -> Result<(), String> {
-    let mut dlc_manager_or = dlc_manager_or.lock().unwrap();
-    let mut binding = dlc_manager_or.as_mut();
-    let dlc_manager = match &mut binding {
-        Some(m) => m,
-        None => return Err(String::from("DLC manager not initialized.")),
-    };
+    oracle_public_keys: Vec<String>,
+    oracle_threshold: u16,
+    bounty_deadline: u32,
+    refund_delay_secs: u32,
+    counter_party_public_key: String,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    let public_key = parse_public_key(&counter_party_public_key)?;
+    let fee_rate_sats_per_vbyte = dlc_manager.resolve_fee_rate(fee_rate).await?;
 
-    let public_key = match bitcoin::secp256k1::PublicKey::from_str(counter_party_public_key) {
-        Ok(pk) => pk,
-        Err(e) => return Err(format!("Error parsing public key: {}", e)),
-    };
+    let dlc_contract = create_bounty_contract(
+        bounty_amount_sats,
+        taker_collateral_sats,
+        fee_rate_sats_per_vbyte,
+        &partial_outcomes,
+        create_oracle_input(&oracle_public_keys, oracle_threshold, oracle_event_id)?,
+    )?;
+
+    dlc_manager
+        .offer_contract(
+            dlc_contract,
+            public_key,
+            BountyTimeout {
+                deadline: bounty_deadline,
+                refund_delay_secs,
+            },
+        )
+        .await
+}
+
+/// Builds the same contract as `offer_contract` and works out what it would
+/// cost and pay out, without sending an offer or reserving any UTXOs.
+#[tauri::command]
+async fn preview_offer(
+    bounty_amount_sats: u64,
+    taker_collateral_sats: u64,
+    fee_rate: FeeRateInput,
+    partial_outcomes: Vec<PartialBountyOutcome>,
+    oracle_event_id: String,
+    oracle_public_keys: Vec<String>,
+    oracle_threshold: u16,
+    bounty_deadline: u32,
+    refund_delay_secs: u32,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<OfferPreview, ResolvrError> {
+    let fee_rate_sats_per_vbyte = dlc_manager.resolve_fee_rate(fee_rate).await?;
 
     let dlc_contract = create_bounty_contract(
         bounty_amount_sats,
         taker_collateral_sats,
         fee_rate_sats_per_vbyte,
-        oracle.get_public_key(),
-        oracle_event_id,
-    );
+        &partial_outcomes,
+        create_oracle_input(&oracle_public_keys, oracle_threshold, oracle_event_id)?,
+    )?;
 
-    match dlc_manager.send_offer(&dlc_contract, public_key) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(format!("Error sending contract offer: {}", e)),
-    }
+    dlc_manager
+        .preview_offer(
+            dlc_contract,
+            BountyTimeout {
+                deadline: bounty_deadline,
+                refund_delay_secs,
+            },
+        )
+        .await
 }
 
+/// Offers a contract for a bounty that the escrow agent adjudicates by
+/// attesting to a completion percentage (see `BountyTemplate`).
 #[tauri::command]
-fn accept_contract(
+async fn offer_completion_percentage_contract(
+    bounty_amount_sats: u64,
+    taker_collateral_sats: u64,
+    fee_rate: FeeRateInput,
+    oracle_event_id: String,
+    oracle_public_keys: Vec<String>,
+    oracle_threshold: u16,
+    bounty_deadline: u32,
+    refund_delay_secs: u32,
+    counter_party_public_key: String,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    let public_key = parse_public_key(&counter_party_public_key)?;
+    let fee_rate_sats_per_vbyte = dlc_manager.resolve_fee_rate(fee_rate).await?;
+
+    let dlc_contract = create_completion_percentage_bounty_contract(
+        bounty_amount_sats,
+        taker_collateral_sats,
+        fee_rate_sats_per_vbyte,
+        create_oracle_input(&oracle_public_keys, oracle_threshold, oracle_event_id)?,
+    )?;
+
+    dlc_manager
+        .offer_contract(
+            dlc_contract,
+            public_key,
+            BountyTimeout {
+                deadline: bounty_deadline,
+                refund_delay_secs,
+            },
+        )
+        .await
+}
+
+#[tauri::command]
+async fn accept_contract(
     contract_id: String,
-    dlc_manager_or: tauri::State<Arc<Mutex<Option<ResolvrDlcManager>>>>,
-    dlc_msg_handler: tauri::State<Arc<NostrNip4DlcMessageHandler>>,
-) -> Result<JsonContract, String> {
-    let mut dlc_manager_or = dlc_manager_or.lock().unwrap();
-    let mut binding = dlc_manager_or.as_mut();
-    let dlc_manager = match &mut binding {
-        Some(m) => m,
-        None => return Err(String::from("DLC manager not initialized.")),
-    };
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<JsonContract, ResolvrError> {
+    let contract = dlc_manager
+        .accept_contract(parse_contract_id(&contract_id)?)
+        .await?;
 
-    let contract_id_bytes = match hex::decode(contract_id) {
-        Ok(v) => v,
-        Err(e) => {
-            return Err(format!(
-                "Error decoding contract ID: {}. ID must be a hex string.",
-                e
-            ))
-        }
-    };
+    Ok((&contract).into())
+}
 
-    let contract_id: [u8; 32] = match contract_id_bytes.try_into() {
-        Ok(v) => v,
-        Err(_) => {
-            return Err(String::from(
-                "Error decoding contract ID. ID must be a 32-byte hex string.",
-            ))
-        }
-    };
+/// Declines a contract offered by a counterparty. The counterparty is sent the
+/// optional reason and sees the contract as Rejected.
+#[tauri::command]
+async fn reject_contract(
+    contract_id: String,
+    reason: Option<String>,
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    dlc_manager
+        .reject_contract(parse_contract_id(&contract_id)?, reason)
+        .await
+}
 
-    let (_, counter_party, accept_dlc) = match dlc_manager.accept_contract_offer(&contract_id) {
-        Ok(res) => res,
-        Err(e) => return Err(format!("Error accepting contract: {}", e)),
-    };
-    dlc_msg_handler.send_msg(dlc_messages::Message::Accept(accept_dlc), counter_party);
+/// Runs a periodic check right away rather than waiting for the scheduler, and
+/// returns the contracts that changed stage.
+#[tauri::command]
+async fn run_periodic_check(
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<Vec<JsonContractStageChange>, ResolvrError> {
+    dlc_manager.periodic_check().await
+}
 
-    let contract = match dlc_manager.get_store().get_contract(&contract_id) {
-        Ok(v) => match v {
-            Some(v) => v,
-            None => return Err(String::from("Contract not found.")),
-        },
-        Err(e) => return Err(format!("Error getting contract: {}", e)),
-    };
+#[tauri::command]
+fn get_dead_letter_dlc_msgs(
+    dlc_msg_handler: tauri::State<Arc<NostrNip4DlcMessageHandler>>,
+) -> Result<Vec<DeadLetterDlcMsg>, ResolvrError> {
+    dlc_msg_handler.incoming_msg_queue().get_dead_letters()
+}
 
-    Ok((&contract).into())
+/// Moves a dead-lettered DLC message back into the incoming queue so that it
+/// is processed again.
+#[tauri::command]
+fn retry_dead_letter_dlc_msg(
+    id: u64,
+    dlc_msg_handler: tauri::State<Arc<NostrNip4DlcMessageHandler>>,
+) -> Result<(), ResolvrError> {
+    dlc_msg_handler.incoming_msg_queue().retry_dead_letter(id)
 }
 
 #[tauri::command]
-fn delete_contract(
+fn discard_dead_letter_dlc_msg(
+    id: u64,
+    dlc_msg_handler: tauri::State<Arc<NostrNip4DlcMessageHandler>>,
+) -> Result<(), ResolvrError> {
+    dlc_msg_handler.incoming_msg_queue().discard_dead_letter(id)
+}
+
+#[tauri::command]
+async fn delete_contract(
     contract_id: String,
-    dlc_manager_or: tauri::State<Arc<Mutex<Option<ResolvrDlcManager>>>>,
-) -> Result<(), String> {
-    let mut dlc_manager_or = dlc_manager_or.lock().unwrap();
-    let mut binding = dlc_manager_or.as_mut();
-    let dlc_manager = match &mut binding {
-        Some(m) => m,
-        None => return Err(String::from("DLC manager not initialized.")),
-    };
+    dlc_manager: tauri::State<'_, DlcManagerHandle>,
+) -> Result<(), ResolvrError> {
+    dlc_manager
+        .delete_contract(parse_contract_id(&contract_id)?)
+        .await
+}
 
+fn parse_public_key(public_key: &str) -> Result<bitcoin::secp256k1::PublicKey, ResolvrError> {
+    match bitcoin::secp256k1::PublicKey::from_str(public_key) {
+        Ok(pk) => Ok(pk),
+        Err(e) => Err(ResolvrError::InvalidInput(format!(
+            "Error parsing public key: {}",
+            e
+        ))),
+    }
+}
+
+fn parse_oracle_public_key(oracle_public_key: &str) -> Result<XOnlyPublicKey, ResolvrError> {
+    match XOnlyPublicKey::from_str(oracle_public_key) {
+        Ok(pk) => Ok(pk),
+        Err(e) => Err(ResolvrError::InvalidInput(format!(
+            "Error parsing oracle public key: {}",
+            e
+        ))),
+    }
+}
+
+fn parse_contract_id(contract_id: &str) -> Result<ContractId, ResolvrError> {
     let contract_id_bytes = match hex::decode(contract_id) {
         Ok(v) => v,
         Err(e) => {
-            return Err(format!(
+            return Err(ResolvrError::InvalidContractId(format!(
                 "Error decoding contract ID: {}. ID must be a hex string.",
                 e
-            ))
-        }
-    };
-
-    let contract_id: [u8; 32] = match contract_id_bytes.try_into() {
-        Ok(v) => v,
-        Err(_) => {
-            return Err(String::from(
-                "Error decoding contract ID. ID must be a 32-byte hex string.",
-            ))
+            )))
         }
     };
 
-    match dlc_manager.get_store().delete_contract(&contract_id) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(format!("Error deleting contract: {}", e)),
+    match contract_id_bytes.try_into() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(ResolvrError::InvalidContractId(String::from(
+            "Error decoding contract ID. ID must be a 32-byte hex string.",
+        ))),
     }
 }
 
-struct NostrNip4DlcMessageHandler {}
-
-impl NostrNip4DlcMessageHandler {
-    fn new() -> Self {
-        Self {}
-    }
-
-    /// Sends a message to the given counterparty to progress the state of a DLC
-    /// contract.
-    fn send_msg(&self, _msg: dlc_messages::Message, _counter_party: PublicKey) {
-        panic!("Not implemented.");
-    }
-
-    /// Returns the next incoming messages and the counterparty public key that
-    /// sent it, without removing it from the queue.
-    fn peek_next_incoming_msg(&self) -> &Option<(dlc_messages::Message, PublicKey)> {
-        panic!("Not implemented.");
-    }
+fn now_epoch_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
 
-    /// Returns the next incoming messages and the counterparty public key that
-    /// sent it, removing it from the queue.
-    fn pop_next_incoming_msg(&self) -> Option<(dlc_messages::Message, PublicKey)> {
-        panic!("Not implemented.");
+/// Relays from `RESOLVR_NOSTR_RELAYS`, or `DEFAULT_NOSTR_RELAYS` if it isn't
+/// set.
+fn get_default_nostr_relays() -> Vec<String> {
+    match std::env::var("RESOLVR_NOSTR_RELAYS") {
+        Ok(v) => v.split(',').map(|relay| relay.trim().to_string()).collect(),
+        Err(_) => DEFAULT_NOSTR_RELAYS
+            .iter()
+            .map(|relay| relay.to_string())
+            .collect(),
     }
 }
 
-fn process_incoming_dlc_msgs(
-    dlc_manager: &mut ResolvrDlcManager,
-    dlc_msg_handler: &NostrNip4DlcMessageHandler,
-) -> Result<(), String> {
-    loop {
-        let (msg, counter_party) = match dlc_msg_handler.peek_next_incoming_msg() {
-            Some(next_msg) => next_msg,
-            // If there are no more messages, stop processing.
-            None => return Ok(()),
-        };
-
-        match dlc_manager.on_dlc_message(msg, *counter_party) {
-            Ok(_) => {
-                // Remove the message from the queue since it was processed
-                // successfully.
-                dlc_msg_handler.pop_next_incoming_msg();
-            }
-            Err(e) => return Err(format!("Error processing message: {}", e)),
-        };
+/// Expiry window from the environment variable `name`, or `default_secs` if it
+/// isn't set.
+fn get_expiry_secs_from_env(name: &str, default_secs: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(v) => match v.parse() {
+            Ok(secs) if secs > 0 => secs,
+            _ => panic!("{} must be a positive number of seconds.", name),
+        },
+        Err(_) => default_secs,
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct BitcoinCoreConfig {
     host: String,
     port: u16,
@@ -295,114 +635,517 @@ struct BitcoinCoreConfig {
     rpc_password: String,
 }
 
-type ResolvrDlcManager = dlc_manager::manager::Manager<
-    Arc<BitcoinCoreProvider>,
-    Arc<BitcoinCoreProvider>,
-    Arc<SledStorageProvider>,
-    Arc<NostrNip4ResolvrOracle>,
-    Arc<SystemTimeProvider>,
-    Arc<BitcoinCoreProvider>,
->;
-
 #[tokio::main]
 async fn main() {
     let context = tauri::generate_context!();
     let app_local_data_dir = tauri::api::path::app_local_data_dir(context.config())
         .expect("Error getting app local data dir.");
 
-    // TODO: Set the nPub to our hosted oracle (once it exists).
-    let oracle = Arc::from(NostrNip4ResolvrOracle::new_from_npub());
+    let app_config_dir =
+        tauri::api::path::app_config_dir(context.config()).expect("Error getting app config dir.");
 
-    let dlc_msg_handler = Arc::from(NostrNip4DlcMessageHandler::new());
+    let network = match std::env::var("RESOLVR_NETWORK") {
+        Ok(v) => parse_network(&v).expect("Error parsing RESOLVR_NETWORK."),
+        Err(_) => DEFAULT_NETWORK,
+    };
+
+    let legacy_data_network_or = match std::env::var("RESOLVR_LEGACY_DATA_NETWORK") {
+        Ok(v) => Some(parse_network(&v).expect("Error parsing RESOLVR_LEGACY_DATA_NETWORK.")),
+        Err(_) => None,
+    };
+    if let Err(e) = legacy_data::migrate_legacy_data(
+        &app_local_data_dir,
+        &app_config_dir,
+        legacy_data_network_or,
+    ) {
+        panic!("Error migrating legacy data. {}", e);
+    }
+
+    // Everything tied to a chain is kept apart per network, so that switching
+    // networks never mixes up contracts or connects to the wrong node.
+    let chain_backend_config_store = ChainBackendConfigStore::new(
+        app_config_dir
+            .join(network.to_string())
+            .join("chain_backend.json"),
+        network,
+    );
+
+    let periodic_check_interval_secs: u64 =
+        match std::env::var("RESOLVR_PERIODIC_CHECK_INTERVAL_SECS") {
+            Ok(v) => match v.parse() {
+                Ok(secs) if secs > 0 => secs,
+                _ => panic!(
+                    "RESOLVR_PERIODIC_CHECK_INTERVAL_SECS must be a positive number of seconds."
+                ),
+            },
+            Err(_) => DEFAULT_PERIODIC_CHECK_INTERVAL_SECS,
+        };
+
+    let max_fee_rate_sats_per_vbyte: u64 =
+        match std::env::var("RESOLVR_MAX_FEE_RATE_SATS_PER_VBYTE") {
+            Ok(v) => match v.parse() {
+                Ok(sats_per_vbyte) if sats_per_vbyte > 0 => sats_per_vbyte,
+                _ => panic!("RESOLVR_MAX_FEE_RATE_SATS_PER_VBYTE must be a positive number."),
+            },
+            Err(_) => DEFAULT_MAX_FEE_RATE_SATS_PER_VBYTE,
+        };
+
+    let negotiation_expiry = NegotiationExpiry {
+        offered_secs: get_expiry_secs_from_env(
+            "RESOLVR_OFFER_EXPIRY_SECS",
+            DEFAULT_OFFER_EXPIRY_SECS,
+        ),
+        accepted_secs: get_expiry_secs_from_env(
+            "RESOLVR_ACCEPT_EXPIRY_SECS",
+            DEFAULT_ACCEPT_EXPIRY_SECS,
+        ),
+    };
+
+    let network_data_dir = app_local_data_dir.join(network.to_string());
+    let network_data_dir = network_data_dir
+        .to_str()
+        .expect("Error converting app local data dir to string.");
+    let app_local_data_dir = app_local_data_dir
+        .to_str()
+        .expect("Error converting app local data dir to string.");
+
+    let escrow_agent_registry = EscrowAgentRegistry::new(
+        &format!("{}/escrow_agents", app_local_data_dir),
+        get_escrow_agent_requester_keys().expect("Error getting escrow agent requester keys."),
+    )
+    .await
+    .expect("Error opening escrow agent registry.");
+
+    // Agents in `RESOLVR_ESCROW_AGENT_NPUB` are registered on startup, so that
+    // a development agent doesn't need to be added by hand.
+    // TODO: Default to the nPub of our hosted oracle (once it exists).
+    if let Ok(escrow_agent_npubs) = std::env::var("RESOLVR_ESCROW_AGENT_NPUB") {
+        let registered_npubs: Vec<String> = escrow_agent_registry
+            .get_agents()
+            .expect("Error reading escrow agent registry.")
+            .into_iter()
+            .map(|agent| agent.npub)
+            .collect();
+        for escrow_agent_npub in escrow_agent_npubs.split(',').map(str::trim) {
+            if registered_npubs
+                .iter()
+                .any(|npub| npub == escrow_agent_npub)
+            {
+                continue;
+            }
+            // A bad entry only costs that agent, not the whole app.
+            if let Err(e) = escrow_agent_registry
+                .add_agent(
+                    escrow_agent_npub,
+                    String::from("RESOLVR_ESCROW_AGENT_NPUB"),
+                    get_default_nostr_relays(),
+                )
+                .await
+            {
+                println!(
+                    "Error registering escrow agent {}: {}",
+                    escrow_agent_npub, e
+                );
+            }
+        }
+    }
+
+    // Incoming messages are kept in their own sled database next to the DLC
+    // storage, rather than in a tree of the same database. `SledStorageProvider`
+    // only opens its database from a path and doesn't expose it, and sled holds
+    // an exclusive lock on its data dir, so the two can't share a database
+    // without changes to `dlc_sled_storage_provider`. A message is only removed
+    // from the queue once the DLC manager has processed it, so a crash between
+    // the two writes means it's processed again rather than lost.
+    let dlc_msg_handler = Arc::from(NostrNip4DlcMessageHandler::new(
+        DlcMsgQueue::new(&format!("{}/dlc_msg_queue", network_data_dir))
+            .expect("Error creating DLC message queue."),
+    ));
 
     let dlc_storage_provider: Arc<SledStorageProvider> = Arc::new(
-        SledStorageProvider::new(&format!(
-            "{}/dlc_db_hackathon",
-            app_local_data_dir
-                .to_str()
-                .expect("Error converting app local data dir to string.")
-        ))
-        .expect("Error creating DLC storage."),
+        SledStorageProvider::new(&format!("{}/dlc_db", network_data_dir))
+            .expect("Error creating DLC storage."),
+    );
+
+    let contract_detail_store =
+        ContractDetailStore::new(&format!("{}/contract_details", network_data_dir))
+            .expect("Error creating contract detail store.");
+
+    let dlc_manager = DlcManagerHandle::spawn(
+        network,
+        FeeService::new(max_fee_rate_sats_per_vbyte),
+        negotiation_expiry,
+        dlc_storage_provider,
+        contract_detail_store,
+        dlc_msg_handler.clone(),
     );
 
-    let dlc_manager_or: Arc<Mutex<Option<ResolvrDlcManager>>> = Arc::new(Mutex::new(None));
+    dlc_manager.spawn_periodic_check_scheduler(std::time::Duration::from_secs(
+        periodic_check_interval_secs,
+    ));
+    dlc_manager.spawn_reconnect_scheduler();
 
-    let dlc_msg_handler_clone = dlc_msg_handler.clone();
-    let dlc_manager_or_clone = dlc_manager_or.clone();
+    // Connect to the last chain backend used, so that messages are processed
+    // and contracts are checked without having to open the app's node page.
+    // The node may not be up yet, so it's retried until it is.
+    match chain_backend_config_store.load() {
+        Ok(Some(chain_backend_config)) => {
+            let dlc_manager = dlc_manager.clone();
+            let oracles = escrow_agent_registry.get_oracles();
+            tokio::task::spawn(async move {
+                match dlc_manager
+                    .connect_with_retry(chain_backend_config, oracles)
+                    .await
+                {
+                    Ok(()) => println!("Connected to the chain backend."),
+                    Err(ResolvrError::Rpc(e)) => println!(
+                        "Error connecting to the chain backend, retrying in the background: {}",
+                        e
+                    ),
+                    Err(e) => println!("Error connecting to the chain backend: {}", e),
+                }
+            });
+        }
+        Ok(None) => {}
+        Err(e) => println!("Error loading chain backend config: {}", e),
+    }
+
+    let dlc_manager_clone = dlc_manager.clone();
     tokio::task::spawn(async move {
         loop {
-            if let Some(dlc_manager) = dlc_manager_or_clone.lock().unwrap().as_mut() {
-                if let Err(e) = process_incoming_dlc_msgs(dlc_manager, &dlc_msg_handler_clone) {
-                    // TODO: Handle error.
-                    println!("Error processing incoming DLC messages: {}", e);
-                };
+            if let Err(e) = dlc_manager_clone.process_incoming_msgs().await {
+                // TODO: Handle error.
+                println!("Error processing incoming DLC messages: {}", e);
             };
             tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
     });
 
+    let mut stage_change_receiver = dlc_manager.subscribe_to_stage_changes();
+
     tauri::Builder::default()
+        .setup(move |app| {
+            let app_handle = app.handle();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    match stage_change_receiver.recv().await {
+                        Ok(stage_change) => {
+                            if let Err(e) =
+                                app_handle.emit_all(CONTRACT_STAGE_CHANGED_EVENT, stage_change)
+                            {
+                                println!("Error emitting contract stage change: {}", e);
+                            }
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(count)) => {
+                            println!("Dropped {} contract stage changes.", count);
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             save_nostr_nsec_to_keychain,
             get_nostr_nsec_from_keychain,
+            connect_to_nostr_relays,
             request_oracle_adjudication,
             get_oracle_adjudication_request_status,
+            get_escrow_agents,
+            add_escrow_agent,
+            remove_escrow_agent,
+            get_network,
             connect_to_bitcoin_core,
+            connect_to_esplora,
+            reconnect_to_chain_backend,
+            disconnect_from_chain_backend,
+            get_saved_chain_backend_config,
+            get_wallet_balance,
+            get_new_address,
+            get_wallet_utxos,
+            send_to_address,
             get_contracts,
+            get_contract,
+            get_fee_rate_suggestions,
             offer_contract,
+            preview_offer,
+            offer_completion_percentage_contract,
             accept_contract,
-            delete_contract
+            reject_contract,
+            delete_contract,
+            run_periodic_check,
+            get_dead_letter_dlc_msgs,
+            retry_dead_letter_dlc_msg,
+            discard_dead_letter_dlc_msg
         ])
-        .manage(oracle)
+        .manage(escrow_agent_registry)
+        .manage(chain_backend_config_store)
         .manage(dlc_msg_handler)
-        .manage(dlc_storage_provider)
-        .manage(dlc_manager_or)
+        .manage(dlc_manager)
         .plugin(tauri_plugin_store::Builder::default().build())
         .run(context)
         .expect("Error while running Tauri application.");
 }
 
+/// An outcome for a partially completed bounty, which the escrow agent must
+/// have announced alongside the standard outcomes.
+#[derive(Serialize, Deserialize)]
+struct PartialBountyOutcome {
+    outcome: String,
+
+    /// Percentage of the bounty amount that the taker gets for this outcome,
+    /// on top of their collateral back. The maker gets the rest.
+    bounty_percent: u8,
+}
+
 /// Create a DLC contract template for a bounty.
 fn create_bounty_contract(
     bounty_amount_sats: u64,
     taker_collateral_sats: u64,
     fee_rate_sats_per_vbyte: u64,
-    oracle_public_key: XOnlyPublicKey,
-    oracle_event_id: String,
-) -> ContractInput {
-    ContractInput {
+    partial_outcomes: &[PartialBountyOutcome],
+    oracle_input: OracleInput,
+) -> Result<ContractInput, ResolvrError> {
+    let mut outcome_payouts = vec![
+        // If the bounty is completed, the taker gets the bounty amount plus
+        // their collateral back.
+        EnumerationPayout {
+            outcome: BOUNTY_COMPLETE_ORACLE_MESSAGE.to_string(),
+            payout: dlc::Payout {
+                offer: 0,
+                accept: bounty_amount_sats + taker_collateral_sats,
+            },
+        },
+        // If the bounty is not completed, the maker gets the bounty back plus
+        // the taker's collateral as compensation for their time.
+        EnumerationPayout {
+            outcome: BOUNTY_INSUFFICIENT_ORACLE_MESSAGE.to_string(),
+            payout: dlc::Payout {
+                offer: bounty_amount_sats + taker_collateral_sats,
+                accept: 0,
+            },
+        },
+    ];
+
+    // If the bounty is partially completed, the taker gets their share of the
+    // bounty plus their collateral back.
+    for partial_outcome in partial_outcomes {
+        if partial_outcome.bounty_percent == 0 || partial_outcome.bounty_percent >= 100 {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Partial outcome {} must pay out between 1% and 99% of the bounty.",
+                partial_outcome.outcome
+            )));
+        }
+        if outcome_payouts
+            .iter()
+            .any(|outcome_payout| outcome_payout.outcome == partial_outcome.outcome)
+        {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Duplicate outcome: {}",
+                partial_outcome.outcome
+            )));
+        }
+
+        let taker_bounty_share_sats =
+            bounty_amount_sats * partial_outcome.bounty_percent as u64 / 100;
+        outcome_payouts.push(EnumerationPayout {
+            outcome: partial_outcome.outcome.clone(),
+            payout: dlc::Payout {
+                offer: bounty_amount_sats - taker_bounty_share_sats,
+                accept: taker_bounty_share_sats + taker_collateral_sats,
+            },
+        });
+    }
+
+    Ok(ContractInput {
         offer_collateral: bounty_amount_sats,
         accept_collateral: taker_collateral_sats,
         fee_rate: fee_rate_sats_per_vbyte,
         contract_infos: vec![ContractInputInfo {
-            contract_descriptor: ContractDescriptor::Enum(EnumDescriptor {
-                outcome_payouts: vec![
-                    // If the bounty is completed, the taker gets the bounty
-                    // amount plus their collateral back.
-                    EnumerationPayout {
-                        outcome: BOUNTY_COMPLETE_ORACLE_MESSAGE.to_string(),
-                        payout: dlc::Payout {
-                            offer: 0,
-                            accept: bounty_amount_sats + taker_collateral_sats,
-                        },
-                    },
-                    // If the bounty is not completed, the maker gets the bounty
-                    // back plus the taker's collateral as compensation for their
-                    // time.
-                    EnumerationPayout {
-                        outcome: BOUNTY_INSUFFICIENT_ORACLE_MESSAGE.to_string(),
-                        payout: dlc::Payout {
-                            offer: bounty_amount_sats + taker_collateral_sats,
-                            accept: 0,
-                        },
-                    },
-                ],
-            }),
-            oracles: OracleInput {
-                public_keys: vec![oracle_public_key],
-                event_id: oracle_event_id,
-                threshold: 1,
-            },
+            contract_descriptor: ContractDescriptor::Enum(EnumDescriptor { outcome_payouts }),
+            oracles: oracle_input,
         }],
+    })
+}
+
+/// Creates the oracle input for a contract adjudicated by several escrow
+/// agents, at least `threshold` of which must attest to the same outcome for
+/// the contract to close. All agents must have announced the same event ID.
+fn create_oracle_input(
+    oracle_public_keys: &[String],
+    threshold: u16,
+    oracle_event_id: String,
+) -> Result<OracleInput, ResolvrError> {
+    let mut public_keys = Vec::with_capacity(oracle_public_keys.len());
+    for oracle_public_key in oracle_public_keys {
+        let public_key = parse_oracle_public_key(oracle_public_key)?;
+        if public_keys.contains(&public_key) {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Duplicate escrow agent {}.",
+                public_key
+            )));
+        }
+        public_keys.push(public_key);
+    }
+
+    if threshold == 0 || threshold as usize > public_keys.len() {
+        return Err(ResolvrError::InvalidInput(format!(
+            "Threshold must be between 1 and the number of escrow agents ({}).",
+            public_keys.len()
+        )));
+    }
+
+    Ok(OracleInput {
+        public_keys,
+        event_id: oracle_event_id,
+        threshold,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the hex-encoded public keys of `nb_agents` different agents.
+    fn get_agent_public_keys(nb_agents: u8) -> Vec<String> {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        (1..=nb_agents)
+            .map(|seed| {
+                let key_pair =
+                    bitcoin::secp256k1::KeyPair::from_seckey_slice(&secp, &[seed; 32]).unwrap();
+                XOnlyPublicKey::from_keypair(&key_pair).0.to_string()
+            })
+            .collect()
+    }
+
+    fn get_test_oracle_input() -> OracleInput {
+        OracleInput {
+            public_keys: Vec::new(),
+            event_id: String::from("bounty"),
+            threshold: 1,
+        }
+    }
+
+    fn get_partial_outcome(outcome: &str, bounty_percent: u8) -> PartialBountyOutcome {
+        PartialBountyOutcome {
+            outcome: outcome.to_string(),
+            bounty_percent,
+        }
+    }
+
+    fn get_outcome_payouts(contract_input: &ContractInput) -> &[EnumerationPayout] {
+        match &contract_input.contract_infos[0].contract_descriptor {
+            ContractDescriptor::Enum(enum_descriptor) => &enum_descriptor.outcome_payouts,
+            ContractDescriptor::Numerical(_) => panic!("Expected an enum contract."),
+        }
+    }
+
+    #[test]
+    fn partial_outcomes_split_the_bounty() {
+        let contract_input = create_bounty_contract(
+            100_000,
+            10_000,
+            2,
+            &[get_partial_outcome("Half done", 50)],
+            get_test_oracle_input(),
+        )
+        .unwrap();
+
+        let outcome_payouts = get_outcome_payouts(&contract_input);
+        assert_eq!(outcome_payouts.len(), 3);
+        assert_eq!(outcome_payouts[2].outcome, "Half done");
+        assert_eq!(outcome_payouts[2].payout.offer, 50_000);
+        assert_eq!(outcome_payouts[2].payout.accept, 60_000);
+    }
+
+    #[test]
+    fn partial_outcomes_must_pay_part_of_the_bounty() {
+        for bounty_percent in [0, 100, 150] {
+            let res = create_bounty_contract(
+                100_000,
+                10_000,
+                2,
+                &[get_partial_outcome("Partly done", bounty_percent)],
+                get_test_oracle_input(),
+            );
+            assert!(matches!(res, Err(ResolvrError::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn partial_outcomes_must_be_unique() {
+        for partial_outcomes in [
+            vec![get_partial_outcome(BOUNTY_COMPLETE_ORACLE_MESSAGE, 50)],
+            vec![get_partial_outcome(BOUNTY_INSUFFICIENT_ORACLE_MESSAGE, 50)],
+            vec![
+                get_partial_outcome("Half done", 50),
+                get_partial_outcome("Half done", 60),
+            ],
+        ] {
+            let res = create_bounty_contract(
+                100_000,
+                10_000,
+                2,
+                &partial_outcomes,
+                get_test_oracle_input(),
+            );
+            match res {
+                Err(ResolvrError::InvalidInput(message)) => {
+                    assert!(message.starts_with("Duplicate outcome"))
+                }
+                _ => panic!("Expected a duplicate outcome error."),
+            }
+        }
+    }
+
+    #[test]
+    fn oracle_input_keeps_agents_in_order() {
+        let public_keys = get_agent_public_keys(3);
+        let oracle_input = create_oracle_input(&public_keys, 2, String::from("bounty")).unwrap();
+
+        let parsed_public_keys: Vec<String> = oracle_input
+            .public_keys
+            .iter()
+            .map(|public_key| public_key.to_string())
+            .collect();
+        assert_eq!(parsed_public_keys, public_keys);
+        assert_eq!(oracle_input.threshold, 2);
+        assert_eq!(oracle_input.event_id, "bounty");
+    }
+
+    #[test]
+    fn oracle_input_refuses_duplicate_agents() {
+        let mut public_keys = get_agent_public_keys(2);
+        public_keys.push(public_keys[0].clone());
+
+        match create_oracle_input(&public_keys, 2, String::from("bounty")) {
+            Err(ResolvrError::InvalidInput(message)) => {
+                assert!(message.starts_with("Duplicate escrow agent"))
+            }
+            _ => panic!("Expected a duplicate agent error."),
+        }
+    }
+
+    #[test]
+    fn oracle_input_refuses_out_of_range_thresholds() {
+        let public_keys = get_agent_public_keys(3);
+        for threshold in [0, 4] {
+            match create_oracle_input(&public_keys, threshold, String::from("bounty")) {
+                Err(ResolvrError::InvalidInput(message)) => {
+                    assert!(message.starts_with("Threshold must be"))
+                }
+                _ => panic!("Expected a threshold error."),
+            }
+        }
+        assert!(create_oracle_input(&[], 1, String::from("bounty")).is_err());
+    }
+
+    #[test]
+    fn oracle_input_refuses_invalid_agents() {
+        let public_keys = vec![String::from("not a public key")];
+        assert!(matches!(
+            create_oracle_input(&public_keys, 1, String::from("bounty")),
+            Err(ResolvrError::InvalidInput(_))
+        ));
     }
 }
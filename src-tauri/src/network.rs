@@ -0,0 +1,177 @@
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, BlockHash, Network, Script};
+use dlc_messages::Message;
+use escrow_agent_messages::error::ResolvrError;
+use std::str::FromStr;
+
+/// Parses a network name as given in `RESOLVR_NETWORK`. Accepts `mainnet` as
+/// well as the `bitcoin` that `Network` itself uses.
+pub fn parse_network(network: &str) -> Result<Network, ResolvrError> {
+    match network {
+        "mainnet" | "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(ResolvrError::InvalidInput(format!(
+            "Unknown network {}. Must be one of mainnet, testnet, signet or regtest.",
+            network
+        ))),
+    }
+}
+
+/// Parses an address, refusing addresses for any network other than `network`.
+pub fn parse_address(address: &str, network: Network) -> Result<Address, ResolvrError> {
+    let address = match Address::from_str(address) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(ResolvrError::InvalidInput(format!(
+                "Error parsing address: {}",
+                e
+            )))
+        }
+    };
+    if !address.is_valid_for_network(network) {
+        return Err(ResolvrError::InvalidInput(format!(
+            "Address {} isn't a {} address.",
+            address, network
+        )));
+    }
+    Ok(address)
+}
+
+pub fn get_genesis_block_hash(network: Network) -> BlockHash {
+    genesis_block(network).block_hash()
+}
+
+/// Returns the chain hash that DLC messages identify `network` by.
+pub fn get_chain_hash(network: Network) -> [u8; 32] {
+    get_genesis_block_hash(network).into_inner()
+}
+
+/// Checks that a message from a counterparty is meant for `network`, so that
+/// e.g. a mainnet offer is never accepted by an app running on regtest, and
+/// that its payout and change scripts are standard.
+pub fn check_counterparty_msg(msg: &Message, network: Network) -> Result<(), ResolvrError> {
+    match msg {
+        Message::Offer(offer_dlc) => {
+            if offer_dlc.chain_hash != get_chain_hash(network) {
+                return Err(ResolvrError::InvalidInput(format!(
+                    "Offer is for a different chain than {}.",
+                    network
+                )));
+            }
+            check_standard_script("payout", &offer_dlc.payout_spk)?;
+            check_standard_script("change", &offer_dlc.change_spk)
+        }
+        Message::Accept(accept_dlc) => {
+            check_standard_script("payout", &accept_dlc.payout_spk)?;
+            check_standard_script("change", &accept_dlc.change_spk)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rejects a counterparty script that isn't one of the standard output types
+/// an address can be made for, such as P2WPKH or P2TR. This says nothing about
+/// the network, since a script looks the same on every network.
+fn check_standard_script(kind: &str, script_pubkey: &Script) -> Result<(), ResolvrError> {
+    // The network only picks the address prefix and doesn't affect whether
+    // the script is standard.
+    match Address::from_script(script_pubkey, Network::Bitcoin) {
+        Some(_) => Ok(()),
+        None => Err(ResolvrError::InvalidInput(format!(
+            "Counterparty {} script {} isn't a standard output script.",
+            kind, script_pubkey
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dlc_messages::{AcceptDlc, OfferDlc};
+    use escrow_agent_messages::test_utils::run_mock_dlc_flow;
+
+    /// Returns an offer and an accept from a mock negotiation. `dlc_manager`
+    /// makes offers for mainnet.
+    fn get_mock_msgs() -> (OfferDlc, AcceptDlc) {
+        let mut msgs = run_mock_dlc_flow().into_iter();
+        match (msgs.next(), msgs.next()) {
+            (Some(Message::Offer(offer_dlc)), Some(Message::Accept(accept_dlc))) => {
+                (offer_dlc, accept_dlc)
+            }
+            _ => panic!("Expected an offer and an accept."),
+        }
+    }
+
+    fn assert_invalid_input(res: Result<(), ResolvrError>, expected_message: &str) {
+        match res {
+            Err(ResolvrError::InvalidInput(message)) => {
+                assert!(
+                    message.starts_with(expected_message),
+                    "Unexpected error: {}",
+                    message
+                )
+            }
+            _ => panic!("Expected an invalid input error."),
+        }
+    }
+
+    #[test]
+    fn offers_must_be_for_our_chain() {
+        let (mut offer_dlc, _) = get_mock_msgs();
+        let offer = Message::Offer(offer_dlc.clone());
+        assert!(check_counterparty_msg(&offer, Network::Bitcoin).is_ok());
+        assert_invalid_input(
+            check_counterparty_msg(&offer, Network::Regtest),
+            "Offer is for a different chain than regtest.",
+        );
+
+        offer_dlc.chain_hash = get_chain_hash(Network::Regtest);
+        let offer = Message::Offer(offer_dlc);
+        assert!(check_counterparty_msg(&offer, Network::Regtest).is_ok());
+        assert!(check_counterparty_msg(&offer, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn offers_must_have_standard_scripts() {
+        let (offer_dlc, _) = get_mock_msgs();
+
+        let mut bad_offer_dlc = offer_dlc.clone();
+        bad_offer_dlc.payout_spk = Script::new();
+        assert_invalid_input(
+            check_counterparty_msg(&Message::Offer(bad_offer_dlc), Network::Bitcoin),
+            "Counterparty payout script",
+        );
+
+        let mut bad_offer_dlc = offer_dlc;
+        bad_offer_dlc.change_spk = Script::new();
+        assert_invalid_input(
+            check_counterparty_msg(&Message::Offer(bad_offer_dlc), Network::Bitcoin),
+            "Counterparty change script",
+        );
+    }
+
+    #[test]
+    fn accepts_must_have_standard_scripts() {
+        let (_, accept_dlc) = get_mock_msgs();
+        assert!(
+            check_counterparty_msg(&Message::Accept(accept_dlc.clone()), Network::Regtest).is_ok()
+        );
+
+        let mut bad_accept_dlc = accept_dlc.clone();
+        bad_accept_dlc.payout_spk = Script::new();
+        assert_invalid_input(
+            check_counterparty_msg(&Message::Accept(bad_accept_dlc), Network::Regtest),
+            "Counterparty payout script",
+        );
+
+        let mut bad_accept_dlc = accept_dlc;
+        bad_accept_dlc.change_spk = Script::new();
+        assert_invalid_input(
+            check_counterparty_msg(&Message::Accept(bad_accept_dlc), Network::Regtest),
+            "Counterparty change script",
+        );
+    }
+}
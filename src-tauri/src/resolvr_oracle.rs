@@ -1,52 +1,338 @@
-use core::panic;
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::{Secp256k1, XOnlyPublicKey};
+use dlc_manager::error::Error as DlcManagerError;
 use dlc_manager::Oracle;
-use dlc_messages::oracle_msgs::{OracleAnnouncement, OracleAttestation};
-use escrow_agent_messages::{AdjudicationRequest, AdjudicationRequestStatus, EscrowAgent};
+use dlc_messages::oracle_msgs::{EventDescriptor, OracleAnnouncement, OracleAttestation};
+use escrow_agent_messages::error::ResolvrError;
+use escrow_agent_messages::{
+    decode_oracle_announcement, decode_oracle_attestation, AdjudicationRequest,
+    AdjudicationRequestStatus, EscrowAgent, EscrowAgentRequest, EscrowAgentResponse,
+    ORACLE_ANNOUNCEMENT_EVENT_KIND, ORACLE_ATTESTATION_EVENT_KIND,
+};
+use nostr_sdk::nips::nip04;
+use nostr_sdk::nips::nip19::FromBech32;
+use nostr_sdk::{
+    Client, Event, EventId, Filter, Keys, Kind, RelayPoolNotification, Tag, Timestamp,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
 
-pub const BOUNTY_COMPLETE_ORACLE_MESSAGE: &str = "BOUNTY_COMPLETE";
-pub const BOUNTY_INSUFFICIENT_ORACLE_MESSAGE: &str = "BOUNTY_INSUFFICIENT";
+pub use escrow_agent_messages::{
+    BOUNTY_COMPLETE_ORACLE_MESSAGE, BOUNTY_INSUFFICIENT_ORACLE_MESSAGE,
+};
 
-pub struct NostrNip4ResolvrOracle {}
+/// How long to wait for relays to return an agent's events, or for the agent
+/// to respond to a request.
+const RELAY_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An escrow agent that publishes its oracle announcements and attestations as
+/// Nostr events. The agent signs announcements and attestations with the same
+/// key it uses for Nostr, so its npub doubles as its oracle public key.
+pub struct NostrNip4ResolvrOracle {
+    agent_public_key: nostr_sdk::secp256k1::XOnlyPublicKey,
+    oracle_public_key: XOnlyPublicKey,
+    keys: Keys,
+    client: Client,
+    runtime: tokio::runtime::Handle,
+    announcements: Mutex<HashMap<String, OracleAnnouncement>>,
+    attestations: Mutex<HashMap<String, OracleAttestation>>,
+}
 
 impl NostrNip4ResolvrOracle {
-    // TODO: Add an nPub key to the constructor (I don't know what type it is yet).
-    pub fn new_from_npub() -> Self {
-        Self {}
+    /// Creates an oracle for the escrow agent with the given npub, reading its
+    /// events from the given relays and sending it requests from `keys`. The
+    /// keys should stay the same across restarts, since the agent knows the
+    /// app's requests by the key they came from. Must be called from within a
+    /// Tokio runtime, which is later used to query the relays.
+    pub async fn new_from_npub(
+        npub: &str,
+        relays: Vec<String>,
+        keys: Keys,
+    ) -> Result<Self, ResolvrError> {
+        let agent_public_key = match nostr_sdk::secp256k1::XOnlyPublicKey::from_bech32(npub) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::InvalidInput(format!(
+                    "Error parsing escrow agent npub: {}",
+                    e
+                )))
+            }
+        };
+        let oracle_public_key = match XOnlyPublicKey::from_slice(&agent_public_key.serialize()) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ResolvrError::InvalidInput(format!(
+                    "Error converting escrow agent public key: {}",
+                    e
+                )))
+            }
+        };
+
+        let client = Client::new(&keys);
+        for relay in relays {
+            if let Err(e) = client.add_relay(relay.as_str(), None).await {
+                return Err(ResolvrError::Transport(format!(
+                    "Error adding relay {}: {}",
+                    relay, e
+                )));
+            }
+        }
+        client.connect().await;
+
+        Ok(Self {
+            agent_public_key,
+            oracle_public_key,
+            keys,
+            client,
+            runtime: tokio::runtime::Handle::current(),
+            announcements: Mutex::new(HashMap::new()),
+            attestations: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Fetches the agent's most recent event of the given kind for the given
+    /// oracle event ID, after checking that it is signed by the agent.
+    fn fetch_agent_event(&self, kind: u64, event_id: &str) -> Result<Event, DlcManagerError> {
+        let filter = Filter::new()
+            .author(self.agent_public_key)
+            .kind(Kind::Custom(kind))
+            .identifier(event_id);
+
+        // `Oracle` is a synchronous trait, but may be called from within the
+        // async runtime, so we need to make sure we're allowed to block here.
+        let events = tokio::task::block_in_place(|| {
+            self.runtime.block_on(
+                self.client
+                    .get_events_of(vec![filter], Some(RELAY_QUERY_TIMEOUT)),
+            )
+        })
+        .map_err(|e| DlcManagerError::OracleError(format!("Error querying relays: {}", e)))?;
+
+        events
+            .into_iter()
+            .filter(|event| event.pubkey == self.agent_public_key && event.verify().is_ok())
+            .max_by_key(|event| event.created_at)
+            .ok_or_else(|| {
+                DlcManagerError::OracleError(format!("No event found for event ID {}.", event_id))
+            })
+    }
+
+    /// Sends a request to the agent as a DM and waits for its reply.
+    fn send_agent_request(
+        &self,
+        request: &EscrowAgentRequest,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        let content = serde_json::to_string(request).map_err(|e| {
+            ResolvrError::Internal(format!("Error encoding escrow agent request: {}", e))
+        })?;
+        let secret_key = self.keys.secret_key().map_err(|e| {
+            ResolvrError::Internal(format!("Error getting Nostr secret key: {}", e))
+        })?;
+
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                // Subscribe before sending so that the reply can't be missed.
+                self.client
+                    .subscribe(vec![Filter::new()
+                        .kind(Kind::EncryptedDirectMessage)
+                        .author(self.agent_public_key)
+                        .pubkey(self.keys.public_key())
+                        .since(Timestamp::now())])
+                    .await;
+                let mut notifications = self.client.notifications();
+
+                let request_event_id = self
+                    .client
+                    .send_direct_msg(self.agent_public_key, content, None)
+                    .await
+                    .map_err(|e| {
+                        ResolvrError::Transport(format!(
+                            "Error sending escrow agent request: {}",
+                            e
+                        ))
+                    })?;
+
+                let response_content = tokio::time::timeout(RELAY_QUERY_TIMEOUT, async {
+                    while let Ok(notification) = notifications.recv().await {
+                        if let RelayPoolNotification::Event(_, event) = notification {
+                            if event.pubkey == self.agent_public_key
+                                && is_reply_to(&event, &request_event_id)
+                            {
+                                return nip04::decrypt(&secret_key, &event.pubkey, &event.content)
+                                    .map_err(|e| {
+                                        ResolvrError::Oracle(format!(
+                                            "Error decrypting response: {}",
+                                            e
+                                        ))
+                                    });
+                            }
+                        }
+                    }
+                    Err(ResolvrError::Transport(String::from(
+                        "Disconnected from relays.",
+                    )))
+                })
+                .await
+                .map_err(|_| {
+                    ResolvrError::Oracle(String::from(
+                        "Timed out waiting for escrow agent response.",
+                    ))
+                })??;
+
+                match serde_json::from_str(&response_content) {
+                    Ok(EscrowAgentResponse::AdjudicationRequestStatus(status)) => Ok(status),
+                    // The agent's error already carries its code.
+                    Ok(EscrowAgentResponse::Error(e)) => Err(e),
+                    Err(e) => Err(ResolvrError::Oracle(format!(
+                        "Error decoding escrow agent response: {}",
+                        e
+                    ))),
+                }
+            })
+        })
     }
 }
 
 impl EscrowAgent for NostrNip4ResolvrOracle {
     fn request_adjudication(
         &self,
-        _adjudication_request: AdjudicationRequest,
-    ) -> Result<AdjudicationRequestStatus, String> {
-        panic!("Not implemented yet.");
+        adjudication_request: AdjudicationRequest,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        self.send_agent_request(&EscrowAgentRequest::RequestAdjudication(
+            adjudication_request,
+        ))
     }
 
     fn get_adjudication_request_status(
         &self,
-        _event_id: &str,
-    ) -> Result<AdjudicationRequestStatus, String> {
-        panic!("Not implemented yet.");
+        event_id: &str,
+    ) -> Result<AdjudicationRequestStatus, ResolvrError> {
+        self.send_agent_request(&EscrowAgentRequest::GetAdjudicationRequestStatus {
+            oracle_event_id: event_id.to_string(),
+        })
     }
 }
 
 impl Oracle for NostrNip4ResolvrOracle {
     fn get_public_key(&self) -> bitcoin::XOnlyPublicKey {
-        panic!("Not implemented yet.");
+        self.oracle_public_key
     }
 
-    fn get_announcement(
-        &self,
-        _event_id: &str,
-    ) -> Result<OracleAnnouncement, dlc_manager::error::Error> {
-        panic!("Not implemented yet.");
+    fn get_announcement(&self, event_id: &str) -> Result<OracleAnnouncement, DlcManagerError> {
+        if let Some(announcement) = self.announcements.lock().unwrap().get(event_id) {
+            return Ok(announcement.clone());
+        }
+
+        let event = self.fetch_agent_event(ORACLE_ANNOUNCEMENT_EVENT_KIND, event_id)?;
+        let announcement =
+            decode_oracle_announcement(&event.content).map_err(DlcManagerError::OracleError)?;
+        verify_announcement(&announcement, &self.oracle_public_key, event_id)?;
+
+        self.announcements
+            .lock()
+            .unwrap()
+            .insert(event_id.to_string(), announcement.clone());
+        Ok(announcement)
     }
 
-    fn get_attestation(
-        &self,
-        _event_id: &str,
-    ) -> Result<OracleAttestation, dlc_manager::error::Error> {
-        panic!("Not implemented yet.");
+    fn get_attestation(&self, event_id: &str) -> Result<OracleAttestation, DlcManagerError> {
+        if let Some(attestation) = self.attestations.lock().unwrap().get(event_id) {
+            return Ok(attestation.clone());
+        }
+
+        let announcement = self.get_announcement(event_id)?;
+        let event = self.fetch_agent_event(ORACLE_ATTESTATION_EVENT_KIND, event_id)?;
+        let attestation =
+            decode_oracle_attestation(&event.content).map_err(DlcManagerError::OracleError)?;
+        verify_attestation(&attestation, &announcement)?;
+
+        self.attestations
+            .lock()
+            .unwrap()
+            .insert(event_id.to_string(), attestation.clone());
+        Ok(attestation)
+    }
+}
+
+fn is_reply_to(event: &Event, event_id: &EventId) -> bool {
+    event
+        .tags
+        .iter()
+        .any(|tag| matches!(tag, Tag::Event(id, ..) if id == event_id))
+}
+
+/// Checks that an announcement is for the expected event and is validly signed
+/// by the expected oracle.
+fn verify_announcement(
+    announcement: &OracleAnnouncement,
+    oracle_public_key: &XOnlyPublicKey,
+    event_id: &str,
+) -> Result<(), DlcManagerError> {
+    if &announcement.oracle_public_key != oracle_public_key {
+        return Err(DlcManagerError::OracleError(String::from(
+            "Announcement is signed by an unexpected oracle.",
+        )));
+    }
+
+    if announcement.oracle_event.event_id != event_id {
+        return Err(DlcManagerError::OracleError(String::from(
+            "Announcement is for an unexpected event.",
+        )));
+    }
+
+    announcement
+        .validate(&Secp256k1::verification_only())
+        .map_err(|e| DlcManagerError::OracleError(format!("Invalid announcement: {}", e)))
+}
+
+/// Checks that an attestation signs valid outcomes for the given announcement,
+/// using the announced nonces and oracle key.
+fn verify_attestation(
+    attestation: &OracleAttestation,
+    announcement: &OracleAnnouncement,
+) -> Result<(), DlcManagerError> {
+    if attestation.oracle_public_key != announcement.oracle_public_key {
+        return Err(DlcManagerError::OracleError(String::from(
+            "Attestation is signed by a different oracle than the announcement.",
+        )));
+    }
+
+    let nonces = &announcement.oracle_event.oracle_nonces;
+    if attestation.signatures.len() != nonces.len() || attestation.outcomes.len() != nonces.len() {
+        return Err(DlcManagerError::OracleError(String::from(
+            "Attestation does not match the number of announced nonces.",
+        )));
+    }
+
+    if let EventDescriptor::EnumEvent(descriptor) = &announcement.oracle_event.event_descriptor {
+        if !descriptor.outcomes.contains(&attestation.outcomes[0]) {
+            return Err(DlcManagerError::OracleError(format!(
+                "Attested outcome {} was not announced.",
+                attestation.outcomes[0]
+            )));
+        }
     }
+
+    let secp = Secp256k1::verification_only();
+    for ((signature, outcome), nonce) in attestation
+        .signatures
+        .iter()
+        .zip(attestation.outcomes.iter())
+        .zip(nonces.iter())
+    {
+        let (signature_nonce, _) = dlc::secp_utils::schnorrsig_decompose(signature)
+            .map_err(|e| DlcManagerError::OracleError(format!("Invalid signature: {}", e)))?;
+        if &signature_nonce != nonce {
+            return Err(DlcManagerError::OracleError(String::from(
+                "Attestation signature does not use the announced nonce.",
+            )));
+        }
+
+        let msg = bitcoin::secp256k1::Message::from_hashed_data::<sha256::Hash>(outcome.as_bytes());
+        secp.verify_schnorr(signature, &msg, &attestation.oracle_public_key)
+            .map_err(|e| DlcManagerError::OracleError(format!("Invalid signature: {}", e)))?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,170 @@
+//! A minimal in-process Nostr relay for tests. It keeps every event it's sent
+//! and serves them to subscriptions, matching filters on IDs, authors, kinds,
+//! tags and time range, which covers every filter the app sends.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How many published events are buffered for each connection before the
+/// oldest are dropped.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+pub struct TestRelay {
+    url: String,
+}
+
+struct RelayState {
+    events: Mutex<Vec<Value>>,
+    event_sender: broadcast::Sender<Value>,
+}
+
+impl TestRelay {
+    /// Starts a relay on a free local port. It runs until the test's runtime
+    /// shuts down.
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Error binding test relay.");
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let (event_sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let state = Arc::new(RelayState {
+            events: Mutex::new(Vec::new()),
+            event_sender,
+        });
+        tokio::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::task::spawn(handle_connection(stream, state.clone()));
+            }
+        });
+
+        Self { url }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<RelayState>) {
+    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    // Replies and live events both go out through one writer.
+    let (reply_sender, mut reply_receiver) = mpsc::unbounded_channel::<Value>();
+    tokio::task::spawn(async move {
+        while let Some(reply) = reply_receiver.recv().await {
+            if ws_sender
+                .send(Message::Text(reply.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let subscriptions: Arc<Mutex<HashMap<String, Vec<Value>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+
+    let mut event_receiver = state.event_sender.subscribe();
+    let live_subscriptions = subscriptions.clone();
+    let live_reply_sender = reply_sender.clone();
+    tokio::task::spawn(async move {
+        while let Ok(event) = event_receiver.recv().await {
+            for (subscription_id, filters) in live_subscriptions.lock().unwrap().iter() {
+                if filters.iter().any(|filter| matches_filter(filter, &event)) {
+                    let _ = live_reply_sender.send(json!(["EVENT", subscription_id, event]));
+                }
+            }
+        }
+    });
+
+    while let Some(Ok(message)) = ws_receiver.next().await {
+        let text = match message {
+            Message::Text(v) => v,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let request: Vec<Value> = match serde_json::from_str(&text) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        match request.first().and_then(Value::as_str) {
+            Some("EVENT") => {
+                let event = match request.get(1) {
+                    Some(v) => v.clone(),
+                    None => continue,
+                };
+                let _ = reply_sender.send(json!(["OK", event["id"], true, ""]));
+                state.events.lock().unwrap().push(event.clone());
+                let _ = state.event_sender.send(event);
+            }
+            Some("REQ") => {
+                let subscription_id = match request.get(1).and_then(Value::as_str) {
+                    Some(v) => v.to_string(),
+                    None => continue,
+                };
+                let filters = request[2..].to_vec();
+                for event in state.events.lock().unwrap().iter() {
+                    if filters.iter().any(|filter| matches_filter(filter, event)) {
+                        let _ = reply_sender.send(json!(["EVENT", subscription_id, event]));
+                    }
+                }
+                let _ = reply_sender.send(json!(["EOSE", subscription_id]));
+                subscriptions
+                    .lock()
+                    .unwrap()
+                    .insert(subscription_id, filters);
+            }
+            Some("CLOSE") => {
+                if let Some(subscription_id) = request.get(1).and_then(Value::as_str) {
+                    subscriptions.lock().unwrap().remove(subscription_id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn matches_filter(filter: &Value, event: &Value) -> bool {
+    let filter = match filter.as_object() {
+        Some(v) => v,
+        None => return false,
+    };
+
+    filter.iter().all(|(key, value)| match key.as_str() {
+        "ids" => contains(value, &event["id"]),
+        "authors" => contains(value, &event["pubkey"]),
+        "kinds" => contains(value, &event["kind"]),
+        "since" => event["created_at"].as_u64() >= value.as_u64(),
+        "until" => event["created_at"].as_u64() <= value.as_u64(),
+        "limit" => true,
+        tag_filter => match tag_filter.strip_prefix('#') {
+            Some(tag_name) => event["tags"].as_array().map_or(false, |tags| {
+                tags.iter().any(|tag| {
+                    tag.get(0).and_then(Value::as_str) == Some(tag_name)
+                        && tag
+                            .get(1)
+                            .map_or(false, |tag_value| contains(value, tag_value))
+                })
+            }),
+            None => false,
+        },
+    })
+}
+
+fn contains(values: &Value, value: &Value) -> bool {
+    values
+        .as_array()
+        .map_or(false, |values| values.contains(value))
+}
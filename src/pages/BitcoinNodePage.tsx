@@ -1,3 +1,4 @@
+import { useEffect, useState } from "react";
 import { Button } from "~/components/ui/button";
 
 import { Input } from "~/components/ui/input";
@@ -17,9 +18,14 @@ import {
 } from "~/components/ui/form";
 import { Link } from "react-router-dom";
 import { ArrowLeftIcon } from "@radix-ui/react-icons";
-import { BitcoinCoreConfig } from "~/types";
+import { BitcoinCoreConfig, Network, ResolvrError } from "~/types";
 
-import { connectToBitcoinCore } from "~/tauriApi";
+import {
+  connectToBitcoinCore,
+  getNetwork,
+  getSavedChainBackendConfig,
+  reconnectToChainBackend,
+} from "~/tauriApi";
 
 const formSchema = z.object({
   host: z.string().url({
@@ -33,6 +39,7 @@ const formSchema = z.object({
 });
 
 export default function BitcoinNodePage() {
+  const [network, setNetwork] = useState<Network | null>(null);
   const form = useForm<z.infer<typeof formSchema>>({
     resolver: zodResolver(formSchema),
     defaultValues: {
@@ -43,6 +50,29 @@ export default function BitcoinNodePage() {
     },
   });
 
+  // Prefill the node the app already connects to on startup. The password is
+  // kept in the keychain, so it has to be entered again to change anything.
+  useEffect(() => {
+    getSavedChainBackendConfig()
+      .then((savedConfig) => {
+        if (savedConfig?.type === "BitcoinCore") {
+          form.reset({
+            host: savedConfig.host,
+            port: savedConfig.port,
+            rpcUser: savedConfig.rpc_user,
+            rpcPassword: "",
+          });
+        }
+      })
+      .catch((e) => console.log("ERROR", e));
+  }, [form]);
+
+  useEffect(() => {
+    getNetwork()
+      .then(setNetwork)
+      .catch((e) => console.log("ERROR", e));
+  }, []);
+
   async function onSubmit(values: z.infer<typeof formSchema>) {
     const bitcoinCoreConfig: BitcoinCoreConfig = {
       host: values.host,
@@ -53,7 +83,19 @@ export default function BitcoinNodePage() {
 
     try {
       // TODO: add toast on success
-      await connectToBitcoinCore(bitcoinCoreConfig);
+      try {
+        await connectToBitcoinCore(bitcoinCoreConfig);
+      } catch (e) {
+        // The app may already be connected, such as to the saved node on
+        // startup, in which case the connection is replaced instead.
+        if ((e as ResolvrError).code !== "AlreadyInitialized") {
+          throw e;
+        }
+        await reconnectToChainBackend({
+          type: "BitcoinCore",
+          ...bitcoinCoreConfig,
+        });
+      }
     } catch (e) {
       // TODO: handle error in UI
       console.log("ERROR", e);
@@ -70,6 +112,13 @@ export default function BitcoinNodePage() {
         <span>Back to all contracts</span>
       </Link>
 
+      {network && (
+        <p className="text-sm text-muted-foreground">
+          The app is running on <span className="font-medium">{network}</span>.
+          Nodes on any other network are refused.
+        </p>
+      )}
+
       <Form {...form}>
         <form onSubmit={form.handleSubmit(onSubmit)} className="space-y-8">
           <FormField
@@ -1,5 +1,21 @@
 import { invoke } from "@tauri-apps/api";
-import { BitcoinCoreConfig } from "./types";
+import { listen, UnlistenFn } from "@tauri-apps/api/event";
+import {
+  BitcoinCoreConfig,
+  ChainBackendConfig,
+  ContractDetail,
+  ContractStageChange,
+  EscrowAgent,
+  EsploraConfig,
+  FeeRateInput,
+  FeeRateSuggestions,
+  Network,
+  OfferPreview,
+  PartialBountyOutcome,
+  SavedChainBackendConfig,
+  WalletBalance,
+  WalletUtxo,
+} from "./types";
 
 /**
  * Save the Nostr secret key to the keychain, indexed by the Nostr public key.
@@ -26,6 +42,30 @@ export const getNostrNsecFromKeychain = async (
   return await invoke("get_nostr_nsec_from_keychain", { npub });
 };
 
+/**
+ * Connect to Nostr relays to send and receive DLC messages as encrypted DMs.
+ * The Nostr secret key for the given public key must already be saved in the
+ * keychain.
+ * @param npub The Nostr public key to send and receive messages as.
+ * @param relays The URLs of the relays to connect to.
+ * @returns A promise that resolves when the relays are connected.
+ */
+export const connectToNostrRelays = async (
+  npub: string,
+  relays: string[],
+): Promise<void> => {
+  return await invoke("connect_to_nostr_relays", { npub, relays });
+};
+
+/**
+ * Get the Bitcoin network the app is running on. Chain backends on any other
+ * network are refused, and contracts are kept separately per network.
+ * @returns The network, which is set when the app starts.
+ */
+export const getNetwork = async (): Promise<Network> => {
+  return await invoke("get_network");
+};
+
 /**
  * Connect to a Bitcoin Core node.
  * If the connection fails, an error is thrown.
@@ -37,3 +77,203 @@ export const connectToBitcoinCore = async (
 ): Promise<void> => {
   return await invoke("connect_to_bitcoin_core", { bitcoinCoreConfig });
 };
+
+/**
+ * Connect to an Esplora server, such as electrs, instead of a Bitcoin Core
+ * node. Contracts are funded from a wallet kept in the app's own storage.
+ * If the server can't be reached, an error is thrown.
+ * @param esploraConfig The server's URL and the network it serves.
+ * @returns A promise that resolves when the connection is successful.
+ */
+export const connectToEsplora = async (
+  esploraConfig: EsploraConfig,
+): Promise<void> => {
+  return await invoke("connect_to_esplora", { esploraConfig });
+};
+
+/**
+ * Get the details of the chain backend that the app connects to on startup.
+ * The Bitcoin Core RPC password is kept in the keychain and isn't returned.
+ * @returns The saved backend details, or null if none has been connected to.
+ */
+export const getSavedChainBackendConfig =
+  async (): Promise<SavedChainBackendConfig | null> => {
+    return await invoke("get_saved_chain_backend_config");
+  };
+
+/**
+ * Reconnect to the chain backend, such as after it restarts or its details
+ * change. Contracts are kept.
+ * @param chainBackendConfig The new configuration, or null to reuse the last
+ * one.
+ * @returns A promise that resolves when the backend is reachable again.
+ */
+export const reconnectToChainBackend = async (
+  chainBackendConfig: ChainBackendConfig | null,
+): Promise<void> => {
+  return await invoke("reconnect_to_chain_backend", { chainBackendConfig });
+};
+
+/**
+ * Disconnect from the chain backend. Contracts are kept, but aren't checked
+ * until a backend is connected again.
+ * @returns A promise that resolves when disconnected.
+ */
+export const disconnectFromChainBackend = async (): Promise<void> => {
+  return await invoke("disconnect_from_chain_backend");
+};
+
+/**
+ * Get the balance of the wallet that contracts are funded from.
+ * @returns The confirmed and unconfirmed balance, in sats. The confirmed
+ * balance includes UTXOs reserved by contracts that haven't been funded yet.
+ */
+export const getWalletBalance = async (): Promise<WalletBalance> => {
+  return await invoke("get_wallet_balance");
+};
+
+/**
+ * Get a new address to receive funds to, e.g. before offering a contract.
+ * @returns An address on the app's network.
+ */
+export const getNewAddress = async (): Promise<string> => {
+  return await invoke("get_new_address");
+};
+
+/**
+ * List the wallet's UTXOs, flagging the ones reserved to fund contracts.
+ * @returns Every UTXO in the wallet.
+ */
+export const getWalletUtxos = async (): Promise<WalletUtxo[]> => {
+  return await invoke("get_wallet_utxos");
+};
+
+/**
+ * Send funds from the wallet. UTXOs reserved by contracts are never spent.
+ * If the address is for a different network, an error is thrown.
+ * @param address The address to send to.
+ * @param amountSats How much to send, in sats.
+ * @returns The ID of the transaction.
+ */
+export const sendToAddress = async (
+  address: string,
+  amountSats: number,
+): Promise<string> => {
+  return await invoke("send_to_address", { address, amountSats });
+};
+
+/**
+ * Get the chain backend's fee rate estimates for funding a contract. Offers
+ * take either one of these targets or an explicit rate, which can't be above
+ * the maximum.
+ * @returns The economy, normal and priority rates and the maximum rate, in
+ * sats per vbyte.
+ */
+export const getFeeRateSuggestions = async (): Promise<FeeRateSuggestions> => {
+  return await invoke("get_fee_rate_suggestions");
+};
+
+/**
+ * Work out what offering a bounty contract would cost and pay out, without
+ * sending the offer or reserving any UTXOs. Takes the same parameters as
+ * `offer_contract`, minus the counterparty.
+ * @returns The funding transaction's size and fee, each party's share of the
+ * fees, the payouts for each outcome, the refund locktime and the UTXOs that
+ * would fund the contract.
+ */
+export const previewOffer = async (params: {
+  bountyAmountSats: number;
+  takerCollateralSats: number;
+  feeRate: FeeRateInput;
+  partialOutcomes: PartialBountyOutcome[];
+  oracleEventId: string;
+  oraclePublicKeys: string[];
+  oracleThreshold: number;
+  bountyDeadline: number;
+  refundDelaySecs: number;
+}): Promise<OfferPreview> => {
+  return await invoke("preview_offer", params);
+};
+
+/**
+ * Check all contracts against the chain and the escrow agent right away,
+ * rather than waiting for the next scheduled check.
+ * @returns The contracts that moved to a new stage.
+ */
+export const runPeriodicCheck = async (): Promise<ContractStageChange[]> => {
+  return await invoke("run_periodic_check");
+};
+
+/**
+ * Listen for contracts moving to a new stage, whether because of incoming
+ * messages, periodic checks or user actions.
+ * @param handler Called with each stage change.
+ * @returns A promise that resolves to a function that stops listening.
+ */
+export const onContractStageChanged = async (
+  handler: (stageChange: ContractStageChange) => void,
+): Promise<UnlistenFn> => {
+  return await listen<ContractStageChange>("contract_stage_changed", (event) =>
+    handler(event.payload),
+  );
+};
+
+/**
+ * Get everything known about a contract.
+ * @param contractId The hex-encoded ID of the contract.
+ * @returns The contract's collateral, oracle, payouts and transactions.
+ */
+export const getContract = async (
+  contractId: string,
+): Promise<ContractDetail> => {
+  return await invoke("get_contract", { contractId });
+};
+
+/**
+ * Decline a contract offered by a counterparty. The counterparty is told, and
+ * the contract moves to the Rejected stage on both sides.
+ * @param contractId The hex-encoded ID of the offered contract.
+ * @param reason Why the offer is declined, shown to the counterparty.
+ */
+export const rejectContract = async (
+  contractId: string,
+  reason?: string,
+): Promise<void> => {
+  return await invoke("reject_contract", { contractId, reason });
+};
+
+/**
+ * Get the escrow agents that contracts can be adjudicated by.
+ * @returns Every registered escrow agent.
+ */
+export const getEscrowAgents = async (): Promise<EscrowAgent[]> => {
+  return await invoke("get_escrow_agents");
+};
+
+/**
+ * Register an escrow agent that contracts can be adjudicated by.
+ * @param npub The escrow agent's Nostr public key.
+ * @param label A name to show for the agent.
+ * @param relays The URLs of the relays the agent uses, or an empty list to use
+ * the default relays.
+ * @returns The registered escrow agent.
+ */
+export const addEscrowAgent = async (
+  npub: string,
+  label: string,
+  relays: string[],
+): Promise<EscrowAgent> => {
+  return await invoke("add_escrow_agent", { npub, label, relays });
+};
+
+/**
+ * Unregister an escrow agent. Open contracts adjudicated by the agent can't
+ * close until it's added back, but can still be refunded.
+ * @param oraclePublicKey The hex-encoded oracle public key of the agent.
+ * @returns A promise that resolves when the agent is removed.
+ */
+export const removeEscrowAgent = async (
+  oraclePublicKey: string,
+): Promise<void> => {
+  return await invoke("remove_escrow_agent", { oraclePublicKey });
+};
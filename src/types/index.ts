@@ -20,3 +20,149 @@ export type BitcoinCoreConfig = {
   rpc_user: string;
   rpc_password: string;
 };
+
+export type EscrowAgent = {
+  npub: string;
+  oracle_public_key: string;
+  label: string;
+  relays: string[];
+};
+
+export type SavedBitcoinCoreConfig = Omit<BitcoinCoreConfig, "rpc_password">;
+
+export type EsploraConfig = {
+  url: string;
+};
+
+export type Network = "bitcoin" | "testnet" | "signet" | "regtest";
+
+export type FeeTarget = "Economy" | "Normal" | "Priority";
+
+export type FeeRateInput =
+  | { type: "Target"; target: FeeTarget }
+  | { type: "Explicit"; sats_per_vbyte: number };
+
+export type FeeRateSuggestions = {
+  economy_sats_per_vbyte: number;
+  normal_sats_per_vbyte: number;
+  priority_sats_per_vbyte: number;
+  max_sats_per_vbyte: number;
+};
+
+export type WalletBalance = {
+  confirmed_sats: number;
+  unconfirmed_sats: number;
+};
+
+export type WalletUtxo = {
+  txid: string;
+  vout: number;
+  value_sats: number;
+  address: string | null;
+  confirmations: number | null;
+  reserved: boolean;
+};
+
+export type ChainBackendConfig =
+  | ({ type: "BitcoinCore" } & BitcoinCoreConfig)
+  | ({ type: "Esplora" } & EsploraConfig);
+
+export type SavedChainBackendConfig =
+  | ({ type: "BitcoinCore" } & SavedBitcoinCoreConfig)
+  | ({ type: "Esplora" } & EsploraConfig);
+
+export type ContractStage =
+  | "Offered"
+  | "Accepted"
+  | "Signed"
+  | "Confirmed"
+  | "PreClosed"
+  | "Closed"
+  | "Refunded"
+  | "FailedAccept"
+  | "FailedSign"
+  | "Rejected";
+
+export type ContractStageChange = {
+  contract_id: string;
+  previous_contract_id: string | null;
+  old_stage: ContractStage | null;
+  new_stage: ContractStage;
+};
+
+export type OutcomePayout = {
+  outcome: string;
+  offer_payout_sats: number;
+  accept_payout_sats: number;
+};
+
+export type PartialBountyOutcome = {
+  outcome: string;
+  bounty_percent: number;
+};
+
+export type PartyFeeEstimate = {
+  funding_fee_sats: number;
+  closing_fee_sats: number;
+};
+
+export type OfferPreview = {
+  fee_rate_sats_per_vbyte: number;
+  funding_tx_vbytes: number;
+  funding_tx_fee_sats: number;
+  offer_fees: PartyFeeEstimate;
+  accept_fees: PartyFeeEstimate;
+  outcome_payouts: OutcomePayout[];
+  refund_locktime: number;
+  funding_utxos: WalletUtxo[];
+};
+
+export type ContractDetail = {
+  id: string;
+  temporary_id: string;
+  counter_party_id: string;
+  is_offer_party: boolean | null;
+  stage: ContractStage;
+  stage_changed_at: number | null;
+  offer_collateral_sats: number | null;
+  accept_collateral_sats: number | null;
+  fee_rate_sats_per_vbyte: number | null;
+  oracle_public_keys: string[];
+  oracle_event_id: string | null;
+  oracle_threshold: number | null;
+  outcome_payouts: OutcomePayout[];
+  numeric_outcome_base: number | null;
+  funding_txid: string | null;
+  funding_vout: number | null;
+  cet_txids: string[];
+  closing_cet_txid: string | null;
+  refund_txid: string | null;
+  refund_locktime: number | null;
+  bounty_deadline: number | null;
+  attested_outcome: string | null;
+  local_payout_sats: number | null;
+  rejection_reason: string | null;
+  expired: boolean;
+};
+
+/**
+ * Error thrown by backend commands. Branch on `code`, which is stable, rather
+ * than on `message`, which is meant for display.
+ */
+export type ResolvrError = {
+  code:
+    | "NotInitialized"
+    | "AlreadyInitialized"
+    | "InvalidContractId"
+    | "InvalidInput"
+    | "NotFound"
+    | "Storage"
+    | "Rpc"
+    | "Oracle"
+    | "Transport"
+    | "Internal";
+  message: string;
+};
+
+export const isResolvrError = (e: unknown): e is ResolvrError =>
+  typeof e === "object" && e !== null && "code" in e && "message" in e;